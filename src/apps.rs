@@ -0,0 +1,631 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::container::ContainerBackend;
+
+// DIAL 2.2.1 section 6.1.2: an application is running, stopped or (if it isn't installed)
+// installable with an url the client can use to install it (hidden is not supported yet)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AppState {
+    Running,
+    Stopped,
+    Installable(String),
+}
+
+impl fmt::Display for AppState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppState::Running => write!(f, "running"),
+            AppState::Stopped => write!(f, "stopped"),
+            AppState::Installable(url) => write!(f, "installable={}", url),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instance {
+    pub run_id: u64,
+    // seconds since the unix epoch
+    pub launched_at: u64,
+}
+
+#[derive(Debug)]
+struct Running {
+    instance: Instance,
+    child: Option<Child>,
+    // name of the container of the instance, for apps with a ContainerBackend
+    container: Option<String>,
+}
+
+/// How an app is started. The launch payload of the client is passed to the command in the
+/// DIAL_PAYLOAD environment variable.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LaunchConfig {
+    // shell command that is run on launch, apps without a command only track their state
+    pub command: Option<String>,
+    // additional environment variables of the command
+    pub env: HashMap<String, String>,
+    // working directory of the command, defaults to ours
+    pub cwd: Option<PathBuf>,
+    // instances are stopped automatically after running this long
+    pub max_runtime: Option<Duration>,
+    // run as a container instead of the command, env is passed to the container
+    pub container: Option<ContainerBackend>,
+}
+
+#[derive(Debug)]
+pub struct App {
+    pub name: String,
+    pub launch: LaunchConfig,
+    // set if the app isn't installed, it can't be launched then
+    pub install_url: Option<String>,
+    // DIAL 2.2.1 section 6.1.2: whether clients may stop the app with a DELETE of its instance
+    pub allow_stop: bool,
+    // registered with POST /admin/apps instead of --app, these are written to the --apps-file
+    pub registered_at_runtime: bool,
+    // DIAL 2.1 section 6.1.2: returned as <additionalData> to 2.x clients
+    pub additional_data: BTreeMap<String, String>,
+    // DIAL 2.1 section 6.5.1: posted by the app itself, also returned as <additionalData>
+    pub dial_data: BTreeMap<String, String>,
+    // behind its own lock so noticing an exited process only needs a read lock on the registry
+    running: Mutex<Option<Running>>,
+    // notified (notify_waiters) whenever the app is launched, stopped or exits on its own
+    changed: Arc<Notify>,
+}
+
+impl App {
+    pub fn new(name: &str, launch: LaunchConfig) -> App {
+        App {
+            name: name.to_string(),
+            launch,
+            install_url: None,
+            allow_stop: true,
+            registered_at_runtime: false,
+            additional_data: BTreeMap::new(),
+            dial_data: BTreeMap::new(),
+            running: Mutex::new(None),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Notified whenever the state of the app changes, exits of the launched process are only
+    /// noticed by the next call to state().
+    pub fn changed(&self) -> Arc<Notify> {
+        self.changed.clone()
+    }
+
+    pub fn state(&self) -> AppState {
+        let mut running = self.running.lock().expect("app instance lock poisoned");
+        // the launched process might have exited on its own
+        if let Some(Running {
+            child: Some(child), ..
+        }) = running.as_mut()
+        {
+            if let Ok(Some(status)) = child.try_wait() {
+                info!("App {} exited with {}", self.name, status);
+                *running = None;
+                self.changed.notify_waiters();
+            }
+        }
+
+        match (running.as_ref(), &self.install_url) {
+            (Some(_), _) => AppState::Running,
+            (None, Some(url)) => AppState::Installable(url.clone()),
+            (None, None) => AppState::Stopped,
+        }
+    }
+
+    pub fn instance(&self) -> Option<Instance> {
+        self.state();
+        self.running
+            .lock()
+            .expect("app instance lock poisoned")
+            .as_ref()
+            .map(|running| running.instance)
+    }
+}
+
+/// What a launch of an app that is already running does, see --launch-policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LaunchPolicy {
+    // hand out the running instance (DIAL 2.2.1 section 6.2.1 lets the app decide)
+    Reuse,
+    // stop it and start a new one
+    Relaunch,
+}
+
+/// What a launch did, the action of its --event-webhook event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LaunchAction {
+    Launch,
+    // the app was running already and that instance was handed out
+    Reuse,
+    // the app was running already and that instance was replaced
+    Relaunch,
+}
+
+impl LaunchAction {
+    /// Whether a new instance was started, answered with 201 instead of 200.
+    pub fn created(self) -> bool {
+        self != LaunchAction::Reuse
+    }
+}
+
+impl fmt::Display for LaunchAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LaunchAction::Launch => write!(f, "launch"),
+            LaunchAction::Reuse => write!(f, "reuse"),
+            LaunchAction::Relaunch => write!(f, "relaunch"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LaunchError {
+    NotFound,
+    NotInstalled,
+    // longer than MAX_IDEMPOTENCY_KEY
+    InvalidIdempotencyKey,
+    Failed(std::io::Error),
+}
+
+#[derive(Debug)]
+pub enum StopError {
+    NotFound,
+    NotRunning,
+}
+
+// how long the result of a launch with an idempotency key is remembered
+const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(60);
+// the keys come from senders, longer ones are rejected and beyond MAX_RECENT_LAUNCHES the oldest
+// launch is forgotten even within the window
+pub const MAX_IDEMPOTENCY_KEY: usize = 128;
+const MAX_RECENT_LAUNCHES: usize = 256;
+
+#[derive(Debug)]
+pub struct AppRegistry {
+    apps: BTreeMap<String, App>,
+    next_run_id: u64,
+    // (app, idempotency key) -> result of the launch and when it happened
+    recent_launches: HashMap<(String, String), (u64, LaunchAction, Instant)>,
+}
+
+impl Default for AppRegistry {
+    fn default() -> Self {
+        AppRegistry::new()
+    }
+}
+
+impl AppRegistry {
+    pub fn new() -> AppRegistry {
+        AppRegistry {
+            apps: BTreeMap::new(),
+            next_run_id: 1,
+            recent_launches: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, name: &str, launch: LaunchConfig) {
+        self.apps.insert(name.to_string(), App::new(name, launch));
+    }
+
+    pub fn register_installable(&mut self, name: &str, install_url: &str) {
+        let mut app = App::new(name, LaunchConfig::default());
+        app.install_url = Some(install_url.to_string());
+        self.apps.insert(name.to_string(), app);
+    }
+
+    /// Removes the app, a running instance is stopped first. False if there is no such app.
+    pub fn unregister(&mut self, name: &str) -> bool {
+        if !self.apps.contains_key(name) {
+            return false;
+        }
+        let _ = self.stop(name);
+        let app = self.apps.remove(name).expect("app is registered");
+        // long polling status requests end, the app is gone
+        app.changed.notify_waiters();
+        self.recent_launches.retain(|(app, _), _| app != name);
+        true
+    }
+
+    pub fn get(&self, name: &str) -> Option<&App> {
+        self.apps.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut App> {
+        self.apps.get_mut(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.apps.contains_key(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.apps.keys().map(String::as_str)
+    }
+
+    pub fn apps(&self) -> impl Iterator<Item = &App> {
+        self.apps.values()
+    }
+
+    /// Launches the app (if it isn't running yet, see LaunchPolicy) and returns the run id of its
+    /// instance and what the launch did.
+    /// A launch repeated with the same `idempotency_key` within a minute returns the result of
+    /// the first one instead (senders retry launches that timed out), failed launches aren't
+    /// remembered.
+    pub fn launch(
+        &mut self,
+        name: &str,
+        payload: &str,
+        idempotency_key: Option<&str>,
+        policy: LaunchPolicy,
+    ) -> Result<(u64, LaunchAction), LaunchError> {
+        self.recent_launches
+            .retain(|_, (_, _, launched)| launched.elapsed() < IDEMPOTENCY_WINDOW);
+        let key = match idempotency_key {
+            Some(key) if key.len() > MAX_IDEMPOTENCY_KEY => {
+                return Err(LaunchError::InvalidIdempotencyKey)
+            }
+            Some(key) => (name.to_string(), key.to_string()),
+            None => return self.launch_instance(name, payload, policy),
+        };
+        if let Some((run_id, action, _)) = self.recent_launches.get(&key) {
+            info!("Repeated launch of {} with key {}", name, key.1);
+            return Ok((*run_id, *action));
+        }
+        let (run_id, action) = self.launch_instance(name, payload, policy)?;
+        if self.recent_launches.len() >= MAX_RECENT_LAUNCHES {
+            let oldest = self
+                .recent_launches
+                .iter()
+                .min_by_key(|(_, (_, _, launched))| *launched)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.recent_launches.remove(&oldest);
+            }
+        }
+        self.recent_launches
+            .insert(key, (run_id, action, Instant::now()));
+        Ok((run_id, action))
+    }
+
+    fn launch_instance(
+        &mut self,
+        name: &str,
+        payload: &str,
+        policy: LaunchPolicy,
+    ) -> Result<(u64, LaunchAction), LaunchError> {
+        let app = self.apps.get_mut(name).ok_or(LaunchError::NotFound)?;
+        if app.install_url.is_some() {
+            return Err(LaunchError::NotInstalled);
+        }
+        let mut action = LaunchAction::Launch;
+        if let Some(instance) = app.instance() {
+            if policy == LaunchPolicy::Reuse {
+                return Ok((instance.run_id, LaunchAction::Reuse));
+            }
+            info!("Relaunching {} (run id {})", name, instance.run_id);
+            // it can only have exited in the meantime, which is just as good
+            let _ = self.stop(name);
+            action = LaunchAction::Relaunch;
+        }
+        let app = self.apps.get_mut(name).ok_or(LaunchError::NotFound)?;
+
+        let run_id = self.next_run_id;
+        let mut container = None;
+        let child = match (&app.launch.container, &app.launch.command) {
+            (Some(backend), _) => {
+                let container_name = ContainerBackend::container_name(&app.name, run_id);
+                info!(
+                    "Launching {}: {} as container {}",
+                    app.name, backend.image, container_name
+                );
+                let mut command = backend.run_command(&container_name, payload, &app.launch.env);
+                let child = backend.spawn(&mut command).map_err(LaunchError::Failed)?;
+                container = Some(container_name);
+                Some(child)
+            }
+            (None, Some(command)) => {
+                info!("Launching {}: {}", app.name, command);
+                let mut child = Command::new("sh");
+                child
+                    .arg("-c")
+                    .arg(command)
+                    .envs(&app.launch.env)
+                    .env("DIAL_PAYLOAD", payload)
+                    // its own process group, stopping the app has to reach whatever sh started
+                    .process_group(0);
+                if let Some(cwd) = &app.launch.cwd {
+                    child.current_dir(cwd);
+                }
+                Some(child.spawn().map_err(LaunchError::Failed)?)
+            }
+            (None, None) => None,
+        };
+
+        self.next_run_id += 1;
+        *app.running.lock().expect("app instance lock poisoned") = Some(Running {
+            instance: Instance {
+                run_id,
+                launched_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+            },
+            child,
+            container,
+        });
+        app.changed.notify_waiters();
+        Ok((run_id, action))
+    }
+
+    pub fn stop(&mut self, name: &str) -> Result<(), StopError> {
+        let app = self.apps.get_mut(name).ok_or(StopError::NotFound)?;
+        if app.state() != AppState::Running {
+            return Err(StopError::NotRunning);
+        }
+
+        let running = app
+            .running
+            .lock()
+            .expect("app instance lock poisoned")
+            .take();
+        match running {
+            Some(Running {
+                child: Some(mut child),
+                container: Some(container),
+                ..
+            }) => {
+                let backend = app
+                    .launch
+                    .container
+                    .clone()
+                    .expect("container instance without a backend");
+                info!("Stopping {} (container {})", app.name, container);
+                // stopping a container takes up to 10 seconds, the registry isn't kept locked
+                // that long
+                std::thread::spawn(move || {
+                    match backend.spawn(&mut backend.stop_command(&container)) {
+                        Ok(mut stop) => {
+                            if let Ok(status) = stop.wait() {
+                                if !status.success() {
+                                    info!("Stopping container {} failed: {}", container, status);
+                                }
+                            }
+                        }
+                        Err(e) => info!("Failed to stop container {}: {}", container, e),
+                    }
+                    let _ = child.wait();
+                });
+            }
+            Some(Running {
+                child: Some(mut child),
+                ..
+            }) => {
+                info!("Stopping {}", app.name);
+                kill_process_group(&child);
+                if let Err(e) = child.kill() {
+                    info!("Failed to kill {}: {}", app.name, e);
+                }
+                // reaped without keeping the registry locked
+                std::thread::spawn(move || {
+                    let _ = child.wait();
+                });
+            }
+            _ => {}
+        }
+        app.changed.notify_waiters();
+        Ok(())
+    }
+}
+
+// the process group id of a launched command is its pid. std can only signal the sh it started,
+// kill(1) is used for the processes it forked.
+fn kill_process_group(child: &Child) {
+    let group = format!("-{}", child.id());
+    let killed = Command::new("kill")
+        .args(["-KILL", "--", &group])
+        .stderr(std::process::Stdio::null())
+        .status();
+    if let Err(e) = killed {
+        info!("Failed to kill process group {}: {}", child.id(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dial_server_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn wait_until_exited(app: &App) {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while app.state() == AppState::Running && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn launched_commands_see_their_env_cwd_and_payload() {
+        let dir = temp_dir("apps");
+        let mut registry = AppRegistry::new();
+        registry.register(
+            "Echo",
+            LaunchConfig {
+                command: Some(
+                    r#"printf '%s|%s|%s' "$GREETING" "$DIAL_PAYLOAD" "$(pwd)" > seen"#.to_string(),
+                ),
+                env: HashMap::from([("GREETING".to_string(), "hello".to_string())]),
+                cwd: Some(dir.clone()),
+                ..LaunchConfig::default()
+            },
+        );
+        registry
+            .launch("Echo", "v=abc&t=1", None, LaunchPolicy::Reuse)
+            .unwrap();
+        wait_until_exited(registry.get("Echo").unwrap());
+        let seen = std::fs::read_to_string(dir.join("seen")).unwrap();
+        let cwd = dir.canonicalize().unwrap();
+        assert_eq!(seen, format!("hello|v=abc&t=1|{}", cwd.display()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_repeated_idempotency_key_doesnt_launch_again() {
+        let dir = temp_dir("idempotency");
+        let mut registry = AppRegistry::new();
+        registry.register(
+            "Counter",
+            LaunchConfig {
+                command: Some("echo launched >> launches".to_string()),
+                cwd: Some(dir.clone()),
+                ..LaunchConfig::default()
+            },
+        );
+        let launches = || {
+            std::fs::read_to_string(dir.join("launches"))
+                .unwrap()
+                .lines()
+                .count()
+        };
+
+        let first = registry
+            .launch("Counter", "", Some("retry-1"), LaunchPolicy::Reuse)
+            .unwrap();
+        assert_eq!(first.1, LaunchAction::Launch);
+        // the command exits right away, without the key the next launch would start it again
+        wait_until_exited(registry.get("Counter").unwrap());
+        assert_eq!(
+            registry
+                .launch("Counter", "", Some("retry-1"), LaunchPolicy::Reuse)
+                .unwrap(),
+            first
+        );
+        assert_eq!(launches(), 1);
+
+        let other = registry
+            .launch("Counter", "", Some("retry-2"), LaunchPolicy::Reuse)
+            .unwrap();
+        assert_ne!(other.0, first.0);
+        wait_until_exited(registry.get("Counter").unwrap());
+        assert_eq!(launches(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_launch_policy_decides_about_a_running_app() {
+        let mut registry = AppRegistry::new();
+        registry.register("Tracked", LaunchConfig::default());
+        let (first, action) = registry
+            .launch("Tracked", "", None, LaunchPolicy::Relaunch)
+            .unwrap();
+        assert_eq!(action, LaunchAction::Launch);
+
+        let reused = registry
+            .launch("Tracked", "", None, LaunchPolicy::Reuse)
+            .unwrap();
+        assert_eq!(reused, (first, LaunchAction::Reuse));
+        assert!(!reused.1.created());
+
+        let (second, action) = registry
+            .launch("Tracked", "", None, LaunchPolicy::Relaunch)
+            .unwrap();
+        assert_eq!(action, LaunchAction::Relaunch);
+        assert!(action.created());
+        assert_ne!(second, first);
+        let instance = registry.get("Tracked").unwrap().instance().unwrap();
+        assert_eq!(instance.run_id, second);
+    }
+
+    // the state of a process from /proc, None once it is gone
+    fn process_state(pid: &str) -> Option<char> {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        stat.rsplit(") ").next()?.chars().next()
+    }
+
+    #[test]
+    fn stopping_an_app_stops_the_processes_it_started() {
+        let dir = temp_dir("process_group");
+        let mut registry = AppRegistry::new();
+        registry.register(
+            "Forking",
+            LaunchConfig {
+                command: Some("sleep 60 & echo $! > pid; wait".to_string()),
+                cwd: Some(dir.clone()),
+                ..LaunchConfig::default()
+            },
+        );
+        registry
+            .launch("Forking", "", None, LaunchPolicy::Reuse)
+            .unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let pid = loop {
+            match std::fs::read_to_string(dir.join("pid")) {
+                Ok(pid) if pid.ends_with('\n') => break pid.trim().to_string(),
+                _ if std::time::Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                _ => panic!("the app didn't start sleep"),
+            }
+        };
+        assert!(matches!(process_state(&pid), Some(state) if state != 'Z'));
+
+        registry.stop("Forking").unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while matches!(process_state(&pid), Some(state) if state != 'Z') {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "sleep {} survived",
+                pid
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn idempotency_keys_are_bounded() {
+        let mut registry = AppRegistry::new();
+        registry.register("Tracked", LaunchConfig::default());
+        let long_key = "k".repeat(MAX_IDEMPOTENCY_KEY + 1);
+        assert!(matches!(
+            registry.launch("Tracked", "", Some(&long_key), LaunchPolicy::Reuse),
+            Err(LaunchError::InvalidIdempotencyKey)
+        ));
+        let longest_key = "k".repeat(MAX_IDEMPOTENCY_KEY);
+        registry
+            .launch("Tracked", "", Some(&longest_key), LaunchPolicy::Reuse)
+            .unwrap();
+
+        for i in 0..MAX_RECENT_LAUNCHES + 10 {
+            let key = format!("key-{}", i);
+            registry
+                .launch("Tracked", "", Some(&key), LaunchPolicy::Reuse)
+                .unwrap();
+            assert!(registry.recent_launches.len() <= MAX_RECENT_LAUNCHES);
+        }
+        assert_eq!(registry.recent_launches.len(), MAX_RECENT_LAUNCHES);
+        // the oldest ones were forgotten, the newest is still remembered
+        let remembered = |key: &str| {
+            registry
+                .recent_launches
+                .contains_key(&("Tracked".to_string(), key.to_string()))
+        };
+        assert!(!remembered(&longest_key));
+        assert!(!remembered("key-0"));
+        assert!(remembered(&format!("key-{}", MAX_RECENT_LAUNCHES + 9)));
+    }
+}
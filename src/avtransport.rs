@@ -0,0 +1,298 @@
+// Minimal AVTransport:1 control endpoint, see
+// http://upnp.org/specs/av/UPnP-av-AVTransport-v1-Service.pdf
+// Only SetAVTransportURI and Play are handled, enough for senders that push a media url and start
+// it. Nothing is rendered here, the url is recorded and either logged or handed to the app
+// configured with --av-app.
+
+use http::{Request, Response, StatusCode};
+
+use crate::context::AppContext;
+use crate::dial;
+use crate::events;
+
+pub const CONTROL_PATH: &str = "/AVTransport/ctrl";
+const SERVICE_TYPE: &str = "urn:schemas-upnp-org:service:AVTransport:1";
+
+// UPnP error codes and descriptions, UDA 1.1 section 3.2.2 and AVTransport section 2.4
+const INVALID_ACTION: (u16, &str) = (401, "Invalid Action");
+const INVALID_ARGS: (u16, &str) = (402, "Invalid Args");
+const ACTION_FAILED: (u16, &str) = (501, "Action Failed");
+const TRANSITION_NOT_AVAILABLE: (u16, &str) = (701, "Transition not available");
+
+/// What the last SetAVTransportURI set and whether Play was called since.
+#[derive(Debug, Default)]
+pub struct TransportState {
+    pub uri: Option<String>,
+    pub metadata: Option<String>,
+    pub playing: bool,
+}
+
+/// Handles a SOAP control request POSTed to CONTROL_PATH.
+pub async fn control(ctx: &AppContext, request: &Request<String>) -> Response<Vec<u8>> {
+    let body = request.body();
+    // UDA 1.1 section 3.2.1: the SOAPACTION header names the action, only senders that
+    // forget it get the first element of the body
+    let action = match request.headers().get("soapaction") {
+        Some(header) => header.to_str().ok().and_then(soap_action),
+        None => action_name(body),
+    };
+    match action {
+        Some("SetAVTransportURI") => set_av_transport_uri(ctx, body),
+        Some("Play") => play(ctx).await,
+        _ => {
+            info!("AVTransport: unsupported action {:?}", action);
+            fault(INVALID_ACTION)
+        }
+    }
+}
+
+// "urn:schemas-upnp-org:service:AVTransport:1#Play", the quotes are required but often missing
+fn soap_action(header: &str) -> Option<&str> {
+    let header = header.trim();
+    let header = header
+        .strip_prefix('"')
+        .and_then(|header| header.strip_suffix('"'))
+        .unwrap_or(header);
+    let (service, action) = header.split_once('#')?;
+    (service == SERVICE_TYPE).then_some(action)
+}
+
+fn set_av_transport_uri(ctx: &AppContext, body: &str) -> Response<Vec<u8>> {
+    let uri = match argument(body, "CurrentURI") {
+        Some(uri) if !uri.is_empty() => uri,
+        _ => return fault(INVALID_ARGS),
+    };
+    info!("AVTransport: SetAVTransportURI {}", uri);
+    let mut state = ctx
+        .av_transport
+        .lock()
+        .expect("transport state lock poisoned");
+    state.uri = Some(uri);
+    state.metadata = argument(body, "CurrentURIMetaData").filter(|metadata| !metadata.is_empty());
+    state.playing = false;
+    action_response("SetAVTransportURI")
+}
+
+async fn play(ctx: &AppContext) -> Response<Vec<u8>> {
+    // the transport state lock isn't held across the launch, it's a std Mutex
+    let uri = match &ctx
+        .av_transport
+        .lock()
+        .expect("transport state lock poisoned")
+        .uri
+    {
+        Some(uri) => uri.clone(),
+        None => return fault(TRANSITION_NOT_AVAILABLE),
+    };
+    match &ctx.config.av_app {
+        Some(name) => {
+            let mut apps = ctx.apps.write().await;
+            match apps.launch(name, &uri, None, ctx.config.launch_policy) {
+                Ok((run_id, action)) => {
+                    info!(
+                        "AVTransport: playing {} with {} (run id {})",
+                        uri, name, run_id
+                    );
+                    events::launched(ctx, name, run_id, action);
+                }
+                Err(e) => {
+                    info!(
+                        "AVTransport: failed to launch {} for {}: {:?}",
+                        name, uri, e
+                    );
+                    return fault(ACTION_FAILED);
+                }
+            }
+        }
+        None => info!("AVTransport: Play {}", uri),
+    }
+    ctx.av_transport
+        .lock()
+        .expect("transport state lock poisoned")
+        .playing = true;
+    action_response("Play")
+}
+
+// the action is the first element in the SOAP body, e.g. <u:Play xmlns:u="...">
+fn action_name(body: &str) -> Option<&str> {
+    let start = body.find(":Body")?;
+    let rest = &body[start..];
+    let rest = &rest[rest.find('>')? + 1..];
+    let rest = &rest[rest.find('<')? + 1..];
+    let end = rest.find(|c: char| c.is_whitespace() || c == '>' || c == '/')?;
+    let name = &rest[..end];
+    // the prefix of the service namespace
+    Some(name.rsplit(':').next().unwrap_or(name))
+}
+
+// arguments are unqualified child elements of the action element
+fn argument(body: &str, name: &str) -> Option<String> {
+    let open = format!("<{}", name);
+    // <CurrentURI doesn't start <CurrentURIMetaData
+    let rest = body.match_indices(&open).find_map(|(start, _)| {
+        let rest = &body[start + open.len()..];
+        rest.starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace())
+            .then_some(rest)
+    })?;
+    // <CurrentURIMetaData/> and <CurrentURIMetaData /> are empty arguments
+    if rest.trim_start().starts_with("/>") {
+        return Some(String::new());
+    }
+    let rest = &rest[rest.find('>')? + 1..];
+    let end = rest.find(&format!("</{}>", name))?;
+    Some(xml_unescape(&rest[..end]))
+}
+
+fn xml_unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// UDA 1.1 section 3.2.2: the response element is named after the action with a Response suffix
+fn action_response(action: &str) -> Response<Vec<u8>> {
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+  <s:Body>
+    <u:{action}Response xmlns:u="{service}"/>
+  </s:Body>
+</s:Envelope>
+"#,
+        action = action,
+        service = SERVICE_TYPE
+    );
+    dial::response_with_body(StatusCode::OK, "text/xml; charset=\"utf-8\"", xml)
+}
+
+// UDA 1.1 section 3.2.2: errors are a SOAP fault with the UPnP error code in the detail, always
+// with status 500
+fn fault((code, description): (u16, &str)) -> Response<Vec<u8>> {
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+  <s:Body>
+    <s:Fault>
+      <faultcode>s:Client</faultcode>
+      <faultstring>UPnPError</faultstring>
+      <detail>
+        <UPnPError xmlns="urn:schemas-upnp-org:control-1-0">
+          <errorCode>{}</errorCode>
+          <errorDescription>{}</errorDescription>
+        </UPnPError>
+      </detail>
+    </s:Fault>
+  </s:Body>
+</s:Envelope>
+"#,
+        code, description
+    );
+    dial::response_with_body(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "text/xml; charset=\"utf-8\"",
+        xml,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn context(args: &[&str]) -> AppContext {
+        AppContext::new(Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap())
+    }
+
+    // a SOAP request for `action` with `arguments` as the content of the action element
+    fn soap(action: &str, arguments: &str) -> Request<String> {
+        let body = format!(
+            r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+  <s:Body>
+    <u:{action} xmlns:u="{service}"><InstanceID>0</InstanceID>{arguments}</u:{action}>
+  </s:Body>
+</s:Envelope>"#,
+            action = action,
+            service = SERVICE_TYPE,
+            arguments = arguments
+        );
+        Request::builder()
+            .method("POST")
+            .uri(CONTROL_PATH)
+            .header("soapaction", format!("\"{}#{}\"", SERVICE_TYPE, action))
+            .body(body)
+            .unwrap()
+    }
+
+    fn body(response: &Response<Vec<u8>>) -> &str {
+        std::str::from_utf8(response.body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn set_av_transport_uri_records_the_uri_for_play() {
+        let ctx = context(&["--app", "Player", "--av-app", "Player"]);
+        let arguments = "<CurrentURI>http://192.0.2.9/a.mp4?x=1&amp;y=2</CurrentURI>\
+                         <CurrentURIMetaData>&lt;DIDL-Lite/&gt;</CurrentURIMetaData>";
+        let response = control(&ctx, &soap("SetAVTransportURI", arguments)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body(&response).contains("<u:SetAVTransportURIResponse"));
+        {
+            let state = ctx.av_transport.lock().unwrap();
+            assert_eq!(state.uri.as_deref(), Some("http://192.0.2.9/a.mp4?x=1&y=2"));
+            assert_eq!(state.metadata.as_deref(), Some("<DIDL-Lite/>"));
+            assert!(!state.playing);
+        }
+
+        let response = control(&ctx, &soap("Play", "<Speed>1</Speed>")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(ctx.av_transport.lock().unwrap().playing);
+        let apps = ctx.apps.read().await;
+        assert!(apps.get("Player").unwrap().instance().is_some());
+    }
+
+    #[tokio::test]
+    async fn the_action_is_taken_from_the_body_without_soapaction() {
+        let ctx = context(&[]);
+        let mut request = soap(
+            "SetAVTransportURI",
+            "<CurrentURI>http://192.0.2.9/a</CurrentURI>",
+        );
+        request.headers_mut().remove("soapaction");
+        assert_eq!(control(&ctx, &request).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn bad_arguments_and_transitions_are_upnp_errors() {
+        let ctx = context(&[]);
+        let response = control(&ctx, &soap("Play", "<Speed>1</Speed>")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body(&response).contains("<errorCode>701</errorCode>"));
+
+        let response = control(&ctx, &soap("SetAVTransportURI", "<CurrentURI/>")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body(&response).contains("<errorCode>402</errorCode>"));
+    }
+
+    #[tokio::test]
+    async fn unknown_actions_are_a_401_fault() {
+        let ctx = context(&[]);
+        let mut other_service = soap("Play", "");
+        other_service.headers_mut().insert(
+            "soapaction",
+            "\"urn:schemas-upnp-org:service:RenderingControl:1#Play\""
+                .parse()
+                .unwrap(),
+        );
+        for request in [soap("Seek", "<Unit>REL_TIME</Unit>"), other_service] {
+            let response = control(&ctx, &request).await;
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            let fault = body(&response);
+            assert!(fault.contains("<faultstring>UPnPError</faultstring>"));
+            assert!(fault.contains("<errorCode>401</errorCode>"), "{}", fault);
+            assert!(fault.contains("<errorDescription>Invalid Action</errorDescription>"));
+        }
+    }
+}
@@ -0,0 +1,229 @@
+// --capture: every SSDP datagram we send or receive is appended to a file, so a capture can be
+// attached to a bug report without running tcpdump.
+//
+// The file starts with the 8 byte magic "SSDPCAP1", followed by records of
+//   u64  timestamp in microseconds since the unix epoch
+//   u8   direction, 0 = received, 1 = sent
+//   4+2  source address and port
+//   4+2  destination address and port
+//   u32  payload length
+//   ...  payload
+// with all numbers in big endian. Addresses that aren't IPv4 are written as 0.0.0.0.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const MAGIC: &[u8; 8] = b"SSDPCAP1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+#[derive(Debug)]
+struct Capture {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    // once the file would grow beyond this it is moved to <path>.1 and a new one is started
+    max_size: u64,
+}
+
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+
+/// Starts appending to `path`, an existing capture is continued.
+pub fn start(path: &Path, max_size: u64) -> io::Result<()> {
+    let (file, size) = open(path)?;
+    *CAPTURE.lock().expect("capture lock poisoned") = Some(Capture {
+        path: path.to_path_buf(),
+        file,
+        size,
+        max_size,
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+pub fn stop() {
+    *CAPTURE.lock().expect("capture lock poisoned") = None;
+}
+
+fn open(path: &Path) -> io::Result<(File, u64)> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut size = file.metadata()?.len();
+    if size == 0 {
+        file.write_all(MAGIC)?;
+        size = MAGIC.len() as u64;
+    }
+    Ok((file, size))
+}
+
+/// Appends a datagram to the capture, if one was started.
+pub fn record(direction: Direction, source: SocketAddr, destination: SocketAddr, payload: &[u8]) {
+    let mut capture = CAPTURE.lock().expect("capture lock poisoned");
+    if let Some(current) = capture.as_mut() {
+        if let Err(e) = append(current, direction, source, destination, payload) {
+            warn!(
+                "writing to {} failed: {}, capture stopped",
+                current.path.display(),
+                e
+            );
+            *capture = None;
+        }
+    }
+}
+
+fn append(
+    capture: &mut Capture,
+    direction: Direction,
+    source: SocketAddr,
+    destination: SocketAddr,
+    payload: &[u8],
+) -> io::Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0);
+    let mut record = Vec::with_capacity(25 + payload.len());
+    record.extend_from_slice(&timestamp.to_be_bytes());
+    record.push(match direction {
+        Direction::Received => 0,
+        Direction::Sent => 1,
+    });
+    for address in [source, destination] {
+        let ip = match address.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        };
+        record.extend_from_slice(&ip.octets());
+        record.extend_from_slice(&address.port().to_be_bytes());
+    }
+    record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(payload);
+
+    let record_size = record.len() as u64;
+    if capture.size + record_size > capture.max_size && capture.size > MAGIC.len() as u64 {
+        let mut rotated = capture.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&capture.path, &rotated)?;
+        let (file, size) = open(&capture.path)?;
+        capture.file = file;
+        capture.size = size;
+    }
+    capture.file.write_all(&record)?;
+    capture.size += record_size;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A record of a capture file, without the timestamp.
+    #[derive(Debug, PartialEq, Eq)]
+    pub struct Record {
+        pub direction: Direction,
+        pub source: SocketAddr,
+        pub destination: SocketAddr,
+        pub payload: Vec<u8>,
+    }
+
+    pub fn read_records(path: &Path) -> Vec<Record> {
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(&bytes[..MAGIC.len()], MAGIC);
+        let mut rest = &bytes[MAGIC.len()..];
+        let mut records = Vec::new();
+        let address = |bytes: &[u8]| {
+            let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+            SocketAddr::from((ip, u16::from_be_bytes([bytes[4], bytes[5]])))
+        };
+        while !rest.is_empty() {
+            let direction = match rest[8] {
+                0 => Direction::Received,
+                _ => Direction::Sent,
+            };
+            let length = u32::from_be_bytes(rest[21..25].try_into().unwrap()) as usize;
+            records.push(Record {
+                direction,
+                source: address(&rest[9..15]),
+                destination: address(&rest[15..21]),
+                payload: rest[25..25 + length].to_vec(),
+            });
+            rest = &rest[25 + length..];
+        }
+        records
+    }
+
+    pub fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dial_server_capture_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn local_capture(path: &Path, max_size: u64) -> Capture {
+        let (file, size) = open(path).unwrap();
+        Capture {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+        }
+    }
+
+    #[test]
+    fn records_have_the_direction_addresses_and_payload() {
+        let path = temp_path("format");
+        let mut capture = local_capture(&path, 1 << 20);
+        let us = SocketAddr::from(([192, 0, 2, 2], 1900));
+        let sender = SocketAddr::from(([192, 0, 2, 9], 50000));
+        append(&mut capture, Direction::Received, sender, us, b"M-SEARCH").unwrap();
+        append(
+            &mut capture,
+            Direction::Sent,
+            us,
+            sender,
+            b"HTTP/1.1 200 OK",
+        )
+        .unwrap();
+        let expected = [
+            Record {
+                direction: Direction::Received,
+                source: sender,
+                destination: us,
+                payload: b"M-SEARCH".to_vec(),
+            },
+            Record {
+                direction: Direction::Sent,
+                source: us,
+                destination: sender,
+                payload: b"HTTP/1.1 200 OK".to_vec(),
+            },
+        ];
+        assert_eq!(read_records(&path), expected);
+    }
+
+    #[test]
+    fn a_full_capture_is_rotated() {
+        let path = temp_path("rotated");
+        let address = SocketAddr::from(([192, 0, 2, 2], 1900));
+        // room for one record of 25 + 100 bytes after the magic
+        let mut capture = local_capture(&path, 8 + 125);
+        for payload in [[b'a'; 100], [b'b'; 100]] {
+            append(&mut capture, Direction::Sent, address, address, &payload).unwrap();
+        }
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+        let rotated = PathBuf::from(rotated);
+        assert_eq!(read_records(&rotated)[0].payload, [b'a'; 100]);
+        let current = read_records(&path);
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].payload, [b'b'; 100]);
+    }
+}
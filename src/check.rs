@@ -0,0 +1,139 @@
+// `dial_server check-discoverable`: searches for an already running server from a separate socket,
+// like a sender on the network would, and reports what came back. Tells a server that doesn't
+// answer apart from a network that drops the multicast traffic.
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use socket2::SockRef;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use crate::client;
+use crate::config::Config;
+use crate::error::{DialError, SocketContext};
+use crate::ssdp::{self, SsdpMessage, SsdpStartLine};
+
+// MX of the search, the server may wait up to this long before answering
+const MX: u64 = 1;
+// how long we wait for answers after the search
+const TIMEOUT: Duration = Duration::from_secs(MX + 2);
+
+// headers every search response should carry (UDA 1.1 section 1.3.3)
+const EXPECTED_HEADERS: &[&str] = &["LOCATION", "ST", "USN", "CACHE-CONTROL", "EXT", "SERVER"];
+
+/// Searches for DIAL devices and checks the answer of the one at `config.advertised_base_url()`,
+/// the exit code is 1 if it didn't answer. Problems with the response are only reported.
+pub async fn run(config: &Config) -> Result<i32, DialError> {
+    // an ephemeral port, so the answer has to come back to another socket than the server's
+    let socket = UdpSocket::bind((config.ssdp_bind_ip, 0))
+        .await
+        .socket_context(|| format!("Binding {}:0", config.ssdp_bind_ip))?;
+    if !config.ssdp_bind_ip.is_unspecified() {
+        SockRef::from(&socket)
+            .set_multicast_if_v4(&config.ssdp_bind_ip)
+            .socket_context(|| format!("Using {} for multicast", config.ssdp_bind_ip))?;
+    }
+    let local_addr = socket
+        .local_addr()
+        .socket_context(|| "Reading the local address".to_string())?;
+
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {}\r\nUSER-AGENT: dial_server/check-discoverable\r\n\r\n",
+        MX,
+        ssdp::DIAL_SEARCH_TARGET
+    );
+    let group = SocketAddr::from((ssdp::MULTICAST_IP, 1900));
+    println!(
+        "Searching for {} from {}",
+        ssdp::DIAL_SEARCH_TARGET,
+        local_addr
+    );
+    socket
+        .send_to(search.as_bytes(), group)
+        .await
+        .socket_context(|| format!("Sending the M-SEARCH to {}", group))?;
+
+    let expected_base = config.advertised_base_url();
+    let mut found = false;
+    let mut well_formed = false;
+    let deadline = Instant::now() + TIMEOUT;
+    let mut buf = [0; ssdp::MAX_MESSAGE_SIZE];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let (amt, src_addr) =
+            match tokio::time::timeout(remaining, socket.recv_from(&mut buf)).await {
+                Err(_) => break,
+                Ok(received) => received.socket_context(|| "Receiving responses".to_string())?,
+            };
+        let raw = String::from_utf8_lossy(&buf[..amt]);
+        let response = match SsdpMessage::parse(&raw) {
+            Some(response) => response,
+            None => {
+                println!("Unparsable answer from {}: {:?}", src_addr, raw);
+                continue;
+            }
+        };
+        let location = response.header("LOCATION").unwrap_or_default();
+        if !location.starts_with(&expected_base) {
+            println!("Other device at {}: {}", src_addr, location);
+            continue;
+        }
+
+        found = true;
+        println!("Response from {}:", src_addr);
+        println!("  status: {}", response.start_line);
+        let mut problems = Vec::new();
+        if response.start_line != (SsdpStartLine::Response { status: 200 })
+            || !raw.starts_with("HTTP/1.1 200 OK\r\n")
+        {
+            problems.push("status line isn't `HTTP/1.1 200 OK` with CRLF".to_string());
+        }
+        for name in EXPECTED_HEADERS {
+            match response.header(name) {
+                Some(value) => println!("  {}: {}", name, value),
+                None => problems.push(format!("{} header is missing", name)),
+            }
+        }
+        if response.header("ST") != Some(ssdp::DIAL_SEARCH_TARGET) {
+            problems.push("ST isn't the DIAL search target".to_string());
+        }
+        if !response
+            .header("USN")
+            .is_some_and(|usn| usn.starts_with("uuid:"))
+        {
+            problems.push("USN doesn't start with uuid:".to_string());
+        }
+        match client::request("GET", location, &[], &[], TIMEOUT).await {
+            Ok(descriptor) if descriptor.status == 200 => {
+                println!("  descriptor: {} bytes", descriptor.body.len())
+            }
+            Ok(descriptor) => problems.push(format!(
+                "descriptor request answered with {}",
+                descriptor.status
+            )),
+            Err(e) => problems.push(format!("descriptor request failed: {}", e)),
+        }
+        for problem in &problems {
+            println!("  problem: {}", problem);
+        }
+        well_formed = problems.is_empty();
+        break;
+    }
+
+    if !found {
+        println!(
+            "No response from {} within {:?}: is the server running and does the network (or a \
+             firewall) let multicast through? Compare with `selftest` on the server's host.",
+            expected_base, TIMEOUT
+        );
+        return Ok(1);
+    }
+    println!(
+        "Server is discoverable{}",
+        if well_formed {
+            ""
+        } else {
+            ", but its response has problems"
+        }
+    );
+    Ok(0)
+}
@@ -0,0 +1,182 @@
+// Minimal HTTP/1.1 client for http://host:port/path urls, used by the selftest and the proxy mode.
+use std::fmt;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::ssdp;
+
+// largest response (head and body) we read, a downstream device or webhook sending more is broken
+const MAX_RESPONSE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum ClientError {
+    InvalidUrl(String),
+    Timeout,
+    Io(std::io::Error),
+    InvalidResponse,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidUrl(url) => write!(f, "not a http url: {}", url),
+            ClientError::Timeout => write!(f, "timed out"),
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::InvalidResponse => write!(f, "invalid response"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ClientResponse {
+    pub status: u16,
+    // status line and headers
+    pub head: String,
+    pub body: Vec<u8>,
+}
+
+impl ClientResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        ssdp::header(&self.head, name)
+    }
+}
+
+fn decode_chunked(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        // chunk extensions follow a ';'
+        let size = size.split(';').next()?.trim();
+        let size = usize::from_str_radix(size, 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(decoded);
+        }
+        decoded.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+pub async fn request(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: Duration,
+) -> Result<ClientResponse, ClientError> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| ClientError::InvalidUrl(url.to_string()))?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        method,
+        path,
+        host,
+        body.len()
+    );
+    for (key, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    request.push_str("\r\n");
+
+    let exchange = async {
+        let mut stream = TcpStream::connect(&address).await?;
+        let mut bytes = request.into_bytes();
+        bytes.extend_from_slice(body);
+        stream.write_all(&bytes).await?;
+        let mut response = Vec::new();
+        // one byte more tells a response of exactly MAX_RESPONSE from a longer one
+        (&mut stream)
+            .take(MAX_RESPONSE as u64 + 1)
+            .read_to_end(&mut response)
+            .await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| ClientError::Timeout)?
+        .map_err(ClientError::Io)?;
+    if response.len() > MAX_RESPONSE {
+        return Err(ClientError::InvalidResponse);
+    }
+
+    let head_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or(ClientError::InvalidResponse)?;
+    let head = String::from_utf8_lossy(&response[..head_end]).to_string();
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or(ClientError::InvalidResponse)?;
+
+    let mut response = ClientResponse {
+        status,
+        head,
+        body: response[head_end + 4..].to_vec(),
+    };
+    if response
+        .header("Transfer-Encoding")
+        .map(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false)
+    {
+        response.body = decode_chunked(&response.body).ok_or(ClientError::InvalidResponse)?;
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // answers one request with `response` and returns the url it listens on
+    async fn serve_once(response: Vec<u8>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream.write_all(&response).await;
+        });
+        url
+    }
+
+    fn response_with_body(length: usize) -> Vec<u8> {
+        let mut response =
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", length).into_bytes();
+        response.resize(response.len() + length, b'x');
+        response
+    }
+
+    #[tokio::test]
+    async fn responses_are_capped() {
+        let timeout = Duration::from_secs(5);
+        let url = serve_once(response_with_body(1000)).await;
+        let response = request("GET", &url, &[], b"", timeout).await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body.len(), 1000);
+
+        let url = serve_once(response_with_body(MAX_RESPONSE)).await;
+        let result = request("GET", &url, &[], b"", timeout).await;
+        assert!(
+            matches!(result, Err(ClientError::InvalidResponse)),
+            "{:?}",
+            result
+        );
+    }
+}
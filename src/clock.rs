@@ -0,0 +1,130 @@
+// The time the timing-sensitive code (re-announcements, MX delays, max_runtime, request timeouts)
+// waits on. TokioClock is tokio's clock (which tests can also pause), MockClock only moves when a
+// test advances it.
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Completes once now() reached `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        self.sleep_until(self.now() + duration)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+/// Like tokio::time::timeout, on `clock`. None if `future` didn't complete within `duration`.
+pub async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = clock.sleep(duration) => None,
+    }
+}
+
+#[cfg(test)]
+pub use mock::MockClock;
+
+#[cfg(test)]
+mod mock {
+    use super::*;
+    use tokio::sync::watch;
+
+    /// Starts at the time it is created and only moves with advance().
+    #[derive(Debug)]
+    pub struct MockClock {
+        now: watch::Sender<Instant>,
+    }
+
+    impl MockClock {
+        pub fn new() -> MockClock {
+            MockClock {
+                now: watch::channel(Instant::now()).0,
+            }
+        }
+
+        /// Moves the time forward, sleeps that are due complete.
+        pub fn advance(&self, duration: Duration) {
+            self.now.send_modify(|now| *now += duration);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.now.borrow()
+        }
+
+        fn sleep_until(&self, deadline: Instant) -> Sleep {
+            let mut now = self.now.subscribe();
+            Box::pin(async move {
+                // the sender lives as long as the clock, a dropped clock never wakes anyone up
+                if now.wait_for(|now| *now >= deadline).await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn mock_sleeps_complete_when_advanced_past_them() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut sleep = clock.sleep(Duration::from_secs(10));
+        assert!(futures_poll(&mut sleep).is_none());
+        clock.advance(Duration::from_secs(9));
+        assert!(futures_poll(&mut sleep).is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!(futures_poll(&mut sleep).is_some());
+        assert_eq!(clock.now() - start, Duration::from_secs(10));
+
+        let never = std::future::pending::<()>();
+        let timed_out = tokio::spawn(async move {
+            let clock = MockClock::new();
+            let waiting = timeout(&clock, Duration::from_secs(1), never);
+            tokio::pin!(waiting);
+            assert!(futures_poll(&mut waiting).is_none());
+            clock.advance(Duration::from_secs(1));
+            waiting.await
+        });
+        assert_eq!(timed_out.await.unwrap(), None);
+    }
+
+    // polls once without waiting
+    fn futures_poll<F: Future + Unpin>(future: &mut F) -> Option<F::Output> {
+        let waker = std::task::Waker::noop();
+        let mut context = std::task::Context::from_waker(waker);
+        match Pin::new(future).poll(&mut context) {
+            std::task::Poll::Ready(output) => Some(output),
+            std::task::Poll::Pending => None,
+        }
+    }
+}
@@ -0,0 +1,1253 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use http::header::{HeaderName, HeaderValue};
+
+use crate::apps::{LaunchConfig, LaunchPolicy};
+use crate::container::ContainerBackend;
+use crate::dial;
+use crate::profile::{self, Profile};
+use crate::registration;
+use crate::selftest;
+use crate::ssdp;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AppConfig {
+    pub name: String,
+    pub launch: LaunchConfig,
+    // apps with an install url are registered as installable instead of installed
+    pub install_url: Option<String>,
+    // DIAL 2.1 section 6.1.2: returned as <additionalData> in the status of the app
+    pub additional_data: BTreeMap<String, String>,
+}
+
+/// CACHE-CONTROL max-age (in seconds) of each kind of advertisement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaxAge {
+    pub root_device: u32,
+    pub device: u32,
+    pub service: u32,
+}
+
+impl MaxAge {
+    pub fn shortest(&self) -> u32 {
+        self.root_device.min(self.device).min(self.service)
+    }
+}
+
+impl Default for MaxAge {
+    fn default() -> Self {
+        MaxAge {
+            root_device: 900,
+            device: 900,
+            service: 900,
+        }
+    }
+}
+
+/// What a TCP connection to the SSDP port gets, see --ssdp-tcp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SsdpTcp {
+    // every GET is answered with the device descriptor
+    Descriptor,
+    // every request is answered with 400
+    BadRequest,
+}
+
+/// What a byebye with our UUID (of a previous instance or our own, looped back) triggers, see
+/// --own-byebye.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OwnByebye {
+    Ignore,
+    // announce again, receivers that saw the byebye dropped us
+    Reannounce,
+}
+
+/// How log lines are written, see --log-format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    // one JSON object per line
+    Json,
+}
+
+/// A search target answered in addition to DIAL, see --respond.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchTarget {
+    pub st: String,
+    pub usn: String,
+    pub location: String,
+}
+
+/// SERVER header of each kind of advertisement and of the HTTP responses, None keeps the one of
+/// the profile
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerHeader {
+    pub root_device: Option<String>,
+    pub device: Option<String>,
+    pub service: Option<String>,
+    pub http: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub apps: Vec<AppConfig>,
+    // emulated search targets, answered with their own USN and LOCATION
+    pub search_targets: Vec<SearchTarget>,
+    // addresses the HTTP listener and the SSDP socket are bound to, --bind-ip sets both
+    pub http_bind_ip: Ipv4Addr,
+    pub ssdp_bind_ip: Ipv4Addr,
+    // address other devices can reach us on, used in every LOCATION we hand out
+    pub announce_ip: Ipv4Addr,
+    pub http_port: u16,
+    // port other devices reach the HTTP server on (e.g. through a port forward), None if it is
+    // http_port
+    pub advertise_port: Option<u16>,
+    // --announce-interface: addresses of the interfaces the NOTIFYs go out on, each with its own
+    // address in LOCATION. Empty announces once, with announce_ip.
+    pub announce_interfaces: Vec<Ipv4Addr>,
+    // host name used instead of announce_ip in the urls we hand out
+    pub advertise_host: Option<String>,
+    // try the next ports if http_port is taken
+    pub port_fallback: bool,
+    pub debug: bool,
+    pub log_format: LogFormat,
+    pub max_age: MaxAge,
+    pub server_header: ServerHeader,
+    pub profile: &'static Profile,
+    // exit after the first launch (or after once_timeout seconds without one)
+    pub once: bool,
+    pub once_timeout: u64,
+    // SO_RCVBUF/SO_SNDBUF of the SSDP socket in bytes, None keeps the OS default
+    pub udp_recv_buffer: Option<usize>,
+    pub udp_send_buffer: Option<usize>,
+    // seconds open connections get to finish on shutdown
+    pub shutdown_grace: u64,
+    // seconds after which the shutdown is forced, even if it's stuck
+    pub shutdown_timeout: u64,
+    // deliver our own multicast traffic back to us (and other programs on this host)
+    pub multicast_loop: bool,
+    // answer searches sent to 127.0.0.1 with a LOCATION on 127.0.0.1
+    pub loopback: bool,
+    // don't join the multicast group and don't send NOTIFYs, only directed searches are answered
+    pub unicast_only: bool,
+    // listen on TCP port 1900 as well, some devices answer port scanners there
+    pub ssdp_tcp: Option<SsdpTcp>,
+    pub own_byebye: OwnByebye,
+    // --extra-header, added to every HTTP response after the ones of the profile
+    pub extra_headers: Vec<(String, String)>,
+    // run the selftest against ourselves and exit
+    pub selftest: bool,
+    // search for an already running server instead of starting one
+    pub check_discoverable: bool,
+    // Application-URL of a DIAL device the app resources are forwarded to
+    pub proxy: Option<String>,
+    // app launched with the url pushed by AVTransport Play, None only logs it
+    pub av_app: Option<String>,
+    // what a launch of an app that is already running does
+    pub launch_policy: LaunchPolicy,
+    // url every launch is POSTed to as a JSON event
+    pub event_webhook: Option<String>,
+    // answer searches with a wrong HOST header instead of ignoring them
+    pub lenient_host: bool,
+    // milliseconds to wait before the first announcement
+    pub announce_delay: u64,
+    // how often every set of alive/byebye messages is sent
+    pub announce_repeats: u32,
+    // seconds within which requested announcements (e.g. after the network came back) are
+    // coalesced into one
+    pub announce_cooldown: u64,
+    // longest delay of a search response in seconds, larger MX values are clamped to it
+    pub max_mx: u64,
+    // Content-Type of the device descriptor, some clients reject XML without a charset
+    pub descriptor_content_type: String,
+    // file served as device descriptor instead of src/desc.xml
+    pub descriptor: Option<PathBuf>,
+    // <deviceType> of the descriptor and NT of the device advertisements, e.g.
+    // urn:dial-multiscreen-org:device:dial:1
+    pub device_type: String,
+    // Access-Control-Allow-Origin of the descriptor, for browser senders fetching it cross-origin
+    pub cors_origin: String,
+    // file every SSDP datagram is appended to, rotated once it reaches capture_max_size bytes
+    pub capture: Option<PathBuf>,
+    pub capture_max_size: u64,
+    // largest request body (e.g. a launch payload) we read, larger ones are answered with 413
+    pub max_body: usize,
+    // longest request target (path and query), longer ones are answered with 414
+    pub max_uri: usize,
+    // largest launch payload passed on to an app, larger ones are answered with 413
+    pub max_payload: usize,
+    // seconds in the Retry-After of a failed launch
+    pub retry_after: u64,
+    // limits of the dial_data an app can store (key and value bytes summed up)
+    pub max_dial_data_entries: usize,
+    pub max_dial_data_bytes: usize,
+    // more --app than this are refused on startup
+    pub max_apps: usize,
+    // install url of the apps given as --installable NAME, {name} is replaced with the app name
+    pub install_url: Option<String>,
+    // apps registered with POST /admin/apps are kept in this file and registered again on startup
+    pub apps_file: Option<PathBuf>,
+    // directory the runtime registered apps (apps.json) and the dial_data of the apps are kept in
+    pub state_dir: Option<PathBuf>,
+    // HTTP Basic credentials required for /admin/*, without them the admin routes only answer
+    // requests from this host
+    pub admin_user: Option<String>,
+    pub admin_pass: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            apps: Vec::new(),
+            search_targets: Vec::new(),
+            http_bind_ip: Ipv4Addr::UNSPECIFIED,
+            ssdp_bind_ip: Ipv4Addr::UNSPECIFIED,
+            announce_ip: Ipv4Addr::new(192, 168, 178, 9),
+            http_port: 8081,
+            advertise_port: None,
+            announce_interfaces: Vec::new(),
+            advertise_host: None,
+            port_fallback: false,
+            debug: false,
+            log_format: LogFormat::Text,
+            max_age: MaxAge::default(),
+            server_header: ServerHeader::default(),
+            profile: &profile::DEFAULT,
+            once: false,
+            once_timeout: 300,
+            udp_recv_buffer: None,
+            udp_send_buffer: None,
+            shutdown_grace: 5,
+            shutdown_timeout: 15,
+            multicast_loop: false,
+            loopback: false,
+            unicast_only: false,
+            own_byebye: OwnByebye::Ignore,
+            ssdp_tcp: None,
+            extra_headers: Vec::new(),
+            selftest: false,
+            check_discoverable: false,
+            proxy: None,
+            av_app: None,
+            launch_policy: LaunchPolicy::Reuse,
+            event_webhook: None,
+            lenient_host: false,
+            announce_delay: 300,
+            announce_repeats: 2,
+            announce_cooldown: 10,
+            max_mx: ssdp::MAX_MX,
+            descriptor_content_type: "application/xml; charset=utf-8".to_string(),
+            descriptor: None,
+            device_type: "urn:dial-multiscreen-org:device:dial:1".to_string(),
+            cors_origin: "*".to_string(),
+            capture: None,
+            capture_max_size: 10 * 1024 * 1024,
+            max_body: 64 * 1024,
+            max_uri: 2048,
+            max_payload: 64 * 1024,
+            retry_after: 5,
+            max_dial_data_entries: 32,
+            max_dial_data_bytes: 4096,
+            max_apps: 64,
+            install_url: None,
+            apps_file: None,
+            state_dir: None,
+            admin_user: None,
+            admin_pass: None,
+        }
+    }
+}
+
+fn usage() -> String {
+    [
+        "Usage: dial_server [selftest | check-discoverable] [OPTIONS]",
+        "  selftest                   discover ourselves over loopback, launch and stop a test app",
+        "  check-discoverable         search for a running server (started with the same OPTIONS)",
+        "  --app NAME[=COMMAND]       register an app, COMMAND is run on launch (repeatable)",
+        "  --respond ST USN LOCATION  also answer searches for ST (repeatable)",
+        "  --app-env NAME KEY=VALUE   set an environment variable for the command of an app",
+        "  --app-cwd NAME DIR         run the command of an app in DIR",
+        "  --app-container NAME IMAGE run an app as container of IMAGE instead of a command",
+        "  --app-container-runtime NAME RUNTIME  docker (default) or podman",
+        "  --app-max-runtime NAME SECONDS  stop instances of an app after SECONDS",
+        "  --av-app NAME              launch NAME with the url of AVTransport Play as payload",
+        "  --app-data NAME KEY=VALUE  add <KEY>VALUE</KEY> to the additionalData of an app",
+        "  --installable NAME=URL     register an app that isn't installed (repeatable)",
+        "  --installable NAME         the same with the --install-url",
+        "  --install-url URL          install url of --installable NAME, {name} is replaced with the name",
+        "  --apps-file FILE           keep apps registered with POST /admin/apps in FILE",
+        "  --state-dir DIR            keep the registered apps and the dial_data of the apps in DIR",
+        "  --proxy APPLICATION-URL    forward /apps/* to another DIAL device (e.g. http://tv:8060/apps/)",
+        "  --bind-ip IP               address to bind the sockets to (default 0.0.0.0)",
+        "  --http-bind-ip IP          address to bind the HTTP listener to (overrides --bind-ip)",
+        "  --ssdp-bind-ip IP          address to bind the SSDP socket to (overrides --bind-ip)",
+        "  --announce-ip IP           address advertised to other devices",
+        "  --http-port PORT           port of the HTTP server (default 8081)",
+        "  --advertise-port PORT      port used in LOCATION and Application-URL (default --http-port)",
+        "  --advertise-host NAME      host name used in LOCATION and Application-URL instead of --announce-ip",
+        "  --port-fallback            use the next free port if the HTTP port is taken",
+        "  --multicast-loop           receive our own multicast traffic (for testing on one host)",
+        "  --loopback                 answer searches sent to 127.0.0.1 with a 127.0.0.1 LOCATION",
+        "  --unicast-only             no multicast, only answer searches sent to our address",
+        "  --lenient-host             answer searches with a wrong HOST header (the mismatch is still logged)",
+        "  --udp-recv-buffer BYTES    receive buffer size of the SSDP socket",
+        "  --udp-send-buffer BYTES    send buffer size of the SSDP socket",
+        "  --announce-delay MS        wait before the first announcement (default 300)",
+        "  --announce-repeats COUNT   send every alive/byebye set COUNT times (default 2)",
+        "  --announce-cooldown SECONDS  announce at most once per SECONDS on network changes (default 10)",
+        "  --max-mx SECONDS           delay search responses by at most SECONDS, 0 to 120 (default 5)",
+        "  --max-age SECONDS          max-age of all advertisements (default 900)",
+        "  --max-age-root SECONDS     max-age of the root device advertisements",
+        "  --max-age-device SECONDS   max-age of the device advertisements",
+        "  --max-age-service SECONDS  max-age of the service advertisements",
+        "  --server VALUE             SERVER header of all messages (default from the profile)",
+        "  --server-root VALUE        SERVER header of the root device advertisements",
+        "  --server-device VALUE      SERVER header of the device advertisements",
+        "  --server-service VALUE     SERVER header of the service advertisements",
+        "  --server-http VALUE        SERVER header of the HTTP responses",
+        "  --descriptor FILE          serve FILE as device descriptor (the profile doesn't change it)",
+        "  --max-body BYTES           largest request body that is accepted (default 64 KiB)",
+        "  --max-uri BYTES            longest request target that is accepted (default 2048)",
+        "  --retry-after SECONDS      Retry-After of a failed launch (default 5)",
+        "  --max-payload BYTES        largest launch payload (default 64 KiB)",
+        "  --max-apps COUNT           most apps that can be registered (default 64)",
+        "  --max-dial-data-entries COUNT  most dial_data entries per app (default 32)",
+        "  --max-dial-data-bytes BYTES    most dial_data bytes per app (default 4096)",
+        "  --capture FILE             append every SSDP datagram to FILE (see README for the format)",
+        "  --capture-max-size BYTES   move FILE to FILE.1 once it reaches BYTES (default 10 MiB)",
+        "  --descriptor-content-type TYPE  Content-Type of the descriptor (default application/xml; charset=utf-8)",
+        "  --device-type URN          deviceType of the descriptor and the NOTIFYs (default urn:dial-multiscreen-org:device:dial:1)",
+        "  --cors-origin ORIGIN       Access-Control-Allow-Origin of the descriptor (default *)",
+        "  --admin-user USER          require HTTP Basic auth for /admin/* (with --admin-pass), without it only this host may use /admin/*",
+        "  --admin-pass PASSWORD      password of --admin-user",
+        "  --profile NAME             emulate a device: default, samsung-tv, lg-webos",
+        "  --once                     exit after the first app launch (exit code 1 on timeout)",
+        "  --once-timeout SECONDS     how long --once waits for a launch (default 300)",
+        "  --shutdown-grace SECONDS   time open connections get to finish on shutdown (default 5)",
+        "  --shutdown-timeout SECONDS exit anyway if the shutdown takes longer (default 15)",
+        "  --extra-header NAME:VALUE  add this header to every HTTP response (repeatable)",
+        "  --ssdp-tcp MODE            answer TCP connections to port 1900: descriptor or bad-request",
+        "  --announce-interface IP    announce on the interface with this address, with it in LOCATION (repeatable)",
+        "  --own-byebye ACTION        on a byebye with our UUID: ignore (default) or reannounce",
+        "  --launch-policy POLICY     launching a running app: reuse (default) or relaunch it",
+        "  --event-webhook URL        POST every launch to URL as JSON (http:// only)",
+        "  --debug                    print debug messages",
+        "  --log-format FORMAT        text (default) or json, one object per line",
+    ]
+    .join("\n")
+}
+
+fn parse_value<T: FromStr>(arg: &str, value: Option<String>) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    let value = value.ok_or_else(|| format!("{} requires a value\n{}", arg, usage()))?;
+    value
+        .parse()
+        .map_err(|e| format!("Invalid value for {}: {} ({})\n{}", arg, value, e, usage()))
+}
+
+// the keys of additionalData become element names in the app's namespace
+pub fn is_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+// values that end up verbatim in a header
+fn parse_header_value(arg: &str, value: Option<String>) -> Result<String, String> {
+    let value: String = parse_value(arg, value)?;
+    if HeaderValue::from_str(&value).is_err() {
+        return Err(format!("Invalid value for {}: {}", arg, value));
+    }
+    Ok(value)
+}
+
+// NAME:VALUE, the framing of the response is ours to decide
+fn parse_extra_header(value: &str) -> Result<(String, String), String> {
+    let (name, header_value) = value
+        .split_once(':')
+        .ok_or_else(|| format!("--extra-header requires NAME:VALUE, got {}", value))?;
+    let (name, header_value) = (name.trim(), header_value.trim());
+    if HeaderName::from_bytes(name.as_bytes()).is_err() {
+        return Err(format!(
+            "Invalid header name for --extra-header: {:?}",
+            name
+        ));
+    }
+    if HeaderValue::from_str(header_value).is_err() {
+        return Err(format!(
+            "Invalid header value for --extra-header: {:?}",
+            header_value
+        ));
+    }
+    if [
+        "content-length",
+        "transfer-encoding",
+        "connection",
+        "content-encoding",
+    ]
+    .iter()
+    .any(|framing| name.eq_ignore_ascii_case(framing))
+    {
+        return Err(format!("--extra-header can't set {}", name));
+    }
+    Ok((name.to_string(), header_value.to_string()))
+}
+
+// addresses we bind to or advertise, SSDP is only implemented for IPv4 (the IPv6 groups FF0x::C
+// aren't joined) and the multicast join fails late and confusingly for anything but a unicast
+// or the unspecified address
+fn parse_ipv4(arg: &str, value: Option<String>) -> Result<Ipv4Addr, String> {
+    let value = value.ok_or_else(|| format!("{} requires a value\n{}", arg, usage()))?;
+    let ip = match value.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => ip,
+        Ok(IpAddr::V6(_)) => {
+            return Err(format!(
+                "{}: {} is an IPv6 address, only IPv4 is supported",
+                arg, value
+            ))
+        }
+        Err(_) => {
+            return Err(format!(
+                "{}: {} is not an IPv4 address (like 192.168.1.20)",
+                arg, value
+            ))
+        }
+    };
+    if ip.is_multicast() || ip.is_broadcast() {
+        return Err(format!(
+            "{}: {} is a multicast or broadcast address, use the address of an interface",
+            arg, ip
+        ));
+    }
+    Ok(ip)
+}
+
+// RFC 1123 host names, the urls with it end up verbatim in LOCATION headers
+fn is_host_name(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.trim_end_matches('.').split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+// UDA 1.1 section 2.3: urn:schemas-upnp-org:device:deviceType:ver, or a vendor domain instead of
+// schemas-upnp-org (with periods replaced by hyphens)
+fn is_device_type(urn: &str) -> bool {
+    match urn.split(':').collect::<Vec<_>>()[..] {
+        ["urn", domain, "device", device_type, version] => {
+            !domain.is_empty()
+                && domain
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && !device_type.is_empty()
+                && device_type
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                && !version.is_empty()
+                && version.chars().all(|c| c.is_ascii_digit())
+        }
+        _ => false,
+    }
+}
+
+// options of the form `--option NAME VALUE` that modify an app registered by an earlier --app
+fn app_option<'a>(
+    config: &'a mut Config,
+    arg: &str,
+    name: Option<String>,
+    value: Option<String>,
+) -> Result<(&'a mut AppConfig, String), String> {
+    let (name, value) = name
+        .zip(value)
+        .ok_or_else(|| format!("{} requires NAME and a value\n{}", arg, usage()))?;
+    let app = config
+        .apps
+        .iter_mut()
+        .find(|app| app.name == name)
+        .ok_or_else(|| format!("{}: no app {} registered (use --app first)", arg, name))?;
+    Ok((app, value))
+}
+
+impl Config {
+    /// SERVER header for one kind of message, see ServerHeader.
+    pub fn server<'a>(&'a self, configured: &'a Option<String>) -> &'a str {
+        configured.as_deref().unwrap_or(self.profile.server)
+    }
+
+    /// Base url (`http://<advertise-host or announce-ip>:<advertise-port>`) of the HTTP server as
+    /// seen by other devices.
+    pub fn advertised_base_url(&self) -> String {
+        let port = self.advertise_port.unwrap_or(self.http_port);
+        match &self.advertise_host {
+            Some(host) => format!("http://{}:{}", host, port),
+            None => format!("http://{}:{}", self.announce_ip, port),
+        }
+    }
+
+    /// Base url of the HTTP server as seen from the interface with the address `interface`, see
+    /// --announce-interface.
+    pub fn interface_base_url(&self, interface: Ipv4Addr) -> String {
+        format!(
+            "http://{}:{}",
+            interface,
+            self.advertise_port.unwrap_or(self.http_port)
+        )
+    }
+
+    /// The interfaces the SSDP socket joins the multicast group on.
+    pub fn multicast_interfaces(&self) -> Vec<Ipv4Addr> {
+        if self.announce_interfaces.is_empty() {
+            vec![self.ssdp_bind_ip]
+        } else {
+            self.announce_interfaces.clone()
+        }
+    }
+
+    /// Problems with the addresses that don't prevent starting but likely make us undiscoverable
+    /// or unreachable for other devices.
+    pub fn address_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if let Some(host) = &self.advertise_host {
+            // receivers use their own resolver, but one that fails here likely fails there too
+            if (host.as_str(), self.http_port)
+                .to_socket_addrs()
+                .map_or(true, |mut addrs| addrs.next().is_none())
+            {
+                warnings.push(format!(
+                    "{} doesn't resolve on this host, receivers might not be able to resolve it either",
+                    host
+                ));
+            }
+            return warnings;
+        }
+        // the LOCATIONs we hand out point to the announce address
+        if !self.http_bind_ip.is_unspecified() && self.http_bind_ip != self.announce_ip {
+            warnings.push(format!(
+                "the HTTP listener is bound to {} but LOCATION advertises {}",
+                self.http_bind_ip, self.announce_ip
+            ));
+        }
+        // receivers of our advertisements are on the segment of the SSDP socket, without the
+        // netmask we can only check that the announce address is the one we are bound to there
+        if !self.ssdp_bind_ip.is_unspecified() && self.ssdp_bind_ip != self.announce_ip {
+            warnings.push(format!(
+                "SSDP is bound to {} but LOCATION advertises {}, devices on that segment might not reach it",
+                self.ssdp_bind_ip, self.announce_ip
+            ));
+        }
+        if self.loopback && !self.http_bind_ip.is_unspecified() && !self.http_bind_ip.is_loopback()
+        {
+            warnings.push(format!(
+                "--loopback hands out 127.0.0.1 LOCATIONs but the HTTP listener is bound to {}",
+                self.http_bind_ip
+            ));
+        }
+        warnings
+    }
+
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config::default();
+        // --installable NAME, registered once the --install-url is known
+        let mut default_installable = Vec::new();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--bind-ip" => {
+                    let ip = parse_ipv4(&arg, args.next())?;
+                    config.http_bind_ip = ip;
+                    config.ssdp_bind_ip = ip;
+                }
+                "--http-bind-ip" => config.http_bind_ip = parse_ipv4(&arg, args.next())?,
+                "--ssdp-bind-ip" => config.ssdp_bind_ip = parse_ipv4(&arg, args.next())?,
+                "--announce-ip" => {
+                    config.announce_ip = parse_ipv4(&arg, args.next())?;
+                    if config.announce_ip.is_unspecified() {
+                        return Err(format!(
+                            "{}: other devices can't reach 0.0.0.0, use the address of an interface",
+                            arg
+                        ));
+                    }
+                }
+                "--announce-interface" => {
+                    let ip = parse_ipv4(&arg, args.next())?;
+                    if ip.is_unspecified() {
+                        return Err(format!(
+                            "{}: requires the address of an interface, not 0.0.0.0",
+                            arg
+                        ));
+                    }
+                    if !config.announce_interfaces.contains(&ip) {
+                        config.announce_interfaces.push(ip);
+                    }
+                }
+                "--http-port" => config.http_port = parse_value(&arg, args.next())?,
+                "--advertise-port" => config.advertise_port = Some(parse_value(&arg, args.next())?),
+                "--advertise-host" => {
+                    let host: String = parse_value(&arg, args.next())?;
+                    if !is_host_name(&host) {
+                        return Err(format!("Invalid value for {}: {}", arg, host));
+                    }
+                    config.advertise_host = Some(host);
+                }
+                "--debug" => config.debug = true,
+                "--own-byebye" => {
+                    let action = args
+                        .next()
+                        .ok_or_else(|| format!("--own-byebye requires a value\n{}", usage()))?;
+                    config.own_byebye = match action.as_str() {
+                        "ignore" => OwnByebye::Ignore,
+                        "reannounce" => OwnByebye::Reannounce,
+                        _ => {
+                            return Err(format!(
+                                "--own-byebye requires ignore or reannounce, got {}\n{}",
+                                action,
+                                usage()
+                            ))
+                        }
+                    };
+                }
+                "--log-format" => {
+                    let format = args
+                        .next()
+                        .ok_or_else(|| format!("--log-format requires a value\n{}", usage()))?;
+                    config.log_format = match format.as_str() {
+                        "text" => LogFormat::Text,
+                        "json" => LogFormat::Json,
+                        _ => {
+                            return Err(format!(
+                                "--log-format requires text or json, got {}\n{}",
+                                format,
+                                usage()
+                            ))
+                        }
+                    };
+                }
+                "--udp-recv-buffer" => {
+                    config.udp_recv_buffer = Some(parse_value(&arg, args.next())?)
+                }
+                "--udp-send-buffer" => {
+                    config.udp_send_buffer = Some(parse_value(&arg, args.next())?)
+                }
+                "--once" => config.once = true,
+                "--launch-policy" => {
+                    let policy = args
+                        .next()
+                        .ok_or_else(|| format!("--launch-policy requires a value\n{}", usage()))?;
+                    config.launch_policy = match policy.as_str() {
+                        "reuse" => LaunchPolicy::Reuse,
+                        "relaunch" => LaunchPolicy::Relaunch,
+                        _ => {
+                            return Err(format!(
+                                "--launch-policy requires reuse or relaunch, got {}\n{}",
+                                policy,
+                                usage()
+                            ))
+                        }
+                    };
+                }
+                "--event-webhook" => {
+                    let url: String = parse_value(&arg, args.next())?;
+                    if !url.starts_with("http://") {
+                        return Err(format!(
+                            "--event-webhook requires a http:// url, got {}",
+                            url
+                        ));
+                    }
+                    config.event_webhook = Some(url);
+                }
+                "--proxy" => {
+                    let url: String = parse_value(&arg, args.next())?;
+                    if !url.starts_with("http://") {
+                        return Err(format!("--proxy requires a http:// url, got {}", url));
+                    }
+                    // the app name is appended to the Application-URL
+                    config.proxy = Some(if url.ends_with('/') {
+                        url
+                    } else {
+                        format!("{}/", url)
+                    });
+                }
+                "--av-app" => {
+                    let name: String = parse_value(&arg, args.next())?;
+                    if !config.apps.iter().any(|app| app.name == name) {
+                        return Err(format!(
+                            "{}: no app {} registered (use --app first)",
+                            arg, name
+                        ));
+                    }
+                    config.av_app = Some(name);
+                }
+                "--multicast-loop" => config.multicast_loop = true,
+                "--port-fallback" => config.port_fallback = true,
+                "--loopback" => config.loopback = true,
+                "--unicast-only" => config.unicast_only = true,
+                "--lenient-host" => config.lenient_host = true,
+                "--descriptor" => config.descriptor = Some(parse_value(&arg, args.next())?),
+                "--max-body" => config.max_body = parse_value(&arg, args.next())?,
+                "--max-uri" => {
+                    config.max_uri = parse_value(&arg, args.next())?;
+                    // a longer target doesn't fit into the head anyway, that is answered with 431
+                    if config.max_uri == 0 || config.max_uri > dial::MAX_HEAD_SIZE {
+                        return Err(format!(
+                            "{} has to be between 1 and {}",
+                            arg,
+                            dial::MAX_HEAD_SIZE
+                        ));
+                    }
+                }
+                "--retry-after" => config.retry_after = parse_value(&arg, args.next())?,
+                "--max-payload" => config.max_payload = parse_value(&arg, args.next())?,
+                "--max-apps" => config.max_apps = parse_value(&arg, args.next())?,
+                "--admin-user" => {
+                    let user: String = parse_value(&arg, args.next())?;
+                    // RFC 7617 section 2: the user-id can't contain a colon
+                    if user.contains(':') {
+                        return Err(format!("{} can't contain ':'", arg));
+                    }
+                    config.admin_user = Some(user);
+                }
+                "--apps-file" => config.apps_file = Some(parse_value(&arg, args.next())?),
+                "--state-dir" => config.state_dir = Some(parse_value(&arg, args.next())?),
+                "--admin-pass" => config.admin_pass = Some(parse_value(&arg, args.next())?),
+                "--max-dial-data-entries" => {
+                    config.max_dial_data_entries = parse_value(&arg, args.next())?
+                }
+                "--max-dial-data-bytes" => {
+                    config.max_dial_data_bytes = parse_value(&arg, args.next())?
+                }
+                "--capture" => config.capture = Some(parse_value(&arg, args.next())?),
+                "--capture-max-size" => config.capture_max_size = parse_value(&arg, args.next())?,
+                "--descriptor-content-type" => {
+                    config.descriptor_content_type = parse_header_value(&arg, args.next())?
+                }
+                "--device-type" => {
+                    let device_type: String = parse_value(&arg, args.next())?;
+                    if !is_device_type(&device_type) {
+                        return Err(format!(
+                            "{}: {} isn't urn:DOMAIN:device:TYPE:VERSION",
+                            arg, device_type
+                        ));
+                    }
+                    config.device_type = device_type;
+                }
+                "--cors-origin" => config.cors_origin = parse_header_value(&arg, args.next())?,
+                "--announce-repeats" => {
+                    config.announce_repeats = parse_value(&arg, args.next())?;
+                    if config.announce_repeats == 0 {
+                        return Err(format!("{} has to be at least 1", arg));
+                    }
+                }
+                "--announce-delay" => config.announce_delay = parse_value(&arg, args.next())?,
+                "--announce-cooldown" => config.announce_cooldown = parse_value(&arg, args.next())?,
+                "--max-mx" => config.max_mx = parse_value(&arg, args.next())?,
+                "--shutdown-grace" => config.shutdown_grace = parse_value(&arg, args.next())?,
+                "--shutdown-timeout" => config.shutdown_timeout = parse_value(&arg, args.next())?,
+                "--once-timeout" => config.once_timeout = parse_value(&arg, args.next())?,
+                "--extra-header" => {
+                    let value: String = parse_value(&arg, args.next())?;
+                    config.extra_headers.push(parse_extra_header(&value)?);
+                }
+                "--ssdp-tcp" => {
+                    let mode = args
+                        .next()
+                        .ok_or_else(|| format!("--ssdp-tcp requires a value\n{}", usage()))?;
+                    config.ssdp_tcp = Some(match mode.as_str() {
+                        "descriptor" => SsdpTcp::Descriptor,
+                        "bad-request" => SsdpTcp::BadRequest,
+                        _ => {
+                            return Err(format!(
+                                "--ssdp-tcp requires descriptor or bad-request, got {}\n{}",
+                                mode,
+                                usage()
+                            ))
+                        }
+                    });
+                }
+                "--profile" => {
+                    let name = args
+                        .next()
+                        .ok_or_else(|| format!("--profile requires a value\n{}", usage()))?;
+                    config.profile = profile::find(&name)
+                        .ok_or_else(|| format!("Unknown profile: {}\n{}", name, usage()))?;
+                }
+                "--max-age" => {
+                    let max_age = parse_value(&arg, args.next())?;
+                    config.max_age = MaxAge {
+                        root_device: max_age,
+                        device: max_age,
+                        service: max_age,
+                    };
+                }
+                "--max-age-root" => config.max_age.root_device = parse_value(&arg, args.next())?,
+                "--max-age-device" => config.max_age.device = parse_value(&arg, args.next())?,
+                "--max-age-service" => config.max_age.service = parse_value(&arg, args.next())?,
+                "--server" => {
+                    let server = parse_header_value(&arg, args.next())?;
+                    config.server_header = ServerHeader {
+                        root_device: Some(server.clone()),
+                        device: Some(server.clone()),
+                        service: Some(server.clone()),
+                        http: Some(server),
+                    };
+                }
+                "--server-root" => {
+                    config.server_header.root_device = Some(parse_header_value(&arg, args.next())?)
+                }
+                "--server-device" => {
+                    config.server_header.device = Some(parse_header_value(&arg, args.next())?)
+                }
+                "--server-service" => {
+                    config.server_header.service = Some(parse_header_value(&arg, args.next())?)
+                }
+                "--server-http" => {
+                    config.server_header.http = Some(parse_header_value(&arg, args.next())?)
+                }
+                "--respond" => {
+                    let st = parse_header_value(&arg, args.next())?;
+                    let usn = parse_header_value(&arg, args.next())?;
+                    let location = parse_header_value(&arg, args.next())?;
+                    config
+                        .search_targets
+                        .push(SearchTarget { st, usn, location });
+                }
+                "--app" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("--app requires a value\n{}", usage()))?;
+                    let app = match value.split_once('=') {
+                        Some((name, command)) => AppConfig {
+                            name: name.to_string(),
+                            launch: LaunchConfig {
+                                command: Some(command.to_string()),
+                                ..LaunchConfig::default()
+                            },
+                            ..AppConfig::default()
+                        },
+                        None => AppConfig {
+                            name: value,
+                            ..AppConfig::default()
+                        },
+                    };
+                    if app.name.is_empty() {
+                        return Err(format!("--app requires a name\n{}", usage()));
+                    }
+                    config.apps.push(app);
+                }
+                "--app-env" => {
+                    let (app, value) = app_option(&mut config, &arg, args.next(), args.next())?;
+                    let (key, value) = value.split_once('=').ok_or_else(|| {
+                        format!("--app-env requires KEY=VALUE, got {}\n{}", value, usage())
+                    })?;
+                    app.launch.env.insert(key.to_string(), value.to_string());
+                }
+                "--app-max-runtime" => {
+                    let (app, value) = app_option(&mut config, &arg, args.next(), args.next())?;
+                    let seconds: u64 = parse_value(&arg, Some(value))?;
+                    app.launch.max_runtime = Some(Duration::from_secs(seconds));
+                }
+                "--app-cwd" => {
+                    let (app, value) = app_option(&mut config, &arg, args.next(), args.next())?;
+                    app.launch.cwd = Some(PathBuf::from(value));
+                }
+                "--app-container" => {
+                    let (app, image) = app_option(&mut config, &arg, args.next(), args.next())?;
+                    if app.launch.command.is_some() {
+                        return Err(format!(
+                            "{}: {} already has a command, an app is either a command or a \
+                             container",
+                            arg, app.name
+                        ));
+                    }
+                    app.launch.container = Some(ContainerBackend::new(&image));
+                }
+                "--app-container-runtime" => {
+                    let (app, runtime) = app_option(&mut config, &arg, args.next(), args.next())?;
+                    let name = app.name.clone();
+                    let backend = app.launch.container.as_mut().ok_or_else(|| {
+                        format!(
+                            "{}: {} isn't a container (use --app-container first)",
+                            arg, name
+                        )
+                    })?;
+                    backend.runtime = runtime;
+                }
+                "--app-data" => {
+                    let (app, value) = app_option(&mut config, &arg, args.next(), args.next())?;
+                    let (key, value) = value.split_once('=').ok_or_else(|| {
+                        format!("--app-data requires KEY=VALUE, got {}\n{}", value, usage())
+                    })?;
+                    if !is_xml_name(key) {
+                        return Err(format!(
+                            "--app-data: {} is not a valid XML element name",
+                            key
+                        ));
+                    }
+                    app.additional_data
+                        .insert(key.to_string(), value.to_string());
+                }
+                "--installable" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("--installable requires a value\n{}", usage()))?;
+                    match value.split_once('=') {
+                        Some((name, url)) if !name.is_empty() && !url.is_empty() => {
+                            config.apps.push(AppConfig {
+                                name: name.to_string(),
+                                install_url: Some(url.to_string()),
+                                ..AppConfig::default()
+                            })
+                        }
+                        None if !value.is_empty() => default_installable.push(value),
+                        _ => {
+                            return Err(format!(
+                                "--installable requires NAME=URL or NAME, got {}\n{}",
+                                value,
+                                usage()
+                            ))
+                        }
+                    }
+                }
+                "--install-url" => {
+                    let url = args
+                        .next()
+                        .ok_or_else(|| format!("--install-url requires a value\n{}", usage()))?;
+                    if url.is_empty() {
+                        return Err("--install-url can't be empty".to_string());
+                    }
+                    config.install_url = Some(url);
+                }
+                "selftest" => {
+                    config.selftest = true;
+                    // the selftest searches from this host, it needs to see our own traffic
+                    config.multicast_loop = true;
+                }
+                "check-discoverable" => config.check_discoverable = true,
+                "-h" | "--help" => return Err(usage()),
+                _ => return Err(format!("Unknown argument: {}\n{}", arg, usage())),
+            }
+        }
+
+        for name in default_installable {
+            let url = config.install_url.as_ref().ok_or_else(|| {
+                format!(
+                    "--installable {} requires an url, e.g. with --install-url",
+                    name
+                )
+            })?;
+            config.apps.push(AppConfig {
+                install_url: Some(url.replace("{name}", &name)),
+                name,
+                ..AppConfig::default()
+            });
+        }
+        // the names POST /admin/apps accepts, they end up in urls, XML and file names
+        if let Some(app) = config
+            .apps
+            .iter()
+            .find(|app| !registration::is_app_name(&app.name))
+        {
+            return Err(format!("{:?} can't be used as app name", app.name));
+        }
+        if config.apps.len() > config.max_apps {
+            return Err(format!(
+                "{} apps registered, at most {} are allowed (--max-apps)",
+                config.apps.len(),
+                config.max_apps
+            ));
+        }
+        if config.admin_user.is_some() != config.admin_pass.is_some() {
+            return Err("--admin-user and --admin-pass have to be used together".to_string());
+        }
+        if config.apps_file.is_some() && config.state_dir.is_some() {
+            return Err(
+                "--apps-file and --state-dir can't be used together, the apps are kept in \
+                 apps.json in the --state-dir"
+                    .to_string(),
+            );
+        }
+        if config.ssdp_tcp.is_some() && config.http_port == 1900 {
+            return Err("--ssdp-tcp needs port 1900, it can't be the --http-port".to_string());
+        }
+        if config.max_mx > ssdp::MAX_MX_LIMIT {
+            return Err(format!("--max-mx can be at most {}", ssdp::MAX_MX_LIMIT));
+        }
+        // the watchdog would cut the grace period short
+        if config.shutdown_timeout <= config.shutdown_grace {
+            return Err(format!(
+                "--shutdown-timeout ({}) has to be longer than --shutdown-grace ({})",
+                config.shutdown_timeout, config.shutdown_grace
+            ));
+        }
+        if !config.announce_interfaces.is_empty() {
+            // a socket bound to one address can only send out of that interface
+            if !config.ssdp_bind_ip.is_unspecified() {
+                return Err("--announce-interface needs SSDP bound to 0.0.0.0".to_string());
+            }
+            if config.advertise_host.is_some() {
+                return Err(
+                    "--announce-interface puts the interface address in LOCATION, it can't be combined with --advertise-host"
+                        .to_string(),
+                );
+            }
+            if config.unicast_only {
+                return Err(
+                    "--unicast-only doesn't announce, --announce-interface has no effect"
+                        .to_string(),
+                );
+            }
+        }
+        if config.unicast_only && config.selftest {
+            return Err(
+                "selftest searches the multicast group, it can't run with --unicast-only"
+                    .to_string(),
+            );
+        }
+        if config.selftest {
+            config.apps.push(AppConfig {
+                name: selftest::SELFTEST_APP.to_string(),
+                ..AppConfig::default()
+            });
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, String> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn max_mx_defaults_to_the_spec_cap() {
+        assert_eq!(parse(&[]).unwrap().max_mx, ssdp::MAX_MX);
+    }
+
+    #[test]
+    fn max_mx_can_be_raised_up_to_the_limit() {
+        assert_eq!(parse(&["--max-mx", "0"]).unwrap().max_mx, 0);
+        assert_eq!(parse(&["--max-mx", "30"]).unwrap().max_mx, 30);
+        assert_eq!(parse(&["--max-mx", "120"]).unwrap().max_mx, 120);
+        assert!(parse(&["--max-mx", "121"]).is_err());
+        assert!(parse(&["--max-mx", "-1"]).is_err());
+    }
+
+    #[test]
+    fn app_env_and_cwd_end_up_in_the_launch_config() {
+        let config = parse(&[
+            "--app",
+            "Echo=echo hi",
+            "--app-env",
+            "Echo",
+            "GREETING=hello",
+            "--app-cwd",
+            "Echo",
+            "/srv/echo",
+        ])
+        .unwrap();
+        let launch = &config.apps[0].launch;
+        assert_eq!(launch.command.as_deref(), Some("echo hi"));
+        assert_eq!(launch.env["GREETING"], "hello");
+        assert_eq!(launch.cwd, Some(PathBuf::from("/srv/echo")));
+        assert!(parse(&["--app-env", "Unknown", "A=B"]).is_err());
+    }
+
+    #[test]
+    fn the_descriptor_content_type_must_be_a_header_value() {
+        assert!(parse(&["--descriptor-content-type", "text/xml\r\nX-Injected: 1"]).is_err());
+    }
+
+    #[test]
+    fn http_and_ssdp_can_be_bound_to_different_addresses() {
+        let config = parse(&["--bind-ip", "192.0.2.2", "--announce-ip", "192.0.2.2"]).unwrap();
+        assert_eq!(config.http_bind_ip, config.ssdp_bind_ip);
+
+        let config = parse(&[
+            "--http-bind-ip",
+            "127.0.0.1",
+            "--ssdp-bind-ip",
+            "192.0.2.2",
+            "--announce-ip",
+            "192.0.2.2",
+        ])
+        .unwrap();
+        assert_eq!(config.http_bind_ip, Ipv4Addr::LOCALHOST);
+        assert_eq!(config.ssdp_bind_ip, Ipv4Addr::new(192, 0, 2, 2));
+        assert_eq!(
+            config.advertised_base_url(),
+            format!("http://192.0.2.2:{}", config.http_port)
+        );
+        // LOCATION points to the SSDP segment, the HTTP listener isn't reachable there
+        let warnings = config.address_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("HTTP listener is bound to 127.0.0.1"));
+    }
+
+    #[test]
+    fn an_announce_address_off_the_ssdp_segment_is_warned_about() {
+        let config = parse(&["--ssdp-bind-ip", "192.0.2.3", "--announce-ip", "192.0.2.2"]).unwrap();
+        let warnings = config.address_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("SSDP is bound to 192.0.2.3"));
+    }
+
+    #[test]
+    fn advertised_hosts_must_be_host_names() {
+        assert!(parse(&["--advertise-host", "tv.example"]).is_ok());
+        for host in ["tv example", "tv\r\nX-Injected: 1", "http://tv", ""] {
+            assert!(parse(&["--advertise-host", host]).is_err(), "{:?}", host);
+        }
+    }
+
+    #[test]
+    fn advertised_hosts_that_dont_resolve_are_warned_about() {
+        let config = parse(&["--advertise-host", "localhost"]).unwrap();
+        assert!(config.address_warnings().is_empty());
+        // RFC 6761: .invalid never resolves
+        let config = parse(&["--advertise-host", "tv.invalid"]).unwrap();
+        let warnings = config.address_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("tv.invalid doesn't resolve"));
+    }
+
+    #[test]
+    fn bind_addresses_must_be_ipv4_unicast() {
+        for (arg, value, reason) in [
+            ("--bind-ip", "192.168.1.300", "is not an IPv4 address"),
+            ("--bind-ip", "tv.local", "is not an IPv4 address"),
+            ("--ssdp-bind-ip", "::1", "is an IPv6 address"),
+            (
+                "--http-bind-ip",
+                "239.255.255.250",
+                "multicast or broadcast",
+            ),
+            (
+                "--ssdp-bind-ip",
+                "255.255.255.255",
+                "multicast or broadcast",
+            ),
+            ("--announce-ip", "0.0.0.0", "can't reach 0.0.0.0"),
+        ] {
+            let e = parse(&[arg, value]).unwrap_err();
+            assert!(e.starts_with(arg) && e.contains(reason), "{}", e);
+        }
+        assert!(parse(&["--bind-ip", "0.0.0.0"]).is_ok());
+    }
+
+    #[test]
+    fn the_number_of_apps_is_capped() {
+        let apps = ["--max-apps", "2", "--app", "A", "--app", "B"];
+        assert_eq!(parse(&apps).unwrap().apps.len(), 2);
+        let e = parse(&[&apps[..], &["--app", "C"]].concat()).unwrap_err();
+        assert!(e.contains("--max-apps"), "{}", e);
+    }
+
+    #[test]
+    fn tcp_on_the_ssdp_port_is_off_by_default() {
+        assert_eq!(parse(&[]).unwrap().ssdp_tcp, None);
+        let mode = |value: &str| parse(&["--ssdp-tcp", value]).map(|config| config.ssdp_tcp);
+        assert_eq!(mode("descriptor").unwrap(), Some(SsdpTcp::Descriptor));
+        assert_eq!(mode("bad-request").unwrap(), Some(SsdpTcp::BadRequest));
+        assert!(mode("echo").is_err());
+        assert!(parse(&["--ssdp-tcp", "descriptor", "--http-port", "1900"]).is_err());
+    }
+
+    #[test]
+    fn extra_headers_must_be_valid_and_leave_the_framing_alone() {
+        let config = parse(&[
+            "--extra-header",
+            "X-Vendor: Acme TV",
+            "--extra-header",
+            "X-Model:42",
+        ]);
+        assert_eq!(
+            config.unwrap().extra_headers,
+            [
+                ("X-Vendor".to_string(), "Acme TV".to_string()),
+                ("X-Model".to_string(), "42".to_string())
+            ]
+        );
+        for invalid in [
+            "X-Vendor",
+            "X Vendor: Acme",
+            "X-Vendor: Acme\r\nX-Injected: 1",
+            "Content-Length: 0",
+            "connection: close",
+        ] {
+            assert!(
+                parse(&["--extra-header", invalid]).is_err(),
+                "{:?}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn announce_interfaces_need_a_wildcard_ssdp_socket() {
+        let config = parse(&[
+            "--announce-interface",
+            "192.0.2.2",
+            "--announce-interface",
+            "198.51.100.2",
+            "--announce-interface",
+            "192.0.2.2",
+            "--advertise-port",
+            "80",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.announce_interfaces,
+            [Ipv4Addr::new(192, 0, 2, 2), Ipv4Addr::new(198, 51, 100, 2)]
+        );
+        assert_eq!(
+            config.interface_base_url(Ipv4Addr::new(198, 51, 100, 2)),
+            "http://198.51.100.2:80"
+        );
+        assert!(parse(&["--announce-interface", "0.0.0.0"]).is_err());
+        let interface = ["--announce-interface", "192.0.2.2"];
+        for conflicting in [
+            &["--ssdp-bind-ip", "192.0.2.2"][..],
+            &["--advertise-host", "tv.local"],
+            &["--unicast-only"],
+        ] {
+            assert!(parse(&[&interface[..], conflicting].concat()).is_err());
+        }
+    }
+
+    #[test]
+    fn app_names_are_checked_like_registered_ones() {
+        assert!(parse(&["--app", "YouTube", "--app", "my-app_2.0=true"]).is_ok());
+        for name in ["a/b", "<x>", "a b", "_list", "Netflix&x"] {
+            assert!(parse(&["--app", name]).is_err(), "{}", name);
+            assert!(
+                parse(&["--installable", &format!("{}=http://store/", name)]).is_err(),
+                "{}",
+                name
+            );
+        }
+        let error = parse(&[
+            "--installable",
+            "a/b",
+            "--install-url",
+            "http://store/{name}",
+        ])
+        .expect_err("a/b isn't an app name");
+        assert!(error.contains("a/b"), "{}", error);
+    }
+}
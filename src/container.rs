@@ -0,0 +1,149 @@
+// Apps that are started as a container (--app-container) instead of a shell command. The
+// container runs in the foreground of `docker run`, so the launched process lives exactly as long
+// as the container and the exit of either is noticed like the one of a command.
+use std::collections::HashMap;
+use std::io;
+use std::process::{Child, Command};
+
+/// A container runtime with a docker compatible command line (docker, podman) and the image it
+/// starts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerBackend {
+    pub runtime: String,
+    pub image: String,
+}
+
+impl ContainerBackend {
+    pub fn new(image: &str) -> ContainerBackend {
+        ContainerBackend {
+            runtime: "docker".to_string(),
+            image: image.to_string(),
+        }
+    }
+
+    /// The container of an instance is named after the app and its run id, so it can be stopped
+    /// by name and is easy to find in `docker ps`.
+    pub fn container_name(app: &str, run_id: u64) -> String {
+        let app: String = app
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        format!("dial-{}-{}", app, run_id)
+    }
+
+    /// `docker run --rm --name NAME -e DIAL_PAYLOAD=... IMAGE`, the container is removed once it
+    /// stops.
+    pub fn run_command(
+        &self,
+        container_name: &str,
+        payload: &str,
+        env: &HashMap<String, String>,
+    ) -> Command {
+        let mut command = Command::new(&self.runtime);
+        command.args(["run", "--rm", "--name", container_name]);
+        for (key, value) in env {
+            command.arg("--env").arg(format!("{}={}", key, value));
+        }
+        command
+            .arg("--env")
+            .arg(format!("DIAL_PAYLOAD={}", payload))
+            .arg(&self.image);
+        command
+    }
+
+    /// `docker stop NAME`, stopping also removes the container (see run_command).
+    pub fn stop_command(&self, container_name: &str) -> Command {
+        let mut command = Command::new(&self.runtime);
+        command.args(["stop", container_name]);
+        command
+    }
+
+    /// Spawns `command`, a missing runtime gets an error that says so instead of just "No such
+    /// file or directory".
+    pub fn spawn(&self, command: &mut Command) -> io::Result<Child> {
+        command.spawn().map_err(|e| {
+            if e.kind() == io::ErrorKind::NotFound {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "container runtime {} not found (is it installed and in PATH?)",
+                        self.runtime
+                    ),
+                )
+            } else {
+                e
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn run_and_stop_command_lines() {
+        let backend = ContainerBackend::new("example/receiver:1");
+        let name = ContainerBackend::container_name("YouTube", 7);
+        assert_eq!(name, "dial-YouTube-7");
+        let env = HashMap::from([("MODE".to_string(), "tv".to_string())]);
+
+        let run = backend.run_command(&name, "v=abc&t=1", &env);
+        assert_eq!(run.get_program(), "docker");
+        assert_eq!(
+            args(&run),
+            [
+                "run",
+                "--rm",
+                "--name",
+                "dial-YouTube-7",
+                "--env",
+                "MODE=tv",
+                "--env",
+                "DIAL_PAYLOAD=v=abc&t=1",
+                "example/receiver:1",
+            ]
+        );
+        let stop = backend.stop_command(&name);
+        assert_eq!(stop.get_program(), "docker");
+        assert_eq!(args(&stop), ["stop", "dial-YouTube-7"]);
+    }
+
+    #[test]
+    fn container_names_only_keep_safe_characters() {
+        assert_eq!(
+            ContainerBackend::container_name("My App/2", 1),
+            "dial-My_App_2-1"
+        );
+    }
+
+    #[test]
+    fn a_missing_runtime_is_named_in_the_error() {
+        let backend = ContainerBackend {
+            runtime: "dial-server-missing-runtime".to_string(),
+            image: "example/receiver:1".to_string(),
+        };
+        let mut command = backend.stop_command("dial-YouTube-7");
+        let e = backend.spawn(&mut command).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(
+            e.to_string()
+                .contains("dial-server-missing-runtime not found"),
+            "{}",
+            e
+        );
+    }
+}
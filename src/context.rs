@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use tokio::sync::{mpsc, oneshot, watch, Notify, RwLock};
+
+use crate::apps::AppRegistry;
+use crate::avtransport::TransportState;
+use crate::clock::{Clock, TokioClock};
+use crate::config::Config;
+use crate::dial::{self, DescriptorRenderer, DeviceConfig};
+use crate::error::DialError;
+use crate::gena::Subscriptions;
+use crate::ssdp::{self, MulticastStatus, RecentSearches};
+use crate::store::{self, StateStore};
+
+/// Cloneable token to request the shutdown of the server and to wait for that request.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    // Some(exit code) once the shutdown was requested
+    sender: Arc<watch::Sender<Option<i32>>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (sender, _) = watch::channel(None);
+        Shutdown {
+            sender: Arc::new(sender),
+        }
+    }
+
+    /// Requests the shutdown, only the exit code of the first request is kept.
+    pub fn trigger(&self, exit_code: i32) {
+        self.sender.send_if_modified(|value| {
+            if value.is_none() {
+                *value = Some(exit_code);
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    pub fn requested(&self) -> bool {
+        self.sender.borrow().is_some()
+    }
+
+    /// Waits until the shutdown is requested and returns the requested exit code.
+    pub async fn wait(&self) -> i32 {
+        let mut receiver = self.sender.subscribe();
+        let exit_code = receiver
+            .wait_for(Option::is_some)
+            .await
+            .expect("sender is alive as long as self");
+        exit_code.expect("waited for Some")
+    }
+}
+
+/// The requests that are being handled right now, so a shutdown that doesn't finish can say what
+/// it was waiting for.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    next_id: AtomicU64,
+    requests: Mutex<BTreeMap<u64, String>>,
+}
+
+/// Removes its request from the in-flight requests when dropped.
+pub struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    id: u64,
+}
+
+impl InFlight {
+    pub fn start(&self, description: String) -> InFlightGuard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.requests
+            .lock()
+            .expect("in-flight lock poisoned")
+            .insert(id, description);
+        InFlightGuard {
+            in_flight: self,
+            id,
+        }
+    }
+
+    /// The requests in the order they arrived.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests
+            .lock()
+            .expect("in-flight lock poisoned")
+            .values()
+            .cloned()
+            .collect()
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.in_flight
+            .requests
+            .lock()
+            .expect("in-flight lock poisoned")
+            .remove(&self.id);
+    }
+}
+
+/// Asks the announcing task to send the alive messages right away, answered with the number of
+/// messages sent.
+pub type AnnounceRequest = oneshot::Sender<std::io::Result<usize>>;
+
+// how many searches GET /admin/searches returns
+const RECENT_SEARCHES: usize = 100;
+
+/// State shared by the HTTP and SSDP side of the server, created once in main and cloned into
+/// every task that needs it.
+#[derive(Debug, Clone)]
+pub struct AppContext {
+    pub config: Arc<Config>,
+    // status requests only take the read lock, launches and stops the write lock
+    pub apps: Arc<RwLock<AppRegistry>>,
+    // notified whenever a new app instance was launched
+    pub launched: Arc<Notify>,
+    pub shutdown: Shutdown,
+    pub descriptor_renderer: DescriptorRenderer,
+    pub multicast: Arc<MulticastStatus>,
+    // media url pushed by an AVTransport control point
+    pub av_transport: Arc<Mutex<TransportState>>,
+    // GENA subscribers of the AVTransport events
+    pub subscriptions: Arc<Mutex<Subscriptions>>,
+    // true while SSDP is paused with POST /admin/ssdp/pause, the announcing task sends the byebye
+    // (or the alive messages on resume) when it changes
+    pub ssdp_paused: Arc<watch::Sender<bool>>,
+    // notified to announce right away instead of waiting for the next re-announcement
+    pub reannounce: Arc<Notify>,
+    // POST /admin/announce, answered by the announcing task. None until that task is started
+    pub announce: Option<mpsc::Sender<AnnounceRequest>>,
+    // requests that are being handled, logged if the shutdown has to be forced
+    pub in_flight: Arc<InFlight>,
+    // the registered apps and dial_data, see --state-dir
+    pub store: Arc<dyn StateStore>,
+    // CONFIGID.UPNP.ORG of the advertisements, derived from the rendered descriptor once the
+    // renderer is final, see config_id()
+    config_id: Arc<OnceLock<u32>>,
+    // set once the sockets are bound, the apps are loaded and the first announcement is sent
+    pub started: Arc<AtomicBool>,
+    // GET /admin/searches
+    pub recent_searches: Arc<RecentSearches>,
+    // what the re-announcements, MX delays, max_runtime and request timeouts wait on, tests
+    // replace it with a MockClock
+    pub clock: Arc<dyn Clock>,
+}
+
+impl AppContext {
+    pub fn new(config: Config) -> AppContext {
+        let mut apps = AppRegistry::new();
+        for app in &config.apps {
+            info!("Registering app {}", app.name);
+            match &app.install_url {
+                Some(url) => apps.register_installable(&app.name, url),
+                None => apps.register(&app.name, app.launch.clone()),
+            }
+            if let Some(registered) = apps.get_mut(&app.name) {
+                registered.additional_data = app.additional_data.clone();
+            }
+        }
+
+        let store = store::from_config(&config);
+        AppContext {
+            multicast: Arc::new(MulticastStatus::new(config.ssdp_bind_ip)),
+            config: Arc::new(config),
+            apps: Arc::new(RwLock::new(apps)),
+            launched: Arc::new(Notify::new()),
+            shutdown: Shutdown::new(),
+            descriptor_renderer: DescriptorRenderer::default(),
+            av_transport: Arc::new(Mutex::new(TransportState::default())),
+            subscriptions: Arc::new(Mutex::new(Subscriptions::default())),
+            ssdp_paused: Arc::new(watch::channel(false).0),
+            reannounce: Arc::new(Notify::new()),
+            announce: None,
+            in_flight: Arc::new(InFlight::default()),
+            store,
+            config_id: Arc::new(OnceLock::new()),
+            started: Arc::new(AtomicBool::new(false)),
+            recent_searches: Arc::new(RecentSearches::new(RECENT_SEARCHES)),
+            clock: Arc::new(TokioClock),
+        }
+    }
+
+    /// Replaces the device descriptor, e.g. to add vendor elements. The renderer is run once
+    /// to check that it produces well-formed XML.
+    pub fn with_descriptor_renderer(
+        mut self,
+        render: impl Fn(&DeviceConfig) -> String + Send + Sync + 'static,
+    ) -> Result<AppContext, DialError> {
+        let renderer = DescriptorRenderer::new(render);
+        let descriptor = renderer.render(&self.config);
+        dial::check_well_formed(&descriptor)
+            .map_err(|e| DialError::Config(format!("Invalid device descriptor: {}", e)))?;
+        self.descriptor_renderer = renderer;
+        self.config_id = Arc::new(OnceLock::from(ssdp::config_id(&descriptor)));
+        Ok(self)
+    }
+
+    /// Pauses or resumes SSDP, returns false if it already was in that state.
+    pub fn set_ssdp_paused(&self, paused: bool) -> bool {
+        self.ssdp_paused.send_if_modified(|value| {
+            let changed = *value != paused;
+            *value = paused;
+            changed
+        })
+    }
+
+    pub fn is_ssdp_paused(&self) -> bool {
+        *self.ssdp_paused.borrow()
+    }
+
+    /// CONFIGID.UPNP.ORG, see ssdp::config_id. The descriptor is only rendered for it on first
+    /// use, after main replaced the renderer.
+    pub fn config_id(&self) -> u32 {
+        *self
+            .config_id
+            .get_or_init(|| ssdp::config_id(&self.descriptor_renderer.render(&self.config)))
+    }
+
+    pub fn set_started(&self) {
+        self.started.store(true, Ordering::Relaxed);
+    }
+
+    /// Started and not shutting down, see GET /readyz.
+    pub fn is_ready(&self) -> bool {
+        self.started.load(Ordering::Relaxed) && !self.shutdown.requested()
+    }
+
+    /// see Config::advertised_base_url
+    pub fn base_url(&self) -> String {
+        self.config.advertised_base_url()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile;
+
+    fn with_friendly_name(name: &'static str) -> AppContext {
+        let xml = AppContext::new(Config::default())
+            .descriptor_renderer
+            .render(&Config::default());
+        let xml = xml.replace("<friendlyName>", &format!("<friendlyName>{} ", name));
+        AppContext::new(Config::default())
+            .with_descriptor_renderer(move |_| xml.clone())
+            .expect("well-formed descriptor")
+    }
+
+    #[test]
+    fn config_id_is_stable_for_the_same_descriptor() {
+        let first = AppContext::new(Config::default()).config_id();
+        assert_eq!(first, AppContext::new(Config::default()).config_id());
+        assert_eq!(
+            with_friendly_name("A").config_id(),
+            with_friendly_name("A").config_id()
+        );
+    }
+
+    #[test]
+    fn config_id_changes_with_the_friendly_name() {
+        assert_ne!(
+            with_friendly_name("A").config_id(),
+            with_friendly_name("B").config_id()
+        );
+        let samsung = Config {
+            profile: &profile::SAMSUNG_TV,
+            ..Config::default()
+        };
+        assert_ne!(
+            AppContext::new(Config::default()).config_id(),
+            AppContext::new(samsung).config_id()
+        );
+    }
+
+    #[test]
+    fn config_id_follows_the_replaced_renderer() {
+        let ctx = AppContext::new(Config::default());
+        let default_id = ctx.config_id();
+        let replaced = ctx
+            .with_descriptor_renderer(|_| {
+                "<root><friendlyName>Other</friendlyName></root>".to_string()
+            })
+            .expect("well-formed descriptor");
+        assert_ne!(default_id, replaced.config_id());
+        assert_eq!(
+            replaced.config_id(),
+            ssdp::config_id("<root><friendlyName>Other</friendlyName></root>")
+        );
+    }
+}
@@ -0,0 +1,2495 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use http::header::{HeaderName, HeaderValue};
+use http::{Method, Request, Response, StatusCode, Version};
+use tokio::sync::oneshot;
+
+use crate::apps::{self, AppRegistry, AppState, LaunchError, StopError};
+use crate::avtransport;
+use crate::config::{self, Config};
+use crate::context::AppContext;
+use crate::error::DialError;
+use crate::events;
+use crate::gena;
+use crate::json;
+use crate::proxy;
+use crate::registration;
+use crate::store;
+
+// DIAL 2.2.1 section 5.4: the app resources live below the Application-URL
+const APPS_PATH: &str = "/apps/";
+// not a valid app name (DIAL app names are registered and don't start with '_'),
+// so this can't conflict with a real application resource
+const APPS_LIST_PATH: &str = "/apps/_list";
+// JSON with diagnostics about the server itself
+const STATUS_PATH: &str = "/status";
+const HEALTH_PATH: &str = "/healthz";
+const READY_PATH: &str = "/readyz";
+// everything below it requires the --admin-user credentials (if set)
+const ADMIN_PATH: &str = "/admin/";
+// POST: stop answering searches and announcing (after a byebye) until resumed
+const SSDP_PAUSE_PATH: &str = "/admin/ssdp/pause";
+const SSDP_RESUME_PATH: &str = "/admin/ssdp/resume";
+const ANNOUNCE_PATH: &str = "/admin/announce";
+const SEARCHES_PATH: &str = "/admin/searches";
+pub const DESCRIPTOR_PATH: &str = "/upnp_device_descriptor.xml";
+
+// longest a GET of the app status with ?wait is held back
+const STATUS_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+const STATUS_WAIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// compressing smaller bodies isn't worth it, the gzip header and trailer alone are 18 bytes
+const MIN_GZIP_SIZE: usize = 512;
+
+// longest request line plus headers we accept, the body is limited by --max-body
+pub const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// Length of the first request in `data` (head and a body framed by Content-Length or chunked
+/// Transfer-Encoding), None if it isn't complete yet. The error is the status to answer with
+/// before closing the connection, it is known as soon as the head is there (or the request
+/// target is longer than `max_uri`).
+pub fn request_length(
+    data: &[u8],
+    max_uri: usize,
+    max_body: usize,
+) -> Result<Option<usize>, StatusCode> {
+    // RFC 9112 section 3: METHOD SP request-target SP HTTP-version, checked before the rest of the
+    // head arrived so a client can't make us buffer a huge target
+    let line_end = data
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .unwrap_or(data.len());
+    let target_length = data[..line_end]
+        .split(u8::is_ascii_whitespace)
+        .filter(|word| !word.is_empty())
+        .nth(1)
+        .map_or(0, <[u8]>::len);
+    if target_length > max_uri {
+        return Err(StatusCode::URI_TOO_LONG);
+    }
+    let head_end = match data.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(head_end) => head_end,
+        None if data.len() > MAX_HEAD_SIZE => {
+            return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        }
+        None => return Ok(None),
+    };
+    if head_end > MAX_HEAD_SIZE {
+        return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+    let head = String::from_utf8_lossy(&data[..head_end]);
+    let body = &data[head_end + 4..];
+    // RFC 9112 section 6.3: chunked wins over Content-Length
+    let body_length = if head_has_token(&head, "transfer-encoding", "chunked") {
+        match decode_chunked(body, max_body)? {
+            Some((length, _)) => length,
+            None => return Ok(None),
+        }
+    } else {
+        let content_length = head_header(&head, "content-length").map(str::parse::<usize>);
+        match content_length {
+            None => 0,
+            Some(Ok(length)) if length <= max_body => length,
+            Some(Ok(_)) => return Err(StatusCode::PAYLOAD_TOO_LARGE),
+            Some(Err(_)) => return Err(StatusCode::BAD_REQUEST),
+        }
+    };
+    let length = head_end + 4 + body_length;
+    Ok((data.len() >= length).then_some(length))
+}
+
+/// True if `data` starts with the complete head of a request that waits for a 100 Continue
+/// before it sends its body (RFC 9110 section 10.1.1), curl does that for larger launch payloads.
+pub fn expects_continue(data: &[u8]) -> bool {
+    match data.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(head_end) => head_has_token(
+            &String::from_utf8_lossy(&data[..head_end]),
+            "expect",
+            "100-continue",
+        ),
+        None => false,
+    }
+}
+
+fn head_header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+fn head_has_token(head: &str, name: &str, token: &str) -> bool {
+    head_header(head, name)
+        .map(|value| {
+            value
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token))
+        })
+        .unwrap_or(false)
+}
+
+// RFC 9112 section 7.1: every chunk is its size in hex (maybe followed by extensions after a
+// ';'), CRLF, the data and another CRLF. A chunk of size 0 ends the body, it is followed by the
+// (ignored) trailer fields and an empty line. Returns the length on the wire and the decoded
+// body, None while it isn't complete.
+fn decode_chunked(body: &[u8], max_body: usize) -> Result<Option<(usize, Vec<u8>)>, StatusCode> {
+    let find_crlf = |data: &[u8]| data.windows(2).position(|window| window == b"\r\n");
+    let mut decoded = Vec::new();
+    let mut position = 0;
+    loop {
+        let Some(line_end) = find_crlf(&body[position..]) else {
+            return Ok(None);
+        };
+        let line = String::from_utf8_lossy(&body[position..position + line_end]);
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| StatusCode::BAD_REQUEST)?;
+        position += line_end + 2;
+        if size == 0 {
+            break;
+        }
+        if size > max_body - decoded.len() {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        let end = position + size;
+        if body.len() < end + 2 {
+            return Ok(None);
+        }
+        if &body[end..end + 2] != b"\r\n" {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        decoded.extend_from_slice(&body[position..end]);
+        position = end + 2;
+    }
+    loop {
+        let Some(line_end) = find_crlf(&body[position..]) else {
+            return Ok(None);
+        };
+        position += line_end + 2;
+        if line_end == 0 {
+            return Ok(Some((position, decoded)));
+        }
+    }
+}
+
+/// Parses a HTTP request, the error describes why the request is malformed (answered with 400).
+pub fn parse_request(text: &str) -> Result<Request<String>, DialError> {
+    let (head, body) = text.split_once("\r\n\r\n").unwrap_or((text, ""));
+    let mut lines = head.lines();
+
+    let first_line = lines
+        .next()
+        .ok_or_else(|| DialError::Parse("empty request".to_string()))?;
+    // METHOD PATH PROTOCOL, some clients pad the tokens with extra spaces or tabs
+    let words: Vec<&str> = first_line.split_whitespace().collect();
+    if words.len() != 3 {
+        return Err(DialError::Parse(format!(
+            "invalid request line (expected 3 tokens, got {}): {}",
+            words.len(),
+            first_line
+        )));
+    }
+    let (method, path, protocol) = (words[0], words[1], words[2]);
+    info!("method: {} path: {} protocol: {}", method, path, protocol);
+    let version = match protocol {
+        "HTTP/1.0" => Version::HTTP_10,
+        "HTTP/1.1" => Version::HTTP_11,
+        _ => {
+            return Err(DialError::Parse(format!(
+                "unsupported protocol: {}",
+                protocol
+            )))
+        }
+    };
+
+    let mut builder = Request::builder().method(method).uri(path).version(version);
+    for line in lines {
+        if line.is_empty() {
+            continue;
+        }
+
+        match line.split_once(": ") {
+            Some((key, value)) => builder = builder.header(key, value),
+            None => info!("Invalid header line: {}", line),
+        }
+    }
+
+    // request_length made sure the body is complete and within --max-body
+    let body = if head_has_token(head, "transfer-encoding", "chunked") {
+        match decode_chunked(body.as_bytes(), usize::MAX) {
+            Ok(Some((_, decoded))) => String::from_utf8(decoded)
+                .map_err(|_| DialError::Parse("chunked body isn't utf-8".to_string()))?,
+            _ => return Err(DialError::Parse("invalid chunked body".to_string())),
+        }
+    } else {
+        body.to_string()
+    };
+
+    builder
+        .body(body)
+        .map_err(|e| DialError::Parse(e.to_string()))
+}
+
+/// Serializes the whole response (status line, headers and body) into one buffer so it can be sent
+/// with a single write instead of one small TCP segment per part.
+pub fn response_to_bytes(response: &Response<Vec<u8>>) -> Vec<u8> {
+    let status = response.status();
+    let version = match response.version() {
+        Version::HTTP_10 => "HTTP/1.0",
+        _ => "HTTP/1.1",
+    };
+    let mut head = format!(
+        "{} {} {}\r\n",
+        version,
+        status.as_str(),
+        status.canonical_reason().unwrap_or("")
+    );
+    for (key, value) in response.headers() {
+        head.push_str(&format!(
+            "{}: {}\r\n",
+            key,
+            value.to_str().expect("cant convert values to string")
+        ));
+    }
+    // RFC 9110 section 8.6: 1xx and 204 responses must not have a Content-Length, responses to HEAD
+    // already carry the one of the GET
+    if !status.is_informational()
+        && status != StatusCode::NO_CONTENT
+        && !response.headers().contains_key("content-length")
+    {
+        head.push_str(&format!("content-length: {}\r\n", response.body().len()));
+    }
+    head.push_str("\r\n");
+
+    let mut bytes = Vec::with_capacity(head.len() + response.body().len());
+    bytes.extend_from_slice(head.as_bytes());
+    bytes.extend_from_slice(response.body());
+    bytes
+}
+
+/// RFC 9112 section 9.3: HTTP/1.1 connections stay open unless the client sends
+/// `Connection: close`, HTTP/1.0 connections are closed unless it sends `Connection: keep-alive`.
+/// `close` wins in both versions, even next to `keep-alive`.
+pub fn keep_alive(request: &Request<String>) -> bool {
+    let has_option = |option: &str| {
+        request
+            .headers()
+            .get_all("connection")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(option))
+    };
+    match request.version() {
+        _ if has_option("close") => false,
+        Version::HTTP_10 => has_option("keep-alive"),
+        _ => true,
+    }
+}
+
+pub fn response(status: StatusCode) -> Response<Vec<u8>> {
+    let mut response = Response::new(Vec::new());
+    *response.status_mut() = status;
+    response
+}
+
+pub fn response_with_body(
+    status: StatusCode,
+    content_type: &str,
+    body: String,
+) -> Response<Vec<u8>> {
+    let mut response = response(status);
+    response.headers_mut().insert(
+        "content-type",
+        HeaderValue::from_str(content_type).expect("Invalid content type"),
+    );
+    *response.body_mut() = body.into_bytes();
+    response
+}
+
+pub async fn handle_request(ctx: &AppContext, request: &Request<String>) -> Response<Vec<u8>> {
+    let path = request.uri().path();
+    let mut response = match (&ctx.config.proxy, path.strip_prefix(APPS_PATH)) {
+        (Some(downstream_url), Some(app_path)) if path != APPS_LIST_PATH => {
+            let apps_url = format!("{}{}", ctx.base_url(), APPS_PATH);
+            proxy::forward(downstream_url, &apps_url, app_path, request).await
+        }
+        _ => route(ctx, request).await,
+    };
+    if response.body().len() >= MIN_GZIP_SIZE {
+        // RFC 9110 section 12.5.5: whether we compress depends on Accept-Encoding, also for
+        // clients that don't accept gzip, otherwise caches hand them the compressed body
+        vary(&mut response, "Accept-Encoding");
+        if accepts_gzip(request) {
+            gzip_body(&mut response);
+        }
+    }
+
+    let headers = response.headers_mut();
+    let server = ctx.config.server(&ctx.config.server_header.http);
+    headers.insert(
+        "server",
+        HeaderValue::from_str(server).expect("Invalid SERVER header"),
+    );
+    for (key, value) in ctx.config.profile.extra_headers {
+        headers.insert(*key, HeaderValue::from_static(value));
+    }
+    // validated when the arguments were parsed
+    for (key, value) in &ctx.config.extra_headers {
+        headers.insert(
+            HeaderName::from_bytes(key.as_bytes()).expect("Invalid --extra-header name"),
+            HeaderValue::from_str(value).expect("Invalid --extra-header value"),
+        );
+    }
+    // RFC 9110 section 9.3.2: HEAD gets the headers of the GET, without the body
+    if request.method() == Method::HEAD {
+        let length = response.body().len();
+        response
+            .headers_mut()
+            .insert("content-length", HeaderValue::from(length));
+        response.body_mut().clear();
+    }
+    response
+}
+
+fn accepts_gzip(request: &Request<String>) -> bool {
+    request
+        .headers()
+        .get_all("accept-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            // "gzip;q=0" explicitly forbids gzip
+            let forbidden = params.any(|param| {
+                param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+            });
+            name.eq_ignore_ascii_case("gzip") && !forbidden
+        })
+}
+
+fn gzip_body(response: &mut Response<Vec<u8>>) {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let compressed = match encoder
+        .write_all(response.body())
+        .and_then(|_| encoder.finish())
+    {
+        Ok(compressed) => compressed,
+        Err(e) => {
+            info!(
+                "Failed to compress response, sending it uncompressed: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    response
+        .headers_mut()
+        .insert("content-encoding", HeaderValue::from_static("gzip"));
+    *response.body_mut() = compressed;
+}
+
+/// The resources below the Application-URL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AppResource<'a> {
+    // /apps/<name>: status (GET) and launch (POST)
+    App(&'a str),
+    // /apps/<name>/run/<run id>: the instance we return as LOCATION on launch. Without the run id
+    // (the `run` link of the app status) it is whichever instance is running.
+    Instance(&'a str, Option<u64>),
+    // /apps/<name>/dial_data
+    DialData(&'a str),
+}
+
+// `app_path` is the path after /apps/ (without the query), None for unknown subresources
+fn parse_app_path(app_path: &str) -> Option<AppResource<'_>> {
+    match app_path.split_once('/') {
+        None => Some(AppResource::App(app_path)),
+        Some((name, "run")) => Some(AppResource::Instance(name, None)),
+        Some((name, "dial_data")) => Some(AppResource::DialData(name)),
+        Some((name, rest)) => {
+            let run_id = rest.strip_prefix("run/")?.parse().ok()?;
+            Some(AppResource::Instance(name, Some(run_id)))
+        }
+    }
+}
+
+// every method route() answers on at least one path, the Allow header of OPTIONS * lists them
+const ROUTED_METHODS: &[&str] = &[
+    "GET",
+    "HEAD",
+    "POST",
+    "DELETE",
+    "OPTIONS",
+    "SUBSCRIBE",
+    "UNSUBSCRIBE",
+];
+
+async fn route(ctx: &AppContext, request: &Request<String>) -> Response<Vec<u8>> {
+    let path = request.uri().path();
+    let method = request.method();
+
+    if path.starts_with(ADMIN_PATH) && !is_admin(ctx, request) {
+        if !admin_credentials_configured(&ctx.config) {
+            let peer = request.extensions().get::<SocketAddr>();
+            info!(
+                "Rejecting {} {} from {:?}, not from this host",
+                method, path, peer
+            );
+            return response(StatusCode::FORBIDDEN);
+        }
+        info!("Rejecting unauthenticated {} {}", method, path);
+        let mut response = response(StatusCode::UNAUTHORIZED);
+        response.headers_mut().insert(
+            "www-authenticate",
+            HeaderValue::from_static("Basic realm=\"dial_server admin\", charset=\"UTF-8\""),
+        );
+        return response;
+    }
+
+    if path == "*" && method == Method::OPTIONS {
+        // RFC 9110 section 9.3.7: OPTIONS * asks about the server as a whole
+        let mut response = response(StatusCode::NO_CONTENT);
+        response.headers_mut().insert(
+            "allow",
+            HeaderValue::from_str(&ROUTED_METHODS.join(", ")).expect("Invalid method"),
+        );
+        response
+    } else if path == "/" && method == Method::GET {
+        let mut response = response_with_body(
+            StatusCode::OK,
+            "text/html; charset=utf-8",
+            "<html>\n<body>TEST</body>\n</html>".to_string(),
+        );
+        response
+            .headers_mut()
+            .insert("access-control-allow-origin", HeaderValue::from_static("*"));
+        response
+    } else if path == DESCRIPTOR_PATH && (method == Method::GET || method == Method::HEAD) {
+        device_descriptor(ctx, request)
+    } else if path == DESCRIPTOR_PATH && method == Method::OPTIONS {
+        descriptor_preflight(ctx, request)
+    } else if path == STATUS_PATH && method == Method::GET {
+        server_status(ctx)
+    } else if path == HEALTH_PATH && method == Method::GET {
+        // liveness: answering at all is all there is to it
+        response_with_body(StatusCode::OK, "text/plain", "ok".to_string())
+    } else if path == READY_PATH && method == Method::GET {
+        readiness(ctx)
+    } else if path == registration::ADMIN_APPS_PATH && method == Method::POST {
+        registration::register(ctx, request).await
+    } else if let (Some(name), &Method::DELETE) = (
+        path.strip_prefix(registration::ADMIN_APPS_PATH)
+            .and_then(|rest| rest.strip_prefix('/')),
+        method,
+    ) {
+        registration::unregister(ctx, name).await
+    } else if path == SSDP_PAUSE_PATH && method == Method::POST {
+        set_ssdp_paused(ctx, true)
+    } else if path == SSDP_RESUME_PATH && method == Method::POST {
+        set_ssdp_paused(ctx, false)
+    } else if path == ANNOUNCE_PATH && method == Method::POST {
+        announce(ctx).await
+    } else if path == SEARCHES_PATH && method == Method::GET {
+        recent_searches(ctx)
+    } else if path == avtransport::CONTROL_PATH && method == Method::POST {
+        avtransport::control(ctx, request).await
+    } else if path == gena::AV_TRANSPORT_EVENT_PATH {
+        gena::handle(ctx, request)
+    } else if path == APPS_LIST_PATH && method == Method::GET {
+        list_apps(ctx).await
+    } else if path == APPS_PATH && method == Method::GET {
+        // the Application-URL itself, probed by some clients to check for DIAL support
+        response(StatusCode::NO_CONTENT)
+    } else if path == APPS_PATH.trim_end_matches('/') && method == Method::GET {
+        let mut response = response(StatusCode::MOVED_PERMANENTLY);
+        response.headers_mut().insert(
+            "location",
+            HeaderValue::from_str(&format!("{}{}", ctx.base_url(), APPS_PATH))
+                .expect("Invalid url"),
+        );
+        response
+    } else if let Some(app_path) = path.strip_prefix(APPS_PATH) {
+        match parse_app_path(app_path) {
+            Some(AppResource::App(name)) if method == Method::GET => {
+                if query_param(request, "wait").is_some() {
+                    wait_for_state_change(ctx, name).await;
+                }
+                app_status(ctx, request, name).await
+            }
+            Some(AppResource::App(name)) if method == Method::POST => {
+                launch_app(ctx, request, name).await
+            }
+            Some(AppResource::Instance(name, run_id)) if method == Method::GET => {
+                app_instance(ctx, name, run_id).await
+            }
+            Some(AppResource::Instance(name, run_id)) if method == Method::DELETE => {
+                stop_app(ctx, name, run_id).await
+            }
+            Some(AppResource::DialData(name)) if method == Method::POST => {
+                store_dial_data(ctx, request, name).await
+            }
+            _ => response(StatusCode::NOT_FOUND),
+        }
+    } else {
+        response(StatusCode::NOT_FOUND)
+    }
+}
+
+/// The device a descriptor describes, what a DescriptorRenderer gets to work with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceConfig {
+    // --device-type, also announced in the NOTIFYs
+    pub device_type: String,
+    // from the profile, None keeps the value of the built-in descriptor
+    pub friendly_name: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model_name: Option<String>,
+    // uuid:<device UUID>, the USNs of all advertisements start with it
+    pub udn: String,
+    // LOCATION and Application-URL start with it, e.g. http://192.168.1.20:8081
+    pub base_url: String,
+}
+
+impl DeviceConfig {
+    pub fn from_config(config: &Config) -> DeviceConfig {
+        let profile = config.profile;
+        DeviceConfig {
+            device_type: config.device_type.clone(),
+            friendly_name: profile.friendly_name.map(str::to_string),
+            manufacturer: profile.manufacturer.map(str::to_string),
+            model_name: profile.model_name.map(str::to_string),
+            udn: format!("uuid:{}", crate::ROOT_DEVICE_UUID),
+            base_url: config.advertised_base_url(),
+        }
+    }
+}
+
+/// Produces the device descriptor XML, the default one fills src/desc.xml with the fields of the
+/// DeviceConfig. Replaced with AppContext::with_descriptor_renderer.
+#[derive(Clone)]
+pub struct DescriptorRenderer(Arc<dyn Fn(&DeviceConfig) -> String + Send + Sync>);
+
+impl DescriptorRenderer {
+    pub fn new(
+        render: impl Fn(&DeviceConfig) -> String + Send + Sync + 'static,
+    ) -> DescriptorRenderer {
+        DescriptorRenderer(Arc::new(render))
+    }
+
+    pub fn render(&self, config: &Config) -> String {
+        (self.0)(&DeviceConfig::from_config(config))
+    }
+}
+
+impl Default for DescriptorRenderer {
+    fn default() -> Self {
+        DescriptorRenderer::new(default_descriptor)
+    }
+}
+
+impl fmt::Debug for DescriptorRenderer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DescriptorRenderer")
+    }
+}
+
+// compiled in, the server doesn't depend on the directory it is started from
+const DEFAULT_DESCRIPTOR: &str = include_str!("desc.xml");
+
+fn default_descriptor(device: &DeviceConfig) -> String {
+    let mut xml = DEFAULT_DESCRIPTOR.to_string();
+    for (tag, value) in [
+        ("friendlyName", &device.friendly_name),
+        ("manufacturer", &device.manufacturer),
+        ("modelName", &device.model_name),
+    ] {
+        if let Some(value) = value {
+            xml = replace_element_text(&xml, tag, &xml_escape(value));
+        }
+    }
+    xml = replace_element_text(&xml, "UDN", &xml_escape(&device.udn));
+    // the NOTIFYs announce the same type
+    replace_element_text(&xml, "deviceType", &xml_escape(&device.device_type))
+}
+
+/// Checks that the descriptor has exactly one root element and that all elements are closed
+/// in order. Not a full XML parser, but enough to catch broken templates before a client does.
+pub fn check_well_formed(xml: &str) -> Result<(), String> {
+    let mut open: Vec<&str> = Vec::new();
+    let mut roots = 0;
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        // comments may contain tags (e.g. commented out elements)
+        if rest[start..].starts_with("<!--") {
+            let end = rest[start..]
+                .find("-->")
+                .ok_or_else(|| "unterminated comment".to_string())?;
+            rest = &rest[start + end + 3..];
+            continue;
+        }
+        let end = rest[start..]
+            .find('>')
+            .ok_or_else(|| "unterminated tag".to_string())?;
+        let tag = &rest[start + 1..start + end];
+        rest = &rest[start + end + 1..];
+
+        // declarations, processing instructions and comments don't nest
+        if tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+        if let Some(name) = tag.strip_prefix('/') {
+            match open.pop() {
+                Some(opened) if opened == name.trim() => {}
+                Some(opened) => return Err(format!("</{}> closes <{}>", name.trim(), opened)),
+                None => return Err(format!("</{}> without opening tag", name.trim())),
+            }
+            continue;
+        }
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        if name.is_empty() {
+            return Err(format!("invalid tag <{}>", tag));
+        }
+        if open.is_empty() {
+            roots += 1;
+        }
+        if !tag.ends_with('/') {
+            open.push(name);
+        }
+    }
+    if let Some(unclosed) = open.pop() {
+        return Err(format!("<{}> is never closed", unclosed));
+    }
+    if roots != 1 {
+        return Err(format!("expected one root element, got {}", roots));
+    }
+    Ok(())
+}
+
+fn device_descriptor(ctx: &AppContext, request: &Request<String>) -> Response<Vec<u8>> {
+    let xml = ctx.descriptor_renderer.render(&ctx.config);
+    let mut response = response_with_body(StatusCode::OK, &ctx.config.descriptor_content_type, xml);
+    // --loopback: a local sender got a LOCATION on 127.0.0.1 and stays there for the apps
+    let peer = request.extensions().get::<SocketAddr>();
+    let base_url = match peer {
+        Some(peer) if ctx.config.loopback && peer.ip().is_loopback() => {
+            format!("http://{}:{}", Ipv4Addr::LOCALHOST, ctx.config.http_port)
+        }
+        _ => ctx.base_url(),
+    };
+    // DIAL 2.2.1 section 5.4: tells the client where the app resources are
+    response.headers_mut().insert(
+        "application-url",
+        HeaderValue::from_str(&format!("{}{}", base_url, APPS_PATH)).expect("Invalid url"),
+    );
+    insert_cors_origin(ctx, &mut response);
+    response
+}
+
+// the CORS preflight of browser senders fetching the descriptor cross-origin, see
+// https://fetch.spec.whatwg.org/#cors-preflight-fetch
+fn descriptor_preflight(ctx: &AppContext, request: &Request<String>) -> Response<Vec<u8>> {
+    let mut response = response(StatusCode::NO_CONTENT);
+    insert_cors_origin(ctx, &mut response);
+    let headers = response.headers_mut();
+    headers.insert("allow", HeaderValue::from_static("GET, HEAD, OPTIONS"));
+    headers.insert(
+        "access-control-allow-methods",
+        HeaderValue::from_static("GET, HEAD"),
+    );
+    // nothing about the descriptor depends on request headers, whatever the page sends is fine
+    if let Some(requested) = request.headers().get("access-control-request-headers") {
+        headers.insert("access-control-allow-headers", requested.clone());
+    }
+    headers.insert("access-control-max-age", HeaderValue::from_static("600"));
+    response
+}
+
+fn insert_cors_origin(ctx: &AppContext, response: &mut Response<Vec<u8>>) {
+    response.headers_mut().insert(
+        "access-control-allow-origin",
+        HeaderValue::from_str(&ctx.config.cors_origin).expect("Invalid origin"),
+    );
+    // a single allowed origin makes the response depend on the Origin of the request
+    if ctx.config.cors_origin != "*" {
+        vary(response, "Origin");
+    }
+}
+
+/// Adds `header` to the Vary header of the response, keeping the ones already listed.
+pub fn vary(response: &mut Response<Vec<u8>>, header: &str) {
+    let headers = response.headers_mut();
+    let mut values: Vec<String> = headers
+        .get_all("vary")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect();
+    if values
+        .iter()
+        .any(|value| value.eq_ignore_ascii_case(header) || value == "*")
+    {
+        return;
+    }
+    values.push(header.to_string());
+    headers.insert(
+        "vary",
+        HeaderValue::from_str(&values.join(", ")).expect("Invalid Vary header"),
+    );
+}
+
+// replaces the text of the first <tag>...</tag> element, returns the xml unchanged if there is none
+fn replace_element_text(xml: &str, tag: &str, text: &str) -> String {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = match xml.find(&open) {
+        Some(start) => start + open.len(),
+        None => return xml.to_string(),
+    };
+    match xml[start..].find(&close) {
+        Some(end) => format!("{}{}{}", &xml[..start], text, &xml[start + end..]),
+        None => xml.to_string(),
+    }
+}
+
+fn wants_json(request: &Request<String>) -> bool {
+    request
+        .headers()
+        .get_all("accept")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("application/json"))
+}
+
+// 404 for an app we don't know, lists the apps we do know to make debugging senders easier
+fn unknown_app(apps: &AppRegistry, request: &Request<String>, name: &str) -> Response<Vec<u8>> {
+    info!("Unknown app requested: {}", name);
+    let mut response = if wants_json(request) {
+        let names: Vec<String> = apps
+            .names()
+            .map(|name| format!("\"{}\"", json::escape(name)))
+            .collect();
+        response_with_body(
+            StatusCode::NOT_FOUND,
+            "application/json",
+            format!(
+                r#"{{"error":"Unknown app: {}","apps":[{}]}}"#,
+                json::escape(name),
+                names.join(",")
+            ),
+        )
+    } else {
+        let names: String = apps
+            .names()
+            .map(|name| format!("\n    <app>{}</app>", xml_escape(name)))
+            .collect();
+        response_with_body(
+            StatusCode::NOT_FOUND,
+            "text/xml; charset=utf-8",
+            format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<error>
+  <message>Unknown app: {}</message>
+  <apps>{}
+  </apps>
+</error>
+"#,
+                xml_escape(name),
+                names
+            ),
+        )
+    };
+    // the format of the body depends on Accept
+    vary(&mut response, "Accept");
+    response
+}
+
+fn query_param<'a>(request: &'a Request<String>, name: &str) -> Option<&'a str> {
+    request.uri().query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if key == name {
+            Some(value)
+        } else {
+            None
+        }
+    })
+}
+
+// application/x-www-form-urlencoded, None if a %-escape is invalid
+fn form_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &tail[2..];
+                continue;
+            }
+            _ => bytes.push(byte),
+        }
+        rest = tail;
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// DIAL 2.1 section 6.5.1: the running app posts the data it wants to hand to senders as form
+/// data (in the body or the query), it replaces what was posted before.
+async fn store_dial_data(
+    ctx: &AppContext,
+    request: &Request<String>,
+    name: &str,
+) -> Response<Vec<u8>> {
+    // only the app itself may do that, i.e. a process on this host
+    let peer = request.extensions().get::<SocketAddr>();
+    if !peer.map(|peer| peer.ip().is_loopback()).unwrap_or(false) {
+        info!("Rejecting dial_data for {} from {:?}", name, peer);
+        return response(StatusCode::FORBIDDEN);
+    }
+
+    let mut dial_data = BTreeMap::new();
+    let pairs = request
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .chain(request.body().trim().split('&'))
+        .filter(|pair| !pair.is_empty());
+    for pair in pairs {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match (form_decode(key), form_decode(value)) {
+            (Some(key), Some(value)) if config::is_xml_name(&key) => {
+                dial_data.insert(key, value);
+            }
+            _ => return response(StatusCode::BAD_REQUEST),
+        }
+    }
+
+    // dial_data ends up in every status response, a misbehaving app shouldn't grow it unbounded
+    let config = &ctx.config;
+    let size: usize = dial_data
+        .iter()
+        .map(|(key, value)| key.len() + value.len())
+        .sum();
+    if size > config.max_dial_data_bytes {
+        info!(
+            "Rejecting dial_data for {}: {} bytes (at most {})",
+            name, size, config.max_dial_data_bytes
+        );
+        return response(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    if dial_data.len() > config.max_dial_data_entries {
+        info!(
+            "Rejecting dial_data for {}: {} entries (at most {})",
+            name,
+            dial_data.len(),
+            config.max_dial_data_entries
+        );
+        return response(StatusCode::INSUFFICIENT_STORAGE);
+    }
+
+    let mut apps = ctx.apps.write().await;
+    match apps.get_mut(name) {
+        Some(app) => {
+            info!("Storing dial_data for {}: {:?}", name, dial_data);
+            // still answered with 200, the app's data is there until the restart
+            if let Err(e) = save_dial_data(ctx, name, &dial_data) {
+                warn!("failed to store the dial_data of {}: {}", name, e);
+            }
+            app.dial_data = dial_data;
+            response(StatusCode::OK)
+        }
+        None => response(StatusCode::NOT_FOUND),
+    }
+}
+
+fn save_dial_data(
+    ctx: &AppContext,
+    name: &str,
+    dial_data: &BTreeMap<String, String>,
+) -> std::io::Result<()> {
+    let entries: Vec<String> = dial_data
+        .iter()
+        .map(|(key, value)| format!(r#""{}":"{}""#, json::escape(key), json::escape(value)))
+        .collect();
+    ctx.store.put(
+        &store::dial_data_key(name),
+        &format!("{{{}}}\n", entries.join(",")),
+    )
+}
+
+/// The dial_data the app `name` posted before the restart (or before it was unregistered),
+/// empty if there is none.
+pub fn load_dial_data(ctx: &AppContext, name: &str) -> BTreeMap<String, String> {
+    let key = store::dial_data_key(name);
+    let text = match ctx.store.get(&key) {
+        Ok(Some(text)) => text,
+        Ok(None) => return BTreeMap::new(),
+        Err(e) => {
+            warn!("failed to read {}: {}", key, e);
+            return BTreeMap::new();
+        }
+    };
+    match json::parse(&text) {
+        Ok(json::Value::Object(members)) => members
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                json::Value::String(value) => Some((key, value)),
+                _ => None,
+            })
+            .collect(),
+        _ => {
+            warn!("ignoring {}, it isn't a JSON object", key);
+            BTreeMap::new()
+        }
+    }
+}
+
+/// Hands the registered apps the dial_data they posted before the restart.
+pub fn restore_dial_data(ctx: &AppContext, apps: &mut AppRegistry) {
+    let keys = match ctx.store.list("dial_data/") {
+        Ok(keys) => keys,
+        Err(e) => {
+            warn!("failed to list the stored dial_data: {}", e);
+            return;
+        }
+    };
+    for key in keys {
+        let Some(name) = store::dial_data_app(&key) else {
+            continue;
+        };
+        let dial_data = load_dial_data(ctx, name);
+        match apps.get_mut(name) {
+            Some(app) => {
+                debug!("Restored {} dial_data entries of {}", dial_data.len(), name);
+                app.dial_data = dial_data;
+            }
+            None => debug!(
+                "Ignoring the stored dial_data of {}, it isn't registered",
+                name
+            ),
+        }
+    }
+}
+
+fn is_dial2_client(request: &Request<String>) -> bool {
+    query_param(request, "clientDialVer")
+        .and_then(|version| version.split('.').next())
+        .and_then(|major| major.parse::<u32>().ok())
+        .map(|major| major >= 2)
+        .unwrap_or(false)
+}
+
+/// Long-poll for senders waiting for a launch or stop: returns once the state of the app differs
+/// from the one it had when called, after STATUS_WAIT_TIMEOUT or when the server shuts down.
+async fn wait_for_state_change(ctx: &AppContext, name: &str) {
+    let (changed, initial_state) = {
+        let apps = ctx.apps.read().await;
+        match apps.get(name) {
+            Some(app) => (app.changed(), app.state()),
+            None => return,
+        }
+    };
+
+    let mut timeout = ctx.clock.sleep(STATUS_WAIT_TIMEOUT);
+    loop {
+        // registered before the state is checked so a change in between isn't missed
+        let notified = changed.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        // the read lock is released before waiting, a launch or stop needs the write lock to
+        // notify us
+        let state = {
+            let apps = ctx.apps.read().await;
+            apps.get(name).map(|app| app.state())
+        };
+        if state.as_ref() != Some(&initial_state) {
+            return;
+        }
+
+        tokio::select! {
+            _ = &mut notified => {}
+            // a launched process exiting on its own is only noticed when its state is checked
+            _ = ctx.clock.sleep(STATUS_WAIT_POLL_INTERVAL) => {}
+            _ = &mut timeout => return,
+            _ = ctx.shutdown.wait() => return,
+        }
+    }
+}
+
+async fn app_status(ctx: &AppContext, request: &Request<String>, name: &str) -> Response<Vec<u8>> {
+    let apps = ctx.apps.read().await;
+    if !apps.contains(name) {
+        return unknown_app(&apps, request, name);
+    }
+    let app = apps.get(name).expect("app is registered");
+
+    let app_state = app.state();
+    // DIAL 2.1 section 6.1.2: additionalData is only understood by 2.x clients, they tell us
+    // their version with ?clientDialVer=
+    let mut data = app.additional_data.clone();
+    data.extend(app.dial_data.clone());
+    if !is_dial2_client(request) {
+        data.clear();
+    }
+    // the XML is what DIAL specifies, JSON is only for senders that ask for it
+    let mut response = if wants_json(request) {
+        let link = match app_state {
+            AppState::Running => r#","link":{"rel":"run","href":"run"}"#,
+            AppState::Stopped | AppState::Installable(_) => "",
+        };
+        let additional_data = if data.is_empty() {
+            String::new()
+        } else {
+            let entries: Vec<String> = data
+                .iter()
+                .map(|(key, value)| format!(r#""{}":"{}""#, json::escape(key), json::escape(value)))
+                .collect();
+            format!(r#","additionalData":{{{}}}"#, entries.join(","))
+        };
+        response_with_body(
+            StatusCode::OK,
+            "application/json",
+            format!(
+                r#"{{"name":"{}","dialVer":"2.2","allowStop":{},"state":"{}"{}{}}}"#,
+                json::escape(&app.name),
+                app.allow_stop,
+                json::escape(&app_state.to_string()),
+                link,
+                additional_data
+            ),
+        )
+    } else {
+        let link = match app_state {
+            AppState::Running => "\n  <link rel=\"run\" href=\"run\"/>",
+            AppState::Stopped | AppState::Installable(_) => "",
+        };
+        let additional_data = if data.is_empty() {
+            String::new()
+        } else {
+            let entries: String = data
+                .iter()
+                .map(|(key, value)| format!("\n    <{}>{}</{}>", key, xml_escape(value), key))
+                .collect();
+            format!("\n  <additionalData>{}\n  </additionalData>", entries)
+        };
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<service xmlns="urn:dial-multiscreen-org:schemas:dial" dialVer="2.2">
+  <name>{}</name>
+  <options allowStop="{}"/>
+  <state>{}</state>{}{}
+</service>
+"#,
+            xml_escape(&app.name),
+            app.allow_stop,
+            xml_escape(&app_state.to_string()),
+            link,
+            additional_data
+        );
+        response_with_body(StatusCode::OK, "text/xml; charset=utf-8", xml)
+    };
+    vary(&mut response, "Accept");
+    response
+}
+
+// stops the instance `run_id` once it ran for `max_runtime`, unless it was stopped (and maybe
+// launched again) in the meantime
+async fn stop_after(ctx: AppContext, name: String, run_id: u64, max_runtime: Duration) {
+    ctx.clock.sleep(max_runtime).await;
+    let mut apps = ctx.apps.write().await;
+    let still_running = apps
+        .get(&name)
+        .and_then(|app| app.instance())
+        .map(|instance| instance.run_id == run_id)
+        .unwrap_or(false);
+    if still_running {
+        info!("{} ran for {:?}, stopping it", name, max_runtime);
+        if let Err(e) = apps.stop(&name) {
+            info!("Failed to stop {}: {:?}", name, e);
+        }
+    }
+}
+
+// the payload ends up in an environment variable (which can't hold a NUL) and in our logs, other
+// control characters than whitespace are dropped so they can't mess with either
+fn launch_payload(body: &str, max_payload: usize) -> Result<String, StatusCode> {
+    if body.len() > max_payload {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    if body.contains('\0') {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(body
+        .chars()
+        .filter(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+        .collect())
+}
+
+async fn launch_app(ctx: &AppContext, request: &Request<String>, name: &str) -> Response<Vec<u8>> {
+    let mut apps = ctx.apps.write().await;
+    // retried launches carry the key of the first attempt
+    let idempotency_key = request
+        .headers()
+        .get("idempotency-key")
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| query_param(request, "idempotencyKey"));
+    let payload = match launch_payload(request.body(), ctx.config.max_payload) {
+        Ok(payload) => payload,
+        Err(status) => {
+            info!("Rejecting launch payload for {}: {}", name, status);
+            return response(status);
+        }
+    };
+    match apps.launch(name, &payload, idempotency_key, ctx.config.launch_policy) {
+        Ok((run_id, action)) => {
+            info!("Launched {} (run id {}, {})", name, run_id, action);
+            events::launched(ctx, name, run_id, action);
+            let created = action.created();
+            if created {
+                ctx.launched.notify_one();
+                let max_runtime = apps.get(name).and_then(|app| app.launch.max_runtime);
+                if let Some(max_runtime) = max_runtime {
+                    tokio::spawn(stop_after(
+                        ctx.clone(),
+                        name.to_string(),
+                        run_id,
+                        max_runtime,
+                    ));
+                }
+            }
+            let mut response = response(if created {
+                StatusCode::CREATED
+            } else {
+                StatusCode::OK
+            });
+            // DIAL 2.2.1 section 6.2.1: LOCATION points to the running instance, with the run id a
+            // sender holding on to it can't stop a later instance by accident
+            response.headers_mut().insert(
+                "location",
+                HeaderValue::from_str(&format!(
+                    "{}{}{}/run/{}",
+                    ctx.base_url(),
+                    APPS_PATH,
+                    name,
+                    run_id
+                ))
+                .expect("Invalid url"),
+            );
+            response
+        }
+        Err(LaunchError::NotFound) => unknown_app(&apps, request, name),
+        // DIAL 2.2.1 section 6.1.2: an app that isn't installed can't be launched
+        Err(LaunchError::NotInstalled) => response(StatusCode::NOT_FOUND),
+        Err(LaunchError::InvalidIdempotencyKey) => {
+            info!(
+                "Rejecting launch of {}: idempotency key longer than {} bytes",
+                name,
+                apps::MAX_IDEMPOTENCY_KEY
+            );
+            response(StatusCode::BAD_REQUEST)
+        }
+        Err(LaunchError::Failed(e)) => {
+            info!("{}", DialError::Launch(name.to_string(), e));
+            let mut response = response(StatusCode::SERVICE_UNAVAILABLE);
+            // RFC 9110 section 10.2.3: tells senders when to try again instead of hammering us
+            response
+                .headers_mut()
+                .insert("retry-after", HeaderValue::from(ctx.config.retry_after));
+            response
+        }
+    }
+}
+
+// DIAL 2.2.1 section 6.4: the instance resource of a running app
+async fn app_instance(ctx: &AppContext, name: &str, run_id: Option<u64>) -> Response<Vec<u8>> {
+    let apps = ctx.apps.read().await;
+    let app = match apps.get(name) {
+        Some(app) => app,
+        None => return response(StatusCode::NOT_FOUND),
+    };
+    let app_state = app.state();
+    let instance = match app.instance() {
+        Some(instance) if run_id.is_none_or(|run_id| run_id == instance.run_id) => instance,
+        _ => return response(StatusCode::NOT_FOUND),
+    };
+
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<instance>
+  <name>{}</name>
+  <runId>{}</runId>
+  <launchedAt>{}</launchedAt>
+  <state>{}</state>
+</instance>
+"#,
+        xml_escape(name),
+        instance.run_id,
+        instance.launched_at,
+        xml_escape(&app_state.to_string())
+    );
+    response_with_body(StatusCode::OK, "text/xml; charset=utf-8", xml)
+}
+
+async fn stop_app(ctx: &AppContext, name: &str, run_id: Option<u64>) -> Response<Vec<u8>> {
+    let mut apps = ctx.apps.write().await;
+    // a stale instance url, the instance it names is gone even if the app runs again
+    if let Some(run_id) = run_id {
+        let running = apps
+            .get(name)
+            .and_then(|app| app.instance())
+            .map(|instance| instance.run_id);
+        if running != Some(run_id) {
+            info!(
+                "Not stopping {}: run id {} isn't the running instance",
+                name, run_id
+            );
+            return response(StatusCode::NOT_FOUND);
+        }
+    }
+    // DIAL 2.2.1 section 6.4: the instance of an app with allowStop="false" can't be deleted
+    if apps.get(name).is_some_and(|app| !app.allow_stop) {
+        return response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    match apps.stop(name) {
+        Ok(()) => response(StatusCode::OK),
+        Err(StopError::NotFound) | Err(StopError::NotRunning) => response(StatusCode::NOT_FOUND),
+    }
+}
+
+// app names and install urls (query strings!) may contain characters that aren't allowed in XML text
+fn xml_escape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+fn server_status(ctx: &AppContext) -> Response<Vec<u8>> {
+    let multicast = &ctx.multicast;
+    response_with_body(
+        StatusCode::OK,
+        "application/json",
+        format!(
+            r#"{{"multicast_group":"{}","multicast_interface":"{}","multicast_joined":{},"ssdp_paused":{}}}"#,
+            multicast.group,
+            multicast.interface,
+            multicast.joined(),
+            ctx.is_ssdp_paused()
+        ),
+    )
+}
+
+pub fn admin_credentials_configured(config: &Config) -> bool {
+    config.admin_user.is_some() && config.admin_pass.is_some()
+}
+
+// RFC 7617: `Authorization: Basic base64(user:pass)`. Without --admin-user only requests from
+// this host are admin, the admin routes register commands that are run on launch.
+fn is_admin(ctx: &AppContext, request: &Request<String>) -> bool {
+    let (user, pass) = match (&ctx.config.admin_user, &ctx.config.admin_pass) {
+        (Some(user), Some(pass)) => (user, pass),
+        _ => {
+            let peer = request.extensions().get::<SocketAddr>();
+            return peer.map(|peer| peer.ip().is_loopback()).unwrap_or(false);
+        }
+    };
+    let expected = base64_encode(format!("{}:{}", user, pass).as_bytes());
+    request
+        .headers()
+        .get_all("authorization")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| value.trim().split_once(' '))
+        .any(|(scheme, credentials)| {
+            scheme.eq_ignore_ascii_case("basic")
+                && constant_time_eq(credentials.trim().as_bytes(), expected.as_bytes())
+        })
+}
+
+// the time to compare doesn't tell how much of the credentials was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let bits = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// the HTTP server is up before the first announcement is sent (and stays up during the shutdown
+// grace period), orchestrators shouldn't send traffic in between
+fn readiness(ctx: &AppContext) -> Response<Vec<u8>> {
+    if ctx.is_ready() {
+        response_with_body(StatusCode::OK, "text/plain", "ready".to_string())
+    } else if ctx.shutdown.requested() {
+        response_with_body(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "text/plain",
+            "shutting down".to_string(),
+        )
+    } else {
+        response_with_body(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "text/plain",
+            "starting".to_string(),
+        )
+    }
+}
+
+// pausing twice doesn't send another byebye, the answer is the same though
+fn set_ssdp_paused(ctx: &AppContext, paused: bool) -> Response<Vec<u8>> {
+    if !ctx.set_ssdp_paused(paused) {
+        debug!("SSDP already {}", if paused { "paused" } else { "running" });
+    }
+    response(StatusCode::NO_CONTENT)
+}
+
+// sends the alive messages now instead of at the next re-announcement, e.g. to get back into
+// the cache of a client that dropped us
+async fn announce(ctx: &AppContext) -> Response<Vec<u8>> {
+    let Some(announce) = &ctx.announce else {
+        return response(StatusCode::SERVICE_UNAVAILABLE);
+    };
+    if ctx.is_ssdp_paused() {
+        debug!("Not announcing, SSDP is paused");
+        return response(StatusCode::CONFLICT);
+    }
+    let (reply, sent) = oneshot::channel();
+    if announce.send(reply).await.is_err() {
+        return response(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    match sent.await {
+        Ok(Ok(sent)) => response_with_body(
+            StatusCode::OK,
+            "application/json",
+            format!(r#"{{"sent":{}}}"#, sent),
+        ),
+        Ok(Err(_)) => response(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(_) => response(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
+// who has been searching for us, oldest first
+fn recent_searches(ctx: &AppContext) -> Response<Vec<u8>> {
+    let entries: Vec<String> = ctx
+        .recent_searches
+        .events()
+        .iter()
+        .map(|search| {
+            let time = search
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let user_agent = match &search.user_agent {
+                Some(user_agent) => format!("\"{}\"", json::escape(user_agent)),
+                None => "null".to_string(),
+            };
+            format!(
+                r#"{{"time":{}.{:03},"source":"{}","st":"{}","user_agent":{}}}"#,
+                time.as_secs(),
+                time.subsec_millis(),
+                search.src_addr,
+                json::escape(&search.search_target),
+                user_agent
+            )
+        })
+        .collect();
+    response_with_body(
+        StatusCode::OK,
+        "application/json",
+        format!("[{}]", entries.join(",")),
+    )
+}
+
+async fn list_apps(ctx: &AppContext) -> Response<Vec<u8>> {
+    let apps = ctx.apps.read().await;
+    let entries: Vec<String> = apps
+        .apps()
+        .map(|app| {
+            let app_state = json::escape(&app.state().to_string());
+            let name = json::escape(&app.name);
+            match app.instance() {
+                Some(instance) => format!(
+                    r#"{{"name":"{}","state":"{}","run_id":{},"launched_at":{}}}"#,
+                    name, app_state, instance.run_id, instance.launched_at
+                ),
+                None => format!(r#"{{"name":"{}","state":"{}"}}"#, name, app_state),
+            }
+        })
+        .collect();
+
+    response_with_body(
+        StatusCode::OK,
+        "application/json",
+        format!("[{}]", entries.join(",")),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    // from this host, tests of remote senders insert their own address
+    fn request(method: &str, path: &str, body: &str) -> Request<String> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(body.to_string())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(SocketAddr::from((Ipv4Addr::LOCALHOST, 50000)));
+        request
+    }
+
+    fn get(path: &str) -> Request<String> {
+        request("GET", path, "")
+    }
+
+    fn context(args: &[&str]) -> AppContext {
+        AppContext::new(Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap())
+    }
+
+    fn body(response: Response<Vec<u8>>) -> String {
+        String::from_utf8(response.into_body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn app_list_reflects_a_launched_app() {
+        let ctx = context(&["--app", "YouTube", "--app", "Netflix"]);
+        let listed = body(route(&ctx, &get(APPS_LIST_PATH)).await);
+        assert!(listed.contains(r#"{"name":"YouTube","state":"stopped"}"#));
+
+        let launched = route(&ctx, &request("POST", "/apps/YouTube", "")).await;
+        assert_eq!(launched.status(), StatusCode::CREATED);
+        let listed = body(route(&ctx, &get(APPS_LIST_PATH)).await);
+        assert!(listed.contains(r#"{"name":"YouTube","state":"running","run_id":"#));
+        assert!(listed.contains(r#""launched_at":"#));
+        assert!(listed.contains(r#"{"name":"Netflix","state":"stopped"}"#));
+        assert!(json::parse(&listed).is_ok());
+    }
+
+    #[tokio::test]
+    async fn options_star_lists_the_routed_methods() {
+        let ctx = AppContext::new(Config::default());
+        let response = route(&ctx, &request("OPTIONS", "*", "")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.headers()["allow"],
+            "GET, HEAD, POST, DELETE, OPTIONS, SUBSCRIBE, UNSUBSCRIBE"
+        );
+    }
+
+    #[tokio::test]
+    async fn every_listed_method_is_routed() {
+        let ctx = AppContext::new(Config::default());
+        let created = route(
+            &ctx,
+            &request("POST", registration::ADMIN_APPS_PATH, r#"{"name": "Foo"}"#),
+        )
+        .await;
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let foo_path = format!("{}/Foo", registration::ADMIN_APPS_PATH);
+        for method in ROUTED_METHODS {
+            let path = match *method {
+                "GET" => "/",
+                "HEAD" | "OPTIONS" => DESCRIPTOR_PATH,
+                "POST" => SSDP_PAUSE_PATH,
+                "DELETE" => &foo_path,
+                _ => gena::AV_TRANSPORT_EVENT_PATH,
+            };
+            let status = route(&ctx, &request(method, path, "")).await.status();
+            assert!(
+                status != StatusCode::NOT_FOUND && status != StatusCode::METHOD_NOT_ALLOWED,
+                "{} {} answered {}",
+                method,
+                path,
+                status
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unlisted_methods_are_not_routed() {
+        let ctx = AppContext::new(Config::default());
+        for method in ["PUT", "PATCH", "TRACE"] {
+            for path in [
+                "/",
+                DESCRIPTOR_PATH,
+                APPS_PATH,
+                gena::AV_TRANSPORT_EVENT_PATH,
+            ] {
+                let status = route(&ctx, &request(method, path, "")).await.status();
+                assert!(
+                    status == StatusCode::NOT_FOUND || status == StatusCode::METHOD_NOT_ALLOWED,
+                    "{} {} answered {}",
+                    method,
+                    path,
+                    status
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_renderer_gets_the_device_config() {
+        let ctx = AppContext::new(Config::default())
+            .with_descriptor_renderer(|device: &DeviceConfig| {
+                format!(
+                    "<root><device><deviceType>{}</deviceType><UDN>{}</UDN>\
+                     <vendor:extra xmlns:vendor=\"urn:example\">1</vendor:extra></device></root>",
+                    device.device_type, device.udn
+                )
+            })
+            .unwrap();
+        let response = route(&ctx, &get(DESCRIPTOR_PATH)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = String::from_utf8(response.into_body()).unwrap();
+        assert!(body.contains(&format!("<UDN>uuid:{}</UDN>", crate::ROOT_DEVICE_UUID)));
+        assert!(body.contains(&format!(
+            "<deviceType>{}</deviceType>",
+            Config::default().device_type
+        )));
+        assert!(body.contains("<vendor:extra"));
+    }
+
+    #[test]
+    fn malformed_descriptors_are_rejected() {
+        let result = AppContext::new(Config::default())
+            .with_descriptor_renderer(|_| "<root><device></root>".to_string());
+        assert!(matches!(result, Err(DialError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn installable_apps_report_their_url_and_cant_be_launched() {
+        let ctx = context(&["--installable", "Netflix=https://store.example/netflix"]);
+        let status = body(route(&ctx, &get("/apps/Netflix")).await);
+        assert!(status.contains("<state>installable=https://store.example/netflix</state>"));
+        let launched = route(&ctx, &request("POST", "/apps/Netflix", "")).await;
+        assert_eq!(launched.status(), StatusCode::NOT_FOUND);
+        let status = body(route(&ctx, &get("/apps/Netflix")).await);
+        assert!(status.contains("<state>installable="));
+    }
+
+    #[test]
+    fn request_lines_without_three_tokens_are_rejected() {
+        for line in ["", "   ", "GET", "GET /"] {
+            let result = parse_request(&format!("{}\r\nHost: x\r\n\r\n", line));
+            assert!(matches!(result, Err(DialError::Parse(_))), "{:?}", line);
+        }
+        assert!(parse_request("GET / HTTP/1.1 extra\r\n\r\n").is_err());
+        assert!(parse_request("GET / HTTP/1.1\r\n\r\n").is_ok());
+    }
+
+    #[test]
+    fn padded_request_lines_are_accepted() {
+        for line in [
+            "GET  /apps/YouTube  HTTP/1.1",
+            "GET\t/apps/YouTube\tHTTP/1.1",
+        ] {
+            let request = parse_request(&format!("{}\r\n\r\n", line)).unwrap();
+            assert_eq!(request.method(), Method::GET);
+            assert_eq!(request.uri().path(), "/apps/YouTube");
+            assert_eq!(request.version(), Version::HTTP_11);
+        }
+        assert!(parse_request("GET / HTTP/2.0\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn special_characters_are_escaped_in_the_status() {
+        // app names can't contain them (they are checked like registered ones), install urls can
+        let ctx = context(&[
+            "--installable",
+            "TomAndJerry=https://store.example/install?app=tom&lang=<de>",
+        ]);
+        let response = route(&ctx, &get("/apps/TomAndJerry")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let xml = body(response);
+        check_well_formed(&xml).unwrap();
+        // check_well_formed only looks at the tags
+        for (i, _) in xml.match_indices('&') {
+            assert!(
+                ["&amp;", "&lt;", "&gt;", "&quot;", "&apos;"]
+                    .iter()
+                    .any(|entity| xml[i..].starts_with(entity)),
+                "{}",
+                xml
+            );
+        }
+        assert!(xml.contains("app=tom&amp;lang=&lt;de&gt;</state>"));
+    }
+
+    #[test]
+    fn responses_are_serialized_into_one_buffer() {
+        let hello = response_with_body(StatusCode::OK, "text/plain", "hello".to_string());
+        let bytes = response_to_bytes(&hello);
+        let text = std::str::from_utf8(&bytes).unwrap();
+        assert!(text.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(text.contains("content-length: 5\r\n"));
+        assert!(text.ends_with("\r\n\r\nhello"));
+
+        let bytes = response_to_bytes(&response(StatusCode::NO_CONTENT));
+        let text = std::str::from_utf8(&bytes).unwrap();
+        assert!(!text.contains("content-length"));
+        assert!(text.ends_with("\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn unknown_apps_list_the_supported_ones() {
+        let ctx = context(&["--app", "YouTube", "--app", "Netflix"]);
+        let response = route(&ctx, &request("POST", "/apps/Foo", "")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["vary"], "Accept");
+        let xml = body(response);
+        check_well_formed(&xml).unwrap();
+        assert!(xml.contains("<message>Unknown app: Foo</message>"));
+        assert!(xml.contains("<app>YouTube</app>") && xml.contains("<app>Netflix</app>"));
+
+        let mut json_request = get("/apps/Foo");
+        json_request
+            .headers_mut()
+            .insert("accept", HeaderValue::from_static("application/json"));
+        let response = route(&ctx, &json_request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let json = body(response);
+        assert!(json::parse(&json).is_ok());
+        assert!(json.contains(r#""apps":["#) && json.contains(r#""YouTube""#));
+    }
+
+    #[tokio::test]
+    async fn instance_resource_of_a_running_and_a_stopped_app() {
+        let ctx = context(&["--app", "YouTube"]);
+        let stopped = route(&ctx, &get("/apps/YouTube/run")).await;
+        assert_eq!(stopped.status(), StatusCode::NOT_FOUND);
+
+        let launched = route(&ctx, &request("POST", "/apps/YouTube", "")).await;
+        let location = launched.headers()["location"].to_str().unwrap();
+        let run_id = location.rsplit('/').next().unwrap().to_string();
+        for path in [
+            "/apps/YouTube/run".to_string(),
+            format!("/apps/YouTube/run/{}", run_id),
+        ] {
+            let running = route(&ctx, &get(&path)).await;
+            assert_eq!(running.status(), StatusCode::OK);
+            let xml = body(running);
+            check_well_formed(&xml).unwrap();
+            assert!(xml.contains(&format!("<runId>{}</runId>", run_id)));
+            assert!(xml.contains("<launchedAt>"));
+            assert!(xml.contains("<state>running</state>"));
+        }
+
+        let stopped = route(&ctx, &request("DELETE", "/apps/YouTube/run", "")).await;
+        assert_eq!(stopped.status(), StatusCode::OK);
+        let gone = route(&ctx, &get("/apps/YouTube/run")).await;
+        assert_eq!(gone.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn additional_data_is_only_sent_to_dial2_clients() {
+        let ctx = context(&["--app", "YouTube", "--app-data", "YouTube", "screenId=abc"]);
+        for (query, expected) in [
+            ("", false),
+            ("?clientDialVer=1.7", false),
+            ("?clientDialVer=garbage", false),
+            ("?clientDialVer=2.1", true),
+            ("?clientDialVer=2", true),
+        ] {
+            let status = body(route(&ctx, &get(&format!("/apps/YouTube{}", query))).await);
+            assert_eq!(
+                status.contains("<additionalData>\n    <screenId>abc</screenId>"),
+                expected,
+                "{}: {}",
+                query,
+                status
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_launch_wakes_a_waiting_status_request() {
+        let ctx = context(&["--app", "YouTube"]);
+        let waiting = {
+            let ctx = ctx.clone();
+            tokio::spawn(async move { body(route(&ctx, &get("/apps/YouTube?wait")).await) })
+        };
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!waiting.is_finished());
+
+        route(&ctx, &request("POST", "/apps/YouTube", "")).await;
+        // well below STATUS_WAIT_POLL_INTERVAL, the launch has to notify the waiting request
+        let status = tokio::time::timeout(Duration::from_millis(500), waiting).await;
+        let status = status.expect("still waiting").unwrap();
+        assert!(status.contains("<state>running</state>"), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn the_descriptor_content_type_has_a_charset() {
+        let ctx = context(&[]);
+        let response = route(&ctx, &get(DESCRIPTOR_PATH)).await;
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/xml; charset=utf-8"
+        );
+
+        let ctx = context(&["--descriptor-content-type", "text/xml"]);
+        let response = route(&ctx, &get(DESCRIPTOR_PATH)).await;
+        assert_eq!(response.headers()["content-type"], "text/xml");
+    }
+
+    #[test]
+    fn app_paths_are_split_into_resources() {
+        let cases = [
+            ("YouTube", Some(AppResource::App("YouTube"))),
+            ("YouTube/run", Some(AppResource::Instance("YouTube", None))),
+            (
+                "YouTube/run/7",
+                Some(AppResource::Instance("YouTube", Some(7))),
+            ),
+            ("YouTube/dial_data", Some(AppResource::DialData("YouTube"))),
+            ("YouTube/bogus", None),
+            ("YouTube/run/abc", None),
+            ("YouTube/dial_data/x", None),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(parse_app_path(path), expected, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn app_subresources_are_routed() {
+        let ctx = context(&["--app", "YouTube"]);
+        let status = route(&ctx, &get("/apps/YouTube")).await;
+        assert_eq!(status.status(), StatusCode::OK);
+        let launched = route(&ctx, &request("POST", "/apps/YouTube", "")).await;
+        assert_eq!(launched.status(), StatusCode::CREATED);
+        let instance = route(&ctx, &get("/apps/YouTube/run")).await;
+        assert_eq!(instance.status(), StatusCode::OK);
+        // only the app itself, on this host, may store dial_data
+        let mut dial_data = request("POST", "/apps/YouTube/dial_data", "a=1");
+        dial_data
+            .extensions_mut()
+            .insert(SocketAddr::from(([127, 0, 0, 1], 40000)));
+        let dial_data = route(&ctx, &dial_data).await;
+        assert_eq!(dial_data.status(), StatusCode::OK);
+        let bogus = route(&ctx, &get("/apps/YouTube/bogus")).await;
+        assert_eq!(bogus.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn apps_are_stopped_after_their_max_runtime() {
+        let clock = Arc::new(MockClock::new());
+        let mut ctx = context(&["--app", "YouTube", "--app-max-runtime", "YouTube", "1"]);
+        ctx.clock = clock.clone();
+        route(&ctx, &request("POST", "/apps/YouTube", "")).await;
+        // lets the spawned stop_after start waiting
+        tokio::task::yield_now().await;
+
+        clock.advance(Duration::from_millis(999));
+        tokio::task::yield_now().await;
+        let status = body(route(&ctx, &get("/apps/YouTube")).await);
+        assert!(status.contains("<state>running</state>"), "{}", status);
+
+        clock.advance(Duration::from_millis(1));
+        tokio::task::yield_now().await;
+        let status = body(route(&ctx, &get("/apps/YouTube")).await);
+        assert!(status.contains("<state>stopped</state>"), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn the_max_runtime_of_an_old_instance_leaves_a_relaunch_alone() {
+        let ctx = context(&["--app", "YouTube"]);
+        let run_id = |response: Response<Vec<u8>>| -> u64 {
+            let location = response.headers()["location"].to_str().unwrap();
+            location.rsplit('/').next().unwrap().parse().unwrap()
+        };
+        let first = run_id(route(&ctx, &request("POST", "/apps/YouTube", "")).await);
+        route(&ctx, &request("DELETE", "/apps/YouTube/run", "")).await;
+        let second = run_id(route(&ctx, &request("POST", "/apps/YouTube", "")).await);
+        assert_ne!(first, second);
+
+        stop_after(ctx.clone(), "YouTube".to_string(), first, Duration::ZERO).await;
+        let apps = ctx.apps.read().await;
+        let instance = apps.get("YouTube").unwrap().instance();
+        assert_eq!(instance.map(|instance| instance.run_id), Some(second));
+    }
+
+    #[tokio::test]
+    async fn launches_are_deduplicated_by_header_or_query_key() {
+        let mut with_header = request("POST", "/apps/YouTube", "");
+        with_header
+            .headers_mut()
+            .insert("idempotency-key", HeaderValue::from_static("abc"));
+        let with_query = request("POST", "/apps/YouTube?idempotencyKey=def", "");
+        for launch in [with_header, with_query] {
+            let ctx = context(&["--app", "YouTube"]);
+            let first = route(&ctx, &launch).await;
+            route(&ctx, &request("DELETE", "/apps/YouTube/run", "")).await;
+            // a retry of a launch that already happened, it must not start the app again
+            let retried = route(&ctx, &launch).await;
+            assert_eq!(retried.headers()["location"], first.headers()["location"]);
+            let status = body(route(&ctx, &get("/apps/YouTube")).await);
+            assert!(status.contains("<state>stopped</state>"), "{}", status);
+        }
+
+        let ctx = context(&["--app", "YouTube"]);
+        let path = format!(
+            "/apps/YouTube?idempotencyKey={}",
+            "k".repeat(apps::MAX_IDEMPOTENCY_KEY + 1)
+        );
+        let rejected = route(&ctx, &request("POST", &path, "")).await;
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+        let status = body(route(&ctx, &get("/apps/YouTube")).await);
+        assert!(status.contains("<state>stopped</state>"), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn dial_data_above_the_caps_is_rejected() {
+        let ctx = context(&[
+            "--app",
+            "YouTube",
+            "--max-dial-data-entries",
+            "2",
+            "--max-dial-data-bytes",
+            "16",
+        ]);
+        let store = |body: &str| {
+            let mut request = request("POST", "/apps/YouTube/dial_data", body);
+            request
+                .extensions_mut()
+                .insert(SocketAddr::from(([127, 0, 0, 1], 40000)));
+            let ctx = ctx.clone();
+            async move { route(&ctx, &request).await.status() }
+        };
+
+        assert_eq!(store("a=1&b=2").await, StatusCode::OK);
+        assert_eq!(store("a=1&b=2&c=3").await, StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(
+            store(&format!("a={}", "x".repeat(16))).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        // a rejected update leaves the stored data alone
+        let status = body(route(&ctx, &get("/apps/YouTube?clientDialVer=2.1")).await);
+        assert!(
+            status.contains("<a>1</a>") && status.contains("<b>2</b>"),
+            "{}",
+            status
+        );
+    }
+
+    #[tokio::test]
+    async fn the_application_url_is_distinct_from_named_apps() {
+        let ctx = context(&["--app", "YouTube"]);
+        let base = route(&ctx, &get("/apps/")).await;
+        assert_eq!(base.status(), StatusCode::NO_CONTENT);
+        assert!(base.body().is_empty());
+
+        let without_slash = route(&ctx, &get("/apps")).await;
+        assert_eq!(without_slash.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            without_slash.headers()["location"],
+            format!("{}/apps/", ctx.base_url())
+        );
+
+        assert_eq!(
+            route(&ctx, &get("/apps/YouTube")).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            route(&ctx, &get("/apps/Netflix")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            route(&ctx, &get("/appsYouTube")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn launch_payloads_are_limited_and_sanitized() {
+        assert_eq!(launch_payload("v=abc", 5), Ok("v=abc".to_string()));
+        assert_eq!(
+            launch_payload("v=abcd", 5),
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        assert_eq!(launch_payload("v=a\0b", 64), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(
+            launch_payload("v=a\x1b[31mb\x07\tc\r\n", 64),
+            Ok("v=a[31mb\tc\r\n".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_payloads_dont_launch() {
+        let ctx = context(&["--app", "YouTube", "--max-payload", "8"]);
+        let oversized = route(&ctx, &request("POST", "/apps/YouTube", "v=123456789")).await;
+        assert_eq!(oversized.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let nul = route(&ctx, &request("POST", "/apps/YouTube", "v=\0")).await;
+        assert_eq!(nul.status(), StatusCode::BAD_REQUEST);
+        let status = body(route(&ctx, &get("/apps/YouTube")).await);
+        assert!(status.contains("<state>stopped</state>"), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn failed_launches_tell_when_to_retry() {
+        // the command can't be spawned without its working directory
+        let ctx = context(&[
+            "--app",
+            "Busy=true",
+            "--app-cwd",
+            "Busy",
+            "/nonexistent/dial_server",
+            "--retry-after",
+            "12",
+        ]);
+        let launched = route(&ctx, &request("POST", "/apps/Busy", "")).await;
+        assert_eq!(launched.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(launched.headers()["retry-after"], "12");
+    }
+
+    #[tokio::test]
+    async fn status_reads_run_alongside_each_other() {
+        let ctx = context(&["--app", "YouTube"]);
+        // a status request in progress elsewhere
+        let reading = ctx.apps.read().await;
+        let status_request = get("/apps/YouTube");
+        let status = tokio::time::timeout(Duration::from_secs(1), route(&ctx, &status_request));
+        let status = status.await.expect("blocked by a reader");
+        assert_eq!(status.status(), StatusCode::OK);
+
+        // a launch has to wait for the readers
+        let launch = {
+            let ctx = ctx.clone();
+            tokio::spawn(async move { route(&ctx, &request("POST", "/apps/YouTube", "")).await })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!launch.is_finished());
+        drop(reading);
+        let launched = tokio::time::timeout(Duration::from_secs(1), launch).await;
+        assert_eq!(launched.unwrap().unwrap().status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn the_descriptor_answers_cors_preflights() {
+        let ctx = context(&[]);
+        let mut preflight = request("OPTIONS", DESCRIPTOR_PATH, "");
+        let headers = preflight.headers_mut();
+        headers.insert("origin", HeaderValue::from_static("https://sender.example"));
+        headers.insert(
+            "access-control-request-method",
+            HeaderValue::from_static("GET"),
+        );
+        headers.insert(
+            "access-control-request-headers",
+            HeaderValue::from_static("x-custom"),
+        );
+        let response = route(&ctx, &preflight).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(headers["access-control-allow-origin"], "*");
+        assert_eq!(headers["access-control-allow-methods"], "GET, HEAD");
+        assert_eq!(headers["access-control-allow-headers"], "x-custom");
+        assert!(!headers.contains_key("vary"));
+
+        // the methods it allows are the ones the descriptor supports
+        let get = handle_request(&ctx, &get(DESCRIPTOR_PATH)).await;
+        let head = handle_request(&ctx, &request("HEAD", DESCRIPTOR_PATH, "")).await;
+        assert_eq!(get.status(), StatusCode::OK);
+        assert_eq!(head.status(), StatusCode::OK);
+        assert!(head.body().is_empty());
+        assert_eq!(
+            head.headers()["content-length"],
+            get.body().len().to_string().as_str()
+        );
+
+        let ctx = context(&["--cors-origin", "https://sender.example"]);
+        let response = route(&ctx, &preflight).await;
+        assert_eq!(
+            response.headers()["access-control-allow-origin"],
+            "https://sender.example"
+        );
+        assert_eq!(response.headers()["vary"], "Origin");
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_the_credentials() {
+        let ctx = context(&["--admin-user", "admin", "--admin-pass", "secret"]);
+        let pause = |authorization: Option<String>| {
+            let mut request = request("POST", SSDP_PAUSE_PATH, "");
+            if let Some(authorization) = authorization {
+                request.headers_mut().insert(
+                    "authorization",
+                    HeaderValue::from_str(&authorization).unwrap(),
+                );
+            }
+            let ctx = ctx.clone();
+            async move { route(&ctx, &request).await }
+        };
+        let basic =
+            |credentials: &str| Some(format!("Basic {}", base64_encode(credentials.as_bytes())));
+
+        for authorization in [
+            None,
+            basic("admin:wrong"),
+            basic("other:secret"),
+            Some("Bearer secret".to_string()),
+        ] {
+            let response = pause(authorization.clone()).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::UNAUTHORIZED,
+                "{:?}",
+                authorization
+            );
+            assert!(response.headers()["www-authenticate"]
+                .to_str()
+                .unwrap()
+                .starts_with("Basic realm="));
+            assert!(!ctx.is_ssdp_paused());
+        }
+        let response = pause(basic("admin:secret")).await;
+        assert!(response.status().is_success(), "{}", response.status());
+        assert!(ctx.is_ssdp_paused());
+
+        // the DIAL and descriptor routes stay open
+        assert_eq!(
+            route(&ctx, &get(DESCRIPTOR_PATH)).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn base64_pads_every_length() {
+        for (data, encoded) in [
+            ("", ""),
+            ("a", "YQ=="),
+            ("ad", "YWQ="),
+            ("adm", "YWRt"),
+            ("admin:secret", "YWRtaW46c2VjcmV0"),
+        ] {
+            assert_eq!(base64_encode(data.as_bytes()), encoded);
+        }
+    }
+
+    #[tokio::test]
+    async fn negotiated_responses_vary_on_what_they_depend_on() {
+        use std::io::Read;
+
+        let ctx = context(&["--app", "YouTube"]);
+        let with_encoding = |path: &str, encoding: &str| {
+            let mut request = get(path);
+            request
+                .headers_mut()
+                .insert("accept-encoding", HeaderValue::from_str(encoding).unwrap());
+            request
+        };
+        let plain = handle_request(&ctx, &get(DESCRIPTOR_PATH)).await;
+        assert!(plain.body().len() >= MIN_GZIP_SIZE);
+        assert_eq!(plain.headers()["vary"], "Accept-Encoding");
+        assert!(!plain.headers().contains_key("content-encoding"));
+
+        let gzipped = handle_request(&ctx, &with_encoding(DESCRIPTOR_PATH, "br, gzip")).await;
+        assert_eq!(gzipped.headers()["content-encoding"], "gzip");
+        assert_eq!(gzipped.headers()["vary"], "Accept-Encoding");
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&gzipped.body()[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(&decoded, plain.body());
+
+        let refused = handle_request(&ctx, &with_encoding(DESCRIPTOR_PATH, "gzip;q=0")).await;
+        assert!(!refused.headers().contains_key("content-encoding"));
+
+        // the app status depends on Accept, it's too small to be compressed
+        let status = handle_request(&ctx, &with_encoding("/apps/YouTube", "gzip")).await;
+        assert!(status.body().len() < MIN_GZIP_SIZE);
+        assert_eq!(status.headers()["vary"], "Accept");
+    }
+
+    #[tokio::test]
+    async fn the_app_status_follows_accept() {
+        let ctx = context(&["--app", "YouTube"]);
+        let status = |accept: Option<&'static str>| {
+            let mut request = get("/apps/YouTube");
+            if let Some(accept) = accept {
+                request
+                    .headers_mut()
+                    .insert("accept", HeaderValue::from_static(accept));
+            }
+            let ctx = ctx.clone();
+            async move { route(&ctx, &request).await }
+        };
+
+        for accept in [None, Some("text/xml"), Some("*/*")] {
+            let response = status(accept).await;
+            assert_eq!(
+                response.headers()["content-type"],
+                "text/xml; charset=utf-8"
+            );
+            let xml = body(response);
+            assert!(
+                xml.contains("<name>YouTube</name>"),
+                "{:?}: {}",
+                accept,
+                xml
+            );
+        }
+
+        let response = status(Some("application/json")).await;
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let json::Value::Object(fields) = json::parse(&body(response)).unwrap() else {
+            panic!("not an object");
+        };
+        assert_eq!(fields["name"], json::Value::String("YouTube".to_string()));
+        assert_eq!(fields["state"], json::Value::String("stopped".to_string()));
+        assert_eq!(fields["allowStop"], json::Value::Bool(true));
+    }
+
+    #[tokio::test]
+    async fn installable_apps_are_told_apart_from_unknown_ones() {
+        let ctx = context(&[
+            "--install-url",
+            "https://store.example/install/{name}",
+            "--installable",
+            "Netflix",
+            "--installable",
+            "Hulu=https://hulu.example/get",
+        ]);
+        let netflix = route(&ctx, &get("/apps/Netflix")).await;
+        assert_eq!(netflix.status(), StatusCode::OK);
+        let status = body(netflix);
+        assert!(
+            status.contains("<state>installable=https://store.example/install/Netflix</state>"),
+            "{}",
+            status
+        );
+        let hulu = body(route(&ctx, &get("/apps/Hulu")).await);
+        assert!(hulu.contains("<state>installable=https://hulu.example/get</state>"));
+
+        let unknown = route(&ctx, &get("/apps/Disney")).await;
+        assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+        assert!(!body(unknown).contains("installable"));
+
+        // the default url is needed for names without one
+        let e = Config::from_args(["--installable", "Netflix"].map(String::from).into_iter());
+        assert!(e.unwrap_err().contains("--install-url"));
+    }
+
+    #[tokio::test]
+    async fn dial_data_is_kept_in_the_state_store() {
+        use store::StateStore;
+
+        let mut ctx = context(&["--app", "YouTube"]);
+        let store = Arc::new(store::MemoryStore::default());
+        ctx.store = store.clone();
+        let mut dial_data = request("POST", "/apps/YouTube/dial_data", "screen=abc");
+        dial_data
+            .extensions_mut()
+            .insert(SocketAddr::from(([127, 0, 0, 1], 40000)));
+        assert_eq!(route(&ctx, &dial_data).await.status(), StatusCode::OK);
+
+        assert!(store
+            .get(&store::dial_data_key("YouTube"))
+            .unwrap()
+            .is_some_and(|text| text.contains("screen")));
+        // what a restart with the same store loads
+        let loaded = load_dial_data(&ctx, "YouTube");
+        assert_eq!(loaded["screen"], "abc");
+    }
+
+    #[test]
+    fn chunked_bodies_are_framed_and_decoded() {
+        let request = "POST /apps/YouTube HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       4;ext=1\r\nv=ab\r\n6\r\nc&t=10\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let pipelined = format!("{}GET / HTTP/1.1\r\n\r\n", request);
+        assert_eq!(
+            request_length(pipelined.as_bytes(), 1024, 64),
+            Ok(Some(request.len()))
+        );
+        // every prefix is incomplete, not an error
+        for end in 0..request.len() {
+            assert_eq!(
+                request_length(&request.as_bytes()[..end], 1024, 64),
+                Ok(None),
+                "{}",
+                end
+            );
+        }
+        assert_eq!(parse_request(request).unwrap().body(), "v=abc&t=10");
+
+        assert_eq!(
+            request_length(request.as_bytes(), 1024, 9),
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        let malformed = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nxyz\r\n";
+        assert_eq!(
+            request_length(malformed.as_bytes(), 1024, 64),
+            Err(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[test]
+    fn content_length_bodies_wait_for_the_whole_body() {
+        let request = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(
+            request_length(&request.as_bytes()[..request.len() - 1], 1024, 64),
+            Ok(None)
+        );
+        assert_eq!(
+            request_length(request.as_bytes(), 1024, 64),
+            Ok(Some(request.len()))
+        );
+        assert_eq!(
+            request_length(request.as_bytes(), 1024, 4),
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        assert!(expects_continue(
+            b"POST / HTTP/1.1\r\nExpect: 100-continue\r\n\r\n"
+        ));
+        assert!(!expects_continue(
+            b"POST / HTTP/1.1\r\nExpect: 100-continue\r\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn extra_headers_are_on_the_descriptor_response() {
+        let ctx = context(&[
+            "--extra-header",
+            "X-Vendor: Acme TV",
+            "--extra-header",
+            "X-Model:42",
+        ]);
+        let response = handle_request(&ctx, &get(DESCRIPTOR_PATH)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-vendor"], "Acme TV");
+        assert_eq!(response.headers()["x-model"], "42");
+        // and on every other response
+        let missing = handle_request(&ctx, &get("/nothing-here")).await;
+        assert_eq!(missing.headers()["x-vendor"], "Acme TV");
+    }
+
+    #[tokio::test]
+    async fn readiness_follows_startup_and_shutdown() {
+        let ctx = context(&[]);
+        let starting = route(&ctx, &get(READY_PATH)).await;
+        assert_eq!(starting.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body(starting), "starting");
+        // alive the whole time
+        assert_eq!(
+            route(&ctx, &get(HEALTH_PATH)).await.status(),
+            StatusCode::OK
+        );
+
+        ctx.set_started();
+        let ready = route(&ctx, &get(READY_PATH)).await;
+        assert_eq!(ready.status(), StatusCode::OK);
+        assert_eq!(body(ready), "ready");
+
+        ctx.shutdown.trigger(0);
+        let stopping = route(&ctx, &get(READY_PATH)).await;
+        assert_eq!(stopping.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body(stopping), "shutting down");
+        assert_eq!(
+            route(&ctx, &get(HEALTH_PATH)).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn stale_instance_urls_dont_stop_a_later_instance() {
+        let ctx = context(&["--app", "YouTube"]);
+        let launch = |ctx: &AppContext| {
+            let ctx = ctx.clone();
+            async move {
+                let launched = route(&ctx, &request("POST", "/apps/YouTube", "")).await;
+                assert_eq!(launched.status(), StatusCode::CREATED);
+                let location = launched.headers()["location"].to_str().unwrap().to_string();
+                let path = location.strip_prefix(&ctx.base_url()).unwrap().to_string();
+                assert!(path.starts_with("/apps/YouTube/run/"), "{}", path);
+                path
+            }
+        };
+        let is_running = |ctx: &AppContext| {
+            let ctx = ctx.clone();
+            async move {
+                body(route(&ctx, &get("/apps/YouTube")).await).contains("<state>running</state>")
+            }
+        };
+
+        let first = launch(&ctx).await;
+        let stopped = route(&ctx, &request("DELETE", &first, "")).await;
+        assert_eq!(stopped.status(), StatusCode::OK);
+        let second = launch(&ctx).await;
+        assert_ne!(first, second);
+
+        let stale = route(&ctx, &request("DELETE", &first, "")).await;
+        assert_eq!(stale.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            route(&ctx, &get(&first)).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let unknown = route(&ctx, &request("DELETE", "/apps/YouTube/run/99", "")).await;
+        assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+        let not_a_number = route(&ctx, &request("DELETE", "/apps/YouTube/run/x", "")).await;
+        assert_eq!(not_a_number.status(), StatusCode::NOT_FOUND);
+        assert!(is_running(&ctx).await);
+        assert_eq!(route(&ctx, &get(&second)).await.status(), StatusCode::OK);
+
+        // the run link of the app status names whichever instance is running
+        let stopped = route(&ctx, &request("DELETE", "/apps/YouTube/run", "")).await;
+        assert_eq!(stopped.status(), StatusCode::OK);
+        assert!(!is_running(&ctx).await);
+    }
+
+    #[tokio::test]
+    async fn a_double_launch_under_relaunch_emits_a_relaunch_event() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let webhook = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/events", webhook.local_addr().unwrap());
+        let ctx = context(&[
+            "--app",
+            "YouTube",
+            "--launch-policy",
+            "relaunch",
+            "--event-webhook",
+            &url,
+        ]);
+        // the body of the next event, the request is read until the connection is half closed or
+        // the body is complete
+        let next_event = || async {
+            let (mut stream, _) = tokio::time::timeout(Duration::from_secs(1), webhook.accept())
+                .await
+                .expect("no event")
+                .unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            loop {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    assert!(head.starts_with("POST /events HTTP/1.1"), "{}", head);
+                    let length: usize = crate::ssdp::header(head, "Content-Length")
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    if body.len() >= length {
+                        stream
+                            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                            .await
+                            .unwrap();
+                        let Ok(json::Value::Object(event)) = json::parse(body) else {
+                            panic!("not a JSON object: {}", body);
+                        };
+                        return event;
+                    }
+                }
+                assert!(read > 0, "incomplete event");
+            }
+        };
+        let action = |event: &BTreeMap<String, json::Value>| event["action"].clone();
+
+        let first = route(&ctx, &request("POST", "/apps/YouTube", "")).await;
+        assert_eq!(first.status(), StatusCode::CREATED);
+        let event = next_event().await;
+        assert_eq!(action(&event), json::Value::String("launch".to_string()));
+        assert_eq!(event["app"], json::Value::String("YouTube".to_string()));
+
+        let second = route(&ctx, &request("POST", "/apps/YouTube", "")).await;
+        assert_eq!(second.status(), StatusCode::CREATED);
+        assert_ne!(first.headers()["location"], second.headers()["location"]);
+        let event = next_event().await;
+        assert_eq!(action(&event), json::Value::String("relaunch".to_string()));
+
+        // the default policy hands out the running instance
+        let ctx = context(&["--app", "YouTube", "--event-webhook", &url]);
+        route(&ctx, &request("POST", "/apps/YouTube", "")).await;
+        next_event().await;
+        let again = route(&ctx, &request("POST", "/apps/YouTube", "")).await;
+        assert_eq!(again.status(), StatusCode::OK);
+        assert_eq!(
+            action(&next_event().await),
+            json::Value::String("reuse".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_routes_without_credentials_are_only_open_to_this_host() {
+        let ctx = context(&["--app", "YouTube"]);
+        let from = |peer: Option<[u8; 4]>| {
+            let mut request = request("POST", "/admin/ssdp/pause", "");
+            request.extensions_mut().remove::<SocketAddr>();
+            if let Some(peer) = peer {
+                request
+                    .extensions_mut()
+                    .insert(SocketAddr::from((peer, 50000)));
+            }
+            request
+        };
+        for peer in [Some([192, 0, 2, 20]), None] {
+            let rejected = route(&ctx, &from(peer)).await;
+            assert_eq!(rejected.status(), StatusCode::FORBIDDEN, "{:?}", peer);
+            assert!(!rejected.headers().contains_key("www-authenticate"));
+        }
+        assert!(!ctx.is_ssdp_paused());
+        let accepted = route(&ctx, &from(Some([127, 0, 0, 1]))).await;
+        assert!(accepted.status().is_success(), "{}", accepted.status());
+        assert!(ctx.is_ssdp_paused());
+
+        // with credentials the address doesn't matter, only the credentials do
+        let ctx = context(&["--admin-user", "admin", "--admin-pass", "secret"]);
+        let local = route(&ctx, &from(Some([127, 0, 0, 1]))).await;
+        assert_eq!(local.status(), StatusCode::UNAUTHORIZED);
+        let mut remote = from(Some([192, 0, 2, 20]));
+        remote.headers_mut().insert(
+            "authorization",
+            HeaderValue::from_static("Basic YWRtaW46c2VjcmV0"),
+        );
+        assert!(route(&ctx, &remote).await.status().is_success());
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+use std::io;
+
+/// Errors that end up in front of the user, either on the terminal or as the reason for an
+/// exit code.
+#[derive(Debug)]
+pub enum DialError {
+    // invalid command line, the message includes the usage where it helps
+    Config(String),
+    // what we were doing with the socket and why it failed
+    Socket(String, io::Error),
+    // a HTTP request we can't handle
+    Parse(String),
+    // the command of an app couldn't be started
+    Launch(String, io::Error),
+    // the HTTP port and the number of ports tried from there were all taken
+    PortInUse(u16, u16),
+}
+
+impl DialError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // like most command line tools, 2 for usage errors
+            DialError::Config(_) => 2,
+            DialError::Socket(..)
+            | DialError::Parse(_)
+            | DialError::Launch(..)
+            | DialError::PortInUse(..) => 1,
+        }
+    }
+}
+
+impl fmt::Display for DialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DialError::Config(message) => write!(f, "{}", message),
+            DialError::Socket(action, e) => write!(f, "{} failed: {}", action, e),
+            DialError::Parse(message) => write!(f, "Invalid request: {}", message),
+            DialError::Launch(app, e) => write!(f, "Failed to launch {}: {}", app, e),
+            DialError::PortInUse(port, 1) => write!(
+                f,
+                "Port {} is already in use (is another dial_server running?), \
+                 choose another one with --http-port or pass --port-fallback",
+                port
+            ),
+            DialError::PortInUse(port, attempts) => write!(
+                f,
+                "Ports {} to {} are all in use, choose another one with --http-port",
+                port,
+                port.saturating_add(attempts - 1)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DialError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DialError::Socket(_, e) | DialError::Launch(_, e) => Some(e),
+            DialError::Config(_) | DialError::Parse(_) | DialError::PortInUse(..) => None,
+        }
+    }
+}
+
+/// Adds the description of what was done with a socket to an io::Error.
+pub trait SocketContext<T> {
+    fn socket_context(self, action: impl FnOnce() -> String) -> Result<T, DialError>;
+}
+
+impl<T> SocketContext<T> for io::Result<T> {
+    fn socket_context(self, action: impl FnOnce() -> String) -> Result<T, DialError> {
+        self.map_err(|e| DialError::Socket(action(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+
+    fn refused() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused")
+    }
+
+    #[test]
+    fn every_variant_is_displayed() {
+        let cases = [
+            (
+                DialError::Config("Unknown argument: --x".to_string()),
+                "Unknown argument: --x",
+            ),
+            (
+                DialError::Socket("Binding 0.0.0.0:1900".to_string(), refused()),
+                "Binding 0.0.0.0:1900 failed: connection refused",
+            ),
+            (
+                DialError::Parse("no request line".to_string()),
+                "Invalid request: no request line",
+            ),
+            (
+                DialError::Launch("YouTube".to_string(), refused()),
+                "Failed to launch YouTube: connection refused",
+            ),
+            (
+                DialError::PortInUse(8081, 1),
+                "Port 8081 is already in use (is another dial_server running?), choose another \
+                 one with --http-port or pass --port-fallback",
+            ),
+            (
+                DialError::PortInUse(8081, 10),
+                "Ports 8081 to 8090 are all in use, choose another one with --http-port",
+            ),
+        ];
+        for (error, expected) in cases {
+            assert_eq!(error.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn usage_errors_exit_with_2() {
+        assert_eq!(DialError::Config(String::new()).exit_code(), 2);
+        assert_eq!(DialError::Parse(String::new()).exit_code(), 1);
+        assert_eq!(DialError::PortInUse(8081, 1).exit_code(), 1);
+    }
+
+    #[test]
+    fn io_errors_are_the_source() {
+        let error = DialError::Socket("Binding".to_string(), refused());
+        assert_eq!(error.source().unwrap().to_string(), "connection refused");
+        assert!(DialError::Config(String::new()).source().is_none());
+    }
+
+    #[test]
+    fn socket_context_describes_the_action() {
+        let result: io::Result<()> = Err(refused());
+        let error = result
+            .socket_context(|| "Joining 239.255.255.250".to_string())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Joining 239.255.255.250 failed: connection refused"
+        );
+    }
+}
@@ -0,0 +1,63 @@
+// Launch events POSTed to the --event-webhook, one request per event. Delivery is best effort:
+// failures are logged and the event is dropped, launches never wait for the webhook.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::apps::LaunchAction;
+use crate::client;
+use crate::context::AppContext;
+use crate::json;
+
+// a webhook that doesn't answer in time only costs us the spawned task
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sends the event of a launch to the --event-webhook, if there is one.
+pub fn launched(ctx: &AppContext, name: &str, run_id: u64, action: LaunchAction) {
+    let Some(url) = ctx.config.event_webhook.clone() else {
+        return;
+    };
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let event = launch_event(time, name, run_id, action);
+    tokio::spawn(async move {
+        let headers = [("Content-Type", "application/json")];
+        match client::request("POST", &url, &headers, event.as_bytes(), TIMEOUT).await {
+            Ok(response) if (200..300).contains(&response.status) => {}
+            Ok(response) => info!("Event webhook {} answered {}", url, response.status),
+            Err(e) => info!("Failed to send the event to {}: {}", url, e),
+        }
+    });
+}
+
+fn launch_event(time: Duration, name: &str, run_id: u64, action: LaunchAction) -> String {
+    format!(
+        r#"{{"time":{}.{:03},"action":"{}","app":"{}","run_id":{}}}"#,
+        time.as_secs(),
+        time.subsec_millis(),
+        action,
+        json::escape(name),
+        run_id
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::Value;
+
+    #[test]
+    fn launch_events_parse() {
+        let event = launch_event(
+            Duration::from_secs(1_700_000_000),
+            "My \"App\"",
+            7,
+            LaunchAction::Relaunch,
+        );
+        let Ok(Value::Object(fields)) = json::parse(&event) else {
+            panic!("not a JSON object: {}", event);
+        };
+        assert_eq!(fields["action"], Value::String("relaunch".to_string()));
+        assert_eq!(fields["app"], Value::String("My \"App\"".to_string()));
+        assert_eq!(fields["run_id"], Value::Number(7.0));
+    }
+}
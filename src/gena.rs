@@ -0,0 +1,297 @@
+// Eventing (GENA) subscriptions, UDA 1.1 section 4. No events are sent yet, but control points
+// refuse to use a service whose eventSubURL doesn't accept their SUBSCRIBE, so subscriptions are
+// handed out, renewed and cancelled like a real device would.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use http::header::HeaderValue;
+use http::{Request, Response, StatusCode};
+use tokio::time::Instant;
+
+use crate::context::AppContext;
+use crate::dial;
+
+pub const AV_TRANSPORT_EVENT_PATH: &str = "/AVTransport/evt";
+// used when the subscriber doesn't ask for a duration (or asks for "infinite"), and the longest
+// one we grant: UDA 1.1 section 4.1.2 lets the device pick a shorter duration than requested
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1800);
+// new subscriptions beyond that are refused until some expire or are cancelled
+const MAX_SUBSCRIPTIONS: usize = 100;
+
+#[derive(Debug)]
+pub struct Subscription {
+    // urls from the CALLBACK header, events would be sent to the first one that works
+    pub callbacks: Vec<String>,
+    pub expires: Instant,
+}
+
+/// Subscriptions by SID.
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    subscriptions: HashMap<String, Subscription>,
+}
+
+impl Subscriptions {
+    fn remove_expired(&mut self) {
+        let now = Instant::now();
+        self.subscriptions
+            .retain(|_, subscription| subscription.expires > now);
+    }
+}
+
+/// Handles SUBSCRIBE and UNSUBSCRIBE on an eventSubURL.
+pub fn handle(ctx: &AppContext, request: &Request<String>) -> Response<Vec<u8>> {
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+    };
+    // computed before taking the lock, nothing in between may panic and poison it
+    let timeout = requested_timeout(header("timeout"));
+    let Some(expires) = Instant::now().checked_add(timeout) else {
+        return dial::response(StatusCode::BAD_REQUEST);
+    };
+    let mut subscriptions = ctx
+        .subscriptions
+        .lock()
+        .expect("subscriptions lock poisoned");
+    subscriptions.remove_expired();
+
+    match (request.method().as_str(), header("sid")) {
+        // UDA 1.1 section 4.1.2: a renewal only carries SID (and TIMEOUT)
+        ("SUBSCRIBE", Some(sid)) => {
+            if header("callback").is_some() || header("nt").is_some() {
+                return dial::response(StatusCode::BAD_REQUEST);
+            }
+            match subscriptions.subscriptions.get_mut(sid) {
+                Some(subscription) => {
+                    subscription.expires = expires;
+                    info!("GENA: renewed {} for {:?}", sid, timeout);
+                    subscribed(sid, timeout)
+                }
+                None => dial::response(StatusCode::PRECONDITION_FAILED),
+            }
+        }
+        ("SUBSCRIBE", None) => {
+            let callbacks = header("callback").map(parse_callbacks).unwrap_or_default();
+            if header("nt") != Some("upnp:event") || callbacks.is_empty() {
+                return dial::response(StatusCode::PRECONDITION_FAILED);
+            }
+            // UDA 1.1 section 4.1.1: 5xx, unable to accept the subscription
+            if subscriptions.subscriptions.len() >= MAX_SUBSCRIPTIONS {
+                info!(
+                    "GENA: refusing {}, {} subscriptions already",
+                    callbacks.join(", "),
+                    MAX_SUBSCRIPTIONS
+                );
+                return dial::response(StatusCode::SERVICE_UNAVAILABLE);
+            }
+            let sid = new_sid();
+            info!(
+                "GENA: {} subscribed as {} for {:?}",
+                callbacks.join(", "),
+                sid,
+                timeout
+            );
+            subscriptions
+                .subscriptions
+                .insert(sid.clone(), Subscription { callbacks, expires });
+            subscribed(&sid, timeout)
+        }
+        ("UNSUBSCRIBE", Some(sid)) => {
+            if header("callback").is_some() || header("nt").is_some() {
+                return dial::response(StatusCode::BAD_REQUEST);
+            }
+            match subscriptions.subscriptions.remove(sid) {
+                Some(subscription) => {
+                    info!(
+                        "GENA: {} unsubscribed {}",
+                        subscription.callbacks.join(", "),
+                        sid
+                    );
+                    dial::response(StatusCode::OK)
+                }
+                None => dial::response(StatusCode::PRECONDITION_FAILED),
+            }
+        }
+        ("UNSUBSCRIBE", None) => dial::response(StatusCode::PRECONDITION_FAILED),
+        _ => dial::response(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+// CALLBACK: <http://192.168.1.20:49152/evt><http://10.0.0.3/evt>
+fn parse_callbacks(header: &str) -> Vec<String> {
+    header
+        .split('<')
+        .filter_map(|part| part.split_once('>'))
+        .map(|(url, _)| url.trim())
+        .filter(|url| url.starts_with("http://"))
+        .map(str::to_string)
+        .collect()
+}
+
+// TIMEOUT: Second-1800 or Second-infinite, at most DEFAULT_TIMEOUT
+fn requested_timeout(header: Option<&str>) -> Duration {
+    header
+        .and_then(|value| value.trim().strip_prefix("Second-"))
+        .and_then(|seconds| seconds.parse().ok())
+        .filter(|&seconds| seconds > 0)
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TIMEOUT)
+        .min(DEFAULT_TIMEOUT)
+}
+
+fn subscribed(sid: &str, timeout: Duration) -> Response<Vec<u8>> {
+    let mut response = dial::response(StatusCode::OK);
+    let headers = response.headers_mut();
+    headers.insert("sid", HeaderValue::from_str(sid).expect("Invalid SID"));
+    headers.insert(
+        "timeout",
+        HeaderValue::from_str(&format!("Second-{}", timeout.as_secs())).expect("Invalid timeout"),
+    );
+    response
+}
+
+// SIDs only have to be unique for this device, the time keeps them apart across restarts
+fn new_sid() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let started = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let count = NEXT.fetch_add(1, Ordering::Relaxed);
+    format!(
+        "uuid:{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        (started >> 32) as u32,
+        (started >> 16) as u16,
+        started as u16,
+        (count >> 48) as u16,
+        count & 0xffff_ffff_ffff
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn subscribe(timeout: &str) -> Request<String> {
+        Request::builder()
+            .method("SUBSCRIBE")
+            .uri(AV_TRANSPORT_EVENT_PATH)
+            .header("callback", "<http://192.168.1.20:49152/evt>")
+            .header("nt", "upnp:event")
+            .header("timeout", timeout)
+            .body(String::new())
+            .expect("valid request")
+    }
+
+    #[test]
+    fn timeout_is_clamped() {
+        assert_eq!(
+            requested_timeout(Some("Second-300")),
+            Duration::from_secs(300)
+        );
+        assert_eq!(requested_timeout(Some("Second-86400")), DEFAULT_TIMEOUT);
+        assert_eq!(
+            requested_timeout(Some("Second-18446744073709551615")),
+            DEFAULT_TIMEOUT
+        );
+        assert_eq!(requested_timeout(Some("Second-infinite")), DEFAULT_TIMEOUT);
+        assert_eq!(requested_timeout(Some("Second-0")), DEFAULT_TIMEOUT);
+        assert_eq!(requested_timeout(None), DEFAULT_TIMEOUT);
+    }
+
+    // used to panic while holding the lock, every later SUBSCRIBE panicked on the poisoned lock
+    #[tokio::test]
+    async fn huge_timeout_neither_panics_nor_breaks_later_subscriptions() {
+        let ctx = AppContext::new(Config::default());
+        let response = handle(&ctx, &subscribe("Second-18446744073709551615"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["timeout"], "Second-1800");
+        let response = handle(&ctx, &subscribe("Second-60"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["timeout"], "Second-60");
+    }
+
+    #[tokio::test]
+    async fn subscriptions_are_capped() {
+        let ctx = AppContext::new(Config::default());
+        for _ in 0..MAX_SUBSCRIPTIONS {
+            assert_eq!(
+                handle(&ctx, &subscribe("Second-60")).status(),
+                StatusCode::OK
+            );
+        }
+        assert_eq!(
+            handle(&ctx, &subscribe("Second-60")).status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    fn with_sid(method: &str, sid: &str) -> Request<String> {
+        Request::builder()
+            .method(method)
+            .uri(AV_TRANSPORT_EVENT_PATH)
+            .header("sid", sid)
+            .header("timeout", "Second-120")
+            .body(String::new())
+            .expect("valid request")
+    }
+
+    #[tokio::test]
+    async fn subscribe_renew_and_unsubscribe() {
+        let ctx = AppContext::new(Config::default());
+        let response = handle(&ctx, &subscribe("Second-60"));
+        assert_eq!(response.status(), StatusCode::OK);
+        let sid = response.headers()["sid"].to_str().unwrap().to_string();
+        assert!(sid.starts_with("uuid:"));
+
+        let renewed = handle(&ctx, &with_sid("SUBSCRIBE", &sid));
+        assert_eq!(renewed.status(), StatusCode::OK);
+        assert_eq!(renewed.headers()["sid"], sid.as_str());
+        assert_eq!(renewed.headers()["timeout"], "Second-120");
+
+        let unsubscribed = handle(&ctx, &with_sid("UNSUBSCRIBE", &sid));
+        assert_eq!(unsubscribed.status(), StatusCode::OK);
+        assert!(ctx.subscriptions.lock().unwrap().subscriptions.is_empty());
+        // gone, neither a renewal nor a second UNSUBSCRIBE finds it
+        for method in ["SUBSCRIBE", "UNSUBSCRIBE"] {
+            let response = handle(&ctx, &with_sid(method, &sid));
+            assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        }
+    }
+
+    #[tokio::test]
+    async fn subscriptions_need_a_callback_and_nt() {
+        let ctx = AppContext::new(Config::default());
+        let mut without_nt = subscribe("Second-60");
+        without_nt.headers_mut().remove("nt");
+        let mut without_callback = subscribe("Second-60");
+        without_callback.headers_mut().remove("callback");
+        for request in [without_nt, without_callback] {
+            assert_eq!(
+                handle(&ctx, &request).status(),
+                StatusCode::PRECONDITION_FAILED
+            );
+        }
+        // a renewal with the headers of a new subscription
+        let mut mixed = with_sid("SUBSCRIBE", "uuid:x");
+        mixed
+            .headers_mut()
+            .insert("nt", "upnp:event".parse().unwrap());
+        assert_eq!(handle(&ctx, &mixed).status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn callbacks_are_the_http_urls_in_angle_brackets() {
+        assert_eq!(
+            parse_callbacks("<http://192.0.2.9:49152/evt><ftp://x/><http://10.0.0.3/evt>"),
+            ["http://192.0.2.9:49152/evt", "http://10.0.0.3/evt"]
+        );
+        assert!(parse_callbacks("http://192.0.2.9/evt").is_empty());
+    }
+}
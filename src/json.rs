@@ -0,0 +1,253 @@
+// Just enough JSON for the admin API and the --apps-file, https://www.rfc-editor.org/rfc/rfc8259
+use std::collections::BTreeMap;
+
+// arrays and objects are parsed recursively, deeper documents would overflow the stack
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+/// Parses a complete JSON document, the error says what was expected where.
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        chars: text.char_indices().peekable(),
+        text,
+        depth: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    match parser.chars.peek() {
+        None => Ok(value),
+        Some(&(position, _)) => Err(format!("unexpected data at {}", position)),
+    }
+}
+
+pub fn escape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    text: &'a str,
+    // arrays and objects we are in
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self
+            .chars
+            .next_if(|(_, c)| matches!(c, ' ' | '\t' | '\n' | '\r'))
+            .is_some()
+        {}
+    }
+
+    fn position(&mut self) -> usize {
+        self.chars
+            .peek()
+            .map(|&(position, _)| position)
+            .unwrap_or(self.text.len())
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        let position = self.position();
+        match self.chars.next() {
+            Some((_, c)) if c == expected => Ok(()),
+            _ => Err(format!("expected '{}' at {}", expected, position)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        let position = self.position();
+        match self.chars.peek().map(|&(_, c)| c) {
+            Some('{') | Some('[') if self.depth == MAX_DEPTH => Err(format!(
+                "nested deeper than {} levels at {}",
+                MAX_DEPTH, position
+            )),
+            Some('{') => self.nested(Self::object),
+            Some('[') => self.nested(Self::array),
+            Some('"') => self.string().map(Value::String),
+            Some('t') => self.literal("true", Value::Bool(true)),
+            Some('f') => self.literal("false", Value::Bool(false)),
+            Some('n') => self.literal("null", Value::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            _ => Err(format!("expected a value at {}", position)),
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Value, String>) -> Result<Value, String> {
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn literal(&mut self, literal: &str, value: Value) -> Result<Value, String> {
+        let position = self.position();
+        if !self.text[position..].starts_with(literal) {
+            return Err(format!("expected {} at {}", literal, position));
+        }
+        for _ in 0..literal.len() {
+            self.chars.next();
+        }
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.position();
+        while self
+            .chars
+            .next_if(|(_, c)| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+            .is_some()
+        {}
+        let end = self.position();
+        self.text[start..end]
+            .parse()
+            .map(Value::Number)
+            .map_err(|_| format!("invalid number at {}", start))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut result = String::new();
+        loop {
+            let position = self.position();
+            match self.chars.next() {
+                None => return Err("unterminated string".to_string()),
+                Some((_, '"')) => return Ok(result),
+                Some((_, '\\')) => match self.chars.next().map(|(_, c)| c) {
+                    Some('"') => result.push('"'),
+                    Some('\\') => result.push('\\'),
+                    Some('/') => result.push('/'),
+                    Some('b') => result.push('\u{8}'),
+                    Some('f') => result.push('\u{c}'),
+                    Some('n') => result.push('\n'),
+                    Some('r') => result.push('\r'),
+                    Some('t') => result.push('\t'),
+                    Some('u') => {
+                        let hex: String = (0..4)
+                            .filter_map(|_| self.chars.next())
+                            .map(|(_, c)| c)
+                            .collect();
+                        // surrogate pairs aren't combined, they end up as U+FFFD
+                        let c = u32::from_str_radix(&hex, 16)
+                            .map_err(|_| format!("invalid \\u escape at {}", position))?;
+                        result.push(char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    _ => return Err(format!("invalid escape at {}", position)),
+                },
+                Some((_, c)) if (c as u32) < 0x20 => {
+                    return Err(format!("control character in string at {}", position))
+                }
+                Some((_, c)) => result.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.chars.next_if(|&(_, c)| c == ']').is_some() {
+            return Ok(Value::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            self.skip_whitespace();
+            let position = self.position();
+            match self.chars.next().map(|(_, c)| c) {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(values)),
+                _ => return Err(format!("expected ',' or ']' at {}", position)),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut members = BTreeMap::new();
+        self.skip_whitespace();
+        if self.chars.next_if(|&(_, c)| c == '}').is_some() {
+            return Ok(Value::Object(members));
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(':')?;
+            members.insert(key, self.value()?);
+            self.skip_whitespace();
+            let position = self.position();
+            match self.chars.next().map(|(_, c)| c) {
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Object(members)),
+                _ => return Err(format!("expected ',' or '}}' at {}", position)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_documents() {
+        let value = parse(r#"{"name": "YouTube", "env": {"A": "1"}, "list": [1, true, null]}"#)
+            .expect("valid json");
+        let Value::Object(members) = value else {
+            panic!("not an object: {:?}", value);
+        };
+        assert_eq!(members["name"], Value::String("YouTube".to_string()));
+        assert_eq!(
+            members["list"],
+            Value::Array(vec![Value::Number(1.0), Value::Bool(true), Value::Null])
+        );
+    }
+
+    #[test]
+    fn accepts_the_maximum_depth() {
+        let text = format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+        assert!(parse(&text).is_ok());
+    }
+
+    #[test]
+    fn rejects_deeper_documents() {
+        let text = format!("{}{}", "[".repeat(MAX_DEPTH + 1), "]".repeat(MAX_DEPTH + 1));
+        let error = parse(&text).expect_err("too deep");
+        assert!(error.contains("nested deeper"), "{}", error);
+    }
+
+    // used to overflow the stack and abort the process
+    #[test]
+    fn rejects_deep_garbage_without_overflowing() {
+        assert!(parse(&"[".repeat(60000)).is_err());
+        assert!(parse(&"{\"a\":".repeat(60000)).is_err());
+    }
+
+    #[test]
+    fn escape_round_trips() {
+        let original = "quote \" backslash \\ newline \n bell \u{7}";
+        let parsed = parse(&format!("\"{}\"", escape(original))).expect("valid string");
+        assert_eq!(parsed, Value::String(original.to_string()));
+    }
+}
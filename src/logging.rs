@@ -0,0 +1,103 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::json;
+
+static DEBUG: AtomicBool = AtomicBool::new(false);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_debug(enabled: bool) {
+    DEBUG.store(enabled, Ordering::Relaxed);
+}
+
+pub fn debug_enabled() -> bool {
+    DEBUG.load(Ordering::Relaxed)
+}
+
+/// --log-format json
+pub fn set_json(enabled: bool) {
+    JSON.store(enabled, Ordering::Relaxed);
+}
+
+/// Prints a log line, as it is (warnings prefixed with "Warning: ") or as a JSON object with the
+/// time (seconds since the epoch), the level, the module it comes from and the message.
+pub fn write(level: &str, target: &str, message: fmt::Arguments) {
+    if !JSON.load(Ordering::Relaxed) {
+        if level == "warn" {
+            println!("Warning: {}", message);
+        } else {
+            println!("{}", message);
+        }
+        return;
+    }
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    println!("{}", json_line(time, level, target, message));
+}
+
+fn json_line(time: Duration, level: &str, target: &str, message: fmt::Arguments) -> String {
+    format!(
+        r#"{{"time":{}.{:03},"level":"{}","target":"{}","message":"{}"}}"#,
+        time.as_secs(),
+        time.subsec_millis(),
+        level,
+        json::escape(target),
+        json::escape(&message.to_string())
+    )
+}
+
+// a log line, like println!
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::logging::write("info", module_path!(), format_args!($($arg)*))
+    };
+}
+
+// like info!, but logged with level warn
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::logging::write("warn", module_path!(), format_args!($($arg)*))
+    };
+}
+
+// like info!, but only prints when started with --debug
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::debug_enabled() {
+            $crate::logging::write("debug", module_path!(), format_args!($($arg)*));
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::Value;
+
+    #[test]
+    fn json_lines_parse() {
+        let time = Duration::from_millis(1_700_000_000_042);
+        let line = json_line(
+            time,
+            "warn",
+            "dial_server::ssdp",
+            format_args!("bad \"request\" from {}\n\tline two", "127.0.0.1"),
+        );
+        assert!(!line.contains('\n'), "{}", line);
+        let Ok(Value::Object(fields)) = json::parse(&line) else {
+            panic!("not a JSON object: {}", line);
+        };
+        assert_eq!(fields["time"], Value::Number(1_700_000_000.042));
+        assert_eq!(fields["level"], Value::String("warn".to_string()));
+        assert_eq!(
+            fields["target"],
+            Value::String("dial_server::ssdp".to_string())
+        );
+        assert_eq!(
+            fields["message"],
+            Value::String("bad \"request\" from 127.0.0.1\n\tline two".to_string())
+        );
+    }
+}
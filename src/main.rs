@@ -1,30 +1,67 @@
-use std::collections::HashMap;
-use std::io::Read;
-use std::net::{Ipv4Addr, UdpSocket};
-use std::time::Duration;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
 
 use http::header::HeaderValue;
+use http::StatusCode;
+use socket2::SockRef;
+
+#[macro_use]
+mod logging;
+
+mod apps;
+mod avtransport;
+mod capture;
+mod check;
+mod client;
+mod clock;
+mod config;
+mod container;
+mod context;
+mod dial;
+mod error;
+mod events;
+mod gena;
+mod json;
+mod profile;
+mod proxy;
+mod registration;
+mod selftest;
+mod ssdp;
+mod store;
+#[cfg(feature = "systemd")]
+mod systemd;
+
+use clock::Clock;
+use config::{Config, LogFormat, OwnByebye, SsdpTcp};
+use context::{AnnounceRequest, AppContext};
+use error::{DialError, SocketContext};
+use ssdp::{SsdpMessage, SsdpStartLine};
 
 // https://sites.google.com/a/dial-multiscreen.org/dial/dial-protocol-specification
 // Used Version: DIAL-2ndScreenProtocol-2.2.1.pdf
 
+const SSDP_MULTICAST_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(ssdp::MULTICAST_IP, 1900));
 const ROOT_DEVICE_UUID: &str = "170ba466-59ac-4039-a457-0fab725b60ff";
 
 fn parse_request_to_string(request: http::request::Builder) -> String {
     let mut result: String = String::new();
     result.push_str(&format!(
         "{} {} {}\r\n",
-        request.method_ref().unwrap().to_string(),
-        request.uri_ref().unwrap().to_string(),
-        match request.version_ref().unwrap() {
-            &http::Version::HTTP_09 => "HTTP/0.9",
-            &http::Version::HTTP_10 => "HTTP/1.0",
-            &http::Version::HTTP_11 => "HTTP/1.1",
-            &http::Version::HTTP_2 => "HTTP/2.0",
-            &http::Version::HTTP_3 => "HTTP/3.0",
+        request.method_ref().unwrap(),
+        request.uri_ref().unwrap(),
+        match *request.version_ref().unwrap() {
+            http::Version::HTTP_09 => "HTTP/0.9",
+            http::Version::HTTP_10 => "HTTP/1.0",
+            http::Version::HTTP_11 => "HTTP/1.1",
+            http::Version::HTTP_2 => "HTTP/2.0",
+            http::Version::HTTP_3 => "HTTP/3.0",
             _ => "HTTP/1.1",
         },
     ));
@@ -43,10 +80,16 @@ fn parse_request_to_string(request: http::request::Builder) -> String {
     result
 }
 
-async fn broadcast_root_device_to_network(
-    socket: &UdpSocket,
+fn cache_control(max_age: u32) -> HeaderValue {
+    HeaderValue::from_str(&format!("max-age = {}", max_age)).expect("This should never be invalid")
+}
+
+fn root_device_notifies(
     root_device_url: &str,
-) -> tokio::io::Result<()> {
+    max_age: u32,
+    config_id: u32,
+    config: &Config,
+) -> Vec<String> {
     // http://www.upnp.org/specs/arch/UPnP-arch-DeviceArchitecture-v1.0.pdf
     // see http://www.upnp.org/specs/basic/UPnP-basic-Basic-v1-Device.pdf
     /*
@@ -62,20 +105,21 @@ async fn broadcast_root_device_to_network(
     // 3 messages for root device
     // Message 1: NT: upnp:rootdevice  ->USN:  uuid:device-UUID::upnp:rootdevice
     let uuid_nt = "upnp:rootdevice";
-    let uuid_usn = format!("uuid::{}::{}", ROOT_DEVICE_UUID, "upnp:rootdevice").to_string();
+    let uuid_usn = format!("uuid:{}::{}", ROOT_DEVICE_UUID, "upnp:rootdevice").to_string();
     let request1 = http::Request::builder()
         .method("NOTIFY")
         .uri("*")
         .version(http::Version::HTTP_11)
         .header("HOST", HeaderValue::from_static("239.255.255.250:1900"))
-        .header("cache-control", HeaderValue::from_static("max-age = 900"))
+        .header("cache-control", cache_control(max_age))
+        .header("CONFIGID.UPNP.ORG", config_id)
         .header(
             "LOCATION",
-            HeaderValue::from_str(&root_device_url).expect("Invalid url"),
+            HeaderValue::from_str(root_device_url).expect("Invalid url"),
         )
         .header(
             "NT",
-            HeaderValue::from_str(&uuid_nt).expect("This should never be invalid utf-8"),
+            HeaderValue::from_str(uuid_nt).expect("This should never be invalid utf-8"),
         )
         .header(
             "USN",
@@ -84,21 +128,22 @@ async fn broadcast_root_device_to_network(
         .header("NTS", HeaderValue::from_static("ssdp:alive"))
         .header(
             "SERVER",
-            HeaderValue::from_static("Linus/Arch UPnP/1.0 Linus_Listener/1.0"),
+            server_header(config, &config.server_header.root_device),
         );
 
     // Message 2: NT: uuid:device-UUID   ->USN: uuid:device-UUID (for root device UUID)
-    let uuid_nt = format!("uuid::{}", ROOT_DEVICE_UUID).to_string();
-    let uuid_usn = format!("uuid::{}", ROOT_DEVICE_UUID).to_string();
+    let uuid_nt = format!("uuid:{}", ROOT_DEVICE_UUID).to_string();
+    let uuid_usn = format!("uuid:{}", ROOT_DEVICE_UUID).to_string();
     let request2 = http::Request::builder()
         .method("NOTIFY")
         .uri("*")
         .version(http::Version::HTTP_11)
         .header("HOST", HeaderValue::from_static("239.255.255.250:1900"))
-        .header("cache-control", HeaderValue::from_static("max-age = 900"))
+        .header("cache-control", cache_control(max_age))
+        .header("CONFIGID.UPNP.ORG", config_id)
         .header(
             "LOCATION",
-            HeaderValue::from_str(&root_device_url).expect("Invalid url"),
+            HeaderValue::from_str(root_device_url).expect("Invalid url"),
         )
         .header(
             "NT",
@@ -107,7 +152,7 @@ async fn broadcast_root_device_to_network(
         .header("NTS", HeaderValue::from_static("ssdp:alive"))
         .header(
             "SERVER",
-            HeaderValue::from_static("Linus/Arch UPnP/1.0 Linus_Listener/1.0"),
+            server_header(config, &config.server_header.root_device),
         )
         .header(
             "USN",
@@ -124,21 +169,18 @@ async fn broadcast_root_device_to_network(
        uuid:device-UUID::urn:schemas-upnp-org:device:deviceType:v (of root device) or
        uuid:device-UUID::urn:domain-name:device:deviceType:v
     */
-    let uuid_nt = format!("urn:schemas-upnp-org:device:{}:{}", "Basic", "1").to_string();
-    let uuid_usn = format!(
-        "uuid:{}::urn:schemas-upnp-org:device:{}:{}",
-        ROOT_DEVICE_UUID, "Basic", "1"
-    )
-    .to_string();
+    let uuid_nt = config.device_type.clone();
+    let uuid_usn = format!("uuid:{}::{}", ROOT_DEVICE_UUID, config.device_type);
     let request3 = http::Request::builder()
         .method("NOTIFY")
         .uri("*")
         .version(http::Version::HTTP_11)
         .header("HOST", HeaderValue::from_static("239.255.255.250:1900"))
-        .header("cache-control", HeaderValue::from_static("max-age = 900"))
+        .header("cache-control", cache_control(max_age))
+        .header("CONFIGID.UPNP.ORG", config_id)
         .header(
             "LOCATION",
-            HeaderValue::from_str(&root_device_url).expect("Invalid url"),
+            HeaderValue::from_str(root_device_url).expect("Invalid url"),
         )
         .header(
             "NT",
@@ -147,7 +189,7 @@ async fn broadcast_root_device_to_network(
         .header("NTS", HeaderValue::from_static("ssdp:alive"))
         .header(
             "SERVER",
-            HeaderValue::from_static("Linus/Arch UPnP/1.0 Linus_Listener/1.0"),
+            server_header(config, &config.server_header.root_device),
         )
         //uuid:device-UUID::upnp:rootdevice
         .header(
@@ -155,22 +197,19 @@ async fn broadcast_root_device_to_network(
             HeaderValue::from_str(&uuid_usn).expect("This should never be invalid utf-8"),
         );
 
-    println!("Sending broadcast messages");
-    socket.send(parse_request_to_string(request1).as_bytes())?;
-    println!("Sent message 1");
-    tokio::time::sleep(Duration::from_millis(100)).await;
-    socket.send(parse_request_to_string(request2).as_bytes())?;
-    println!("Sent message 2");
-    tokio::time::sleep(Duration::from_millis(100)).await;
-    socket.send(parse_request_to_string(request3).as_bytes())?;
-    println!("Sent message 3");
-    Ok(())
+    vec![
+        parse_request_to_string(request1),
+        parse_request_to_string(request2),
+        parse_request_to_string(request3),
+    ]
 }
 
-async fn broadcast_device_to_network(
-    socket: &UdpSocket,
+fn device_notifies(
     root_device_url: &str,
-) -> tokio::io::Result<()> {
+    max_age: u32,
+    config_id: u32,
+    config: &Config,
+) -> Vec<String> {
     /*
     NOTIFY * HTTP/1.1
     USN: uuid:aadda81b-614f-3719-b247-c7545f302b6d::urn:dial-multiscreen-org:device:dial:1
@@ -185,17 +224,18 @@ async fn broadcast_device_to_network(
     // 2 messages for each embedded device
     // NT: uuid:device-UUID -> USN: uuid:device-UUID
     // Message 2: NT: uuid:device-UUID   ->USN: uuid:device-UUID (for root device UUID)
-    let uuid_nt = format!("uuid::{}", ROOT_DEVICE_UUID).to_string();
-    let uuid_usn = format!("uuid::{}", ROOT_DEVICE_UUID).to_string();
+    let uuid_nt = format!("uuid:{}", ROOT_DEVICE_UUID).to_string();
+    let uuid_usn = format!("uuid:{}", ROOT_DEVICE_UUID).to_string();
     let request1 = http::Request::builder()
         .method("NOTIFY")
         .uri("*")
         .version(http::Version::HTTP_11)
         .header("HOST", HeaderValue::from_static("239.255.255.250:1900"))
-        .header("cache-control", HeaderValue::from_static("max-age = 900"))
+        .header("cache-control", cache_control(max_age))
+        .header("CONFIGID.UPNP.ORG", config_id)
         .header(
             "LOCATION",
-            HeaderValue::from_str(&root_device_url).expect("Invalid url"),
+            HeaderValue::from_str(root_device_url).expect("Invalid url"),
         )
         .header(
             "NT",
@@ -204,7 +244,7 @@ async fn broadcast_device_to_network(
         .header("NTS", HeaderValue::from_static("ssdp:alive"))
         .header(
             "SERVER",
-            HeaderValue::from_static("Linus/Arch UPnP/1.0 Linus_Listener/1.0"),
+            server_header(config, &config.server_header.device),
         )
         //uuid:device-UUID::upnp:rootdevice
         .header(
@@ -222,21 +262,18 @@ async fn broadcast_device_to_network(
        uuid:device-UUID::urn:schemas-upnp-org:device:deviceType:v (of root device) or
        uuid:device-UUID::urn:domain-name:device:deviceType:v
     */
-    let uuid_nt = format!("urn:schemas-upnp-org:device:{}:{}", "Basic", "1").to_string();
-    let uuid_usn = format!(
-        "uuid:{}::urn:schemas-upnp-org:device:{}:{}",
-        ROOT_DEVICE_UUID, "Basic", "1"
-    )
-    .to_string();
+    let uuid_nt = config.device_type.clone();
+    let uuid_usn = format!("uuid:{}::{}", ROOT_DEVICE_UUID, config.device_type);
     let request2 = http::Request::builder()
         .method("NOTIFY")
         .uri("*")
         .version(http::Version::HTTP_11)
         .header("HOST", HeaderValue::from_static("239.255.255.250:1900"))
-        .header("cache-control", HeaderValue::from_static("max-age = 900"))
+        .header("cache-control", cache_control(max_age))
+        .header("CONFIGID.UPNP.ORG", config_id)
         .header(
             "LOCATION",
-            HeaderValue::from_str(&root_device_url).expect("Invalid url"),
+            HeaderValue::from_str(root_device_url).expect("Invalid url"),
         )
         .header(
             "NT",
@@ -245,26 +282,25 @@ async fn broadcast_device_to_network(
         .header("NTS", HeaderValue::from_static("ssdp:alive"))
         .header(
             "SERVER",
-            HeaderValue::from_static("Linus/Arch UPnP/1.0 Linus_Listener/1.0"),
+            server_header(config, &config.server_header.device),
         )
         .header(
             "USN",
             HeaderValue::from_str(&uuid_usn).expect("This should never be invalid utf-8"),
         );
 
-    println!("Sending device messages");
-    socket.send(parse_request_to_string(request1).as_bytes())?;
-    println!("Sent message 1");
-    tokio::time::sleep(Duration::from_millis(100)).await;
-    socket.send(parse_request_to_string(request2).as_bytes())?;
-    println!("Sent message 2");
-    Ok(())
+    vec![
+        parse_request_to_string(request1),
+        parse_request_to_string(request2),
+    ]
 }
 
-async fn broadcast_service_type_to_network(
-    socket: &UdpSocket,
+fn service_notifies(
     root_device_url: &str,
-) -> tokio::io::Result<()> {
+    max_age: u32,
+    config_id: u32,
+    config: &Config,
+) -> Vec<String> {
     /*
     Probably need the following services:
     RenderingControl: http://upnp.org/specs/av/UPnP-av-RenderingControl-v1-Service.pdf
@@ -298,10 +334,11 @@ async fn broadcast_service_type_to_network(
         .uri("*")
         .version(http::Version::HTTP_11)
         .header("HOST", HeaderValue::from_static("239.255.255.250:1900"))
-        .header("cache-control", HeaderValue::from_static("max-age = 900"))
+        .header("cache-control", cache_control(max_age))
+        .header("CONFIGID.UPNP.ORG", config_id)
         .header(
             "LOCATION",
-            HeaderValue::from_str(&root_device_url).expect("Invalid url"),
+            HeaderValue::from_str(root_device_url).expect("Invalid url"),
         )
         .header(
             "NT",
@@ -310,227 +347,3420 @@ async fn broadcast_service_type_to_network(
         .header("NTS", HeaderValue::from_static("ssdp:alive"))
         .header(
             "SERVER",
-            HeaderValue::from_static("Linus/Arch UPnP/1.0 Linus_Listener/1.0"),
+            server_header(config, &config.server_header.service),
         )
         .header(
             "USN",
             HeaderValue::from_str(&uuid_usn).expect("This should never be invalid utf-8"),
         );
 
-    println!("Sending device messages");
-    socket.send(parse_request_to_string(request1).as_bytes())?;
-    println!("Sent message 1");
+    vec![parse_request_to_string(request1)]
+}
+
+// 100ms apart, returns how many were sent
+async fn send_notifies(socket: &UdpSocket, messages: &[String]) -> tokio::io::Result<usize> {
+    for (i, message) in messages.iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        send_datagram(socket, message.as_bytes(), SSDP_MULTICAST_ADDR)?;
+        info!("Sent message {}", i + 1);
+    }
+    Ok(messages.len())
+}
+
+// the byebye of every advertisement, in the order they are sent
+fn byebye_messages(config: &Config) -> Vec<String> {
+    // same NT/USN pairs as the ssdp:alive messages (in the same order) so clients forget
+    // everything we announced
+    let mut advertisements = vec![
+        (
+            "upnp:rootdevice".to_string(),
+            format!("uuid:{}::{}", ROOT_DEVICE_UUID, "upnp:rootdevice"),
+        ),
+        (
+            format!("uuid:{}", ROOT_DEVICE_UUID),
+            format!("uuid:{}", ROOT_DEVICE_UUID),
+        ),
+        (
+            config.device_type.clone(),
+            format!("uuid:{}::{}", ROOT_DEVICE_UUID, config.device_type),
+        ),
+    ];
+    if config.profile.advertise_services {
+        advertisements.push((
+            format!(
+                "urn:schemas-upnp-org:service:{}:{}",
+                "RenderingControl", "1"
+            ),
+            format!(
+                "uuid:{}::urn:schemas-upnp-org:service:{}:{}",
+                ROOT_DEVICE_UUID, "RenderingControl", "1"
+            ),
+        ));
+    }
+
+    // reverse order of the alive messages: embedded services and devices go before the root
+    // device, so a client never sees an embedded device whose root device is already gone
+    advertisements
+        .iter()
+        .rev()
+        .map(|(nt, usn)| byebye_message(nt, usn))
+        .collect()
+}
+
+async fn broadcast_byebye(socket: &UdpSocket, config: &Config) -> tokio::io::Result<()> {
+    info!("Sending byebye messages");
+    let messages = byebye_messages(config);
+    for repeat in 0..config.announce_repeats {
+        if repeat > 0 {
+            tokio::time::sleep(repeat_delay()).await;
+        }
+        for message in &messages {
+            debug!("{}", message);
+            send_datagram(socket, message.as_bytes(), SSDP_MULTICAST_ADDR)?;
+        }
+    }
+    Ok(())
+}
+
+/// UDA 1.1 section 1.2.3: a byebye only carries HOST, NT, NTS and USN. LOCATION, CACHE-CONTROL and
+/// SERVER describe an advertisement that is still valid and must not be sent.
+fn byebye_message(nt: &str, usn: &str) -> String {
+    let request = http::Request::builder()
+        .method("NOTIFY")
+        .uri("*")
+        .version(http::Version::HTTP_11)
+        .header("HOST", HeaderValue::from_static("239.255.255.250:1900"))
+        .header(
+            "NT",
+            HeaderValue::from_str(nt).expect("This should never be invalid utf-8"),
+        )
+        .header("NTS", HeaderValue::from_static("ssdp:byebye"))
+        .header(
+            "USN",
+            HeaderValue::from_str(usn).expect("This should never be invalid utf-8"),
+        );
+    parse_request_to_string(request)
+}
+
+// UDA 1.1 section 1.2.2: messages should be sent more than once with some time in between, a
+// random delay of 100-200ms keeps the repeats of several devices started together apart
+fn repeat_delay() -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    Duration::from_millis(100 + u64::from(nanos % 100))
+}
+
+// validated when the arguments were parsed
+fn server_header(config: &Config, configured: &Option<String>) -> HeaderValue {
+    HeaderValue::from_str(config.server(configured)).expect("Invalid SERVER header")
+}
 
+// every datagram we send goes through here so it ends up in the --capture file
+fn send_datagram(
+    socket: &UdpSocket,
+    payload: &[u8],
+    destination: SocketAddr,
+) -> std::io::Result<()> {
+    socket.send_to(payload, destination)?;
+    let source = socket
+        .local_addr()
+        .unwrap_or_else(|_| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    capture::record(capture::Direction::Sent, source, destination, payload);
     Ok(())
 }
 
-async fn broadcast_creation(socket: &UdpSocket, root_device_url: &str) -> tokio::io::Result<()> {
+// right after boot the interface may still be coming up (DHCP, IGMP join, bridge learning), a
+// NOTIFY sent in that window is silently lost and receivers only see us on the next round
+async fn wait_before_announcing(config: &Config) {
+    if config.announce_delay > 0 {
+        debug!("Waiting {}ms before announcing", config.announce_delay);
+        tokio::time::sleep(Duration::from_millis(config.announce_delay)).await;
+    }
+}
+
+async fn broadcast_creation(
+    socket: &UdpSocket,
+    root_device_url: &str,
+    config_id: u32,
+    config: &Config,
+) -> tokio::io::Result<usize> {
+    let max_age = &config.max_age;
     socket
         .set_broadcast(true)
         .expect("set_broadcast call failed ");
-    socket.connect("239.255.255.250:1900")?;
+    // no connect() here, a connected UDP socket only receives datagrams from the address it is
+    // connected to, which would drop every search
 
-    broadcast_root_device_to_network(&socket, root_device_url).await?;
-    broadcast_device_to_network(&socket, root_device_url).await?;
-    broadcast_service_type_to_network(&socket, root_device_url).await?;
+    let mut sent = 0;
+    for repeat in 0..config.announce_repeats {
+        if repeat > 0 {
+            tokio::time::sleep(repeat_delay()).await;
+        }
+        info!("Sending broadcast messages");
+        let messages =
+            root_device_notifies(root_device_url, max_age.root_device, config_id, config);
+        sent += send_notifies(socket, &messages).await?;
+        info!("Sending device messages");
+        let messages = device_notifies(root_device_url, max_age.device, config_id, config);
+        sent += send_notifies(socket, &messages).await?;
+        if config.profile.advertise_services {
+            info!("Sending device messages");
+            let messages = service_notifies(root_device_url, max_age.service, config_id, config);
+            sent += send_notifies(socket, &messages).await?;
+        }
+    }
 
     socket
         .set_broadcast(false)
         .expect("set_broadcast(false) call failed ");
-    tokio::io::Result::Ok(())
+    Ok(sent)
 }
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
-    //239.255.255.250
-    let address = "0.0.0.0";
-    let port = 1900;
-    println!("Opening UDP socket and listening on {}:{}", &address, &port);
-    let socket = UdpSocket::bind(format!("{}:{}", &address, &port))?;
-    socket
-        .join_multicast_v4(
-            &Ipv4Addr::new(239, 255, 255, 250),
-            &Ipv4Addr::new(0, 0, 0, 0),
-        )
-        .expect("Failed to join multicast ");
-    socket.set_multicast_loop_v4(true)?;
-    // println!(
-    //     "{}",
-    //     socket
-    //         .multicast_loop_v4()
-    //         .expect("Failed to retrieve multicast loop ")
-    // :;
-
-    let tcplistener = TcpListener::bind("0.0.0.0:8081").await?;
-    let local_ip = "192.168.178.9";
-    println!("Opening TCP socket and listening on 0.0.0.0:8081");
+/// The interfaces the NOTIFYs go out on (None: the one of the socket) and the LOCATION of the
+/// descriptor that receivers on each can reach, see --announce-interface.
+fn announce_locations(config: &Config, descriptor_url: &str) -> Vec<(Option<Ipv4Addr>, String)> {
+    if config.announce_interfaces.is_empty() {
+        return vec![(None, descriptor_url.to_string())];
+    }
+    config
+        .announce_interfaces
+        .iter()
+        .map(|&interface| {
+            let base_url = config.interface_base_url(interface);
+            (
+                Some(interface),
+                format!("{}{}", base_url, dial::DESCRIPTOR_PATH),
+            )
+        })
+        .collect()
+}
 
-    tokio::spawn(async move {
-        let mut buf = [0; 8 * 1024];
-        loop {
-            let (mut socket, socket_addr) = tcplistener
-                .accept()
-                .await
-                .expect("Failed to listen for tcp connection ");
-
-            tokio::spawn(async move {
-                loop {
-                    let n = match socket.read(&mut buf).await {
-                        // socket closed
-                        Ok(n) if n == 0 => return,
-                        Ok(n) => n,
-                        Err(e) => {
-                            println!("failed to read bytes: {}", e);
-                            return;
-                        }
-                    };
-
-                    println!("Received {} bytes", n);
-
-                    let mut buf = &mut buf[..n];
-
-                    println!("{}", socket_addr);
-                    let text = match std::str::from_utf8(&mut buf) {
-                        Ok(text) => {
-                            println!("{}", text);
-                            text
-                        }
-                        Err(e) => {
-                            println!("Received invalid utf-8 text: {}", e);
-                            return;
-                        }
-                    };
-
-                    let mut headers: HashMap<&str, &str> = HashMap::new();
-                    let method: &str;
-                    let path: &str;
-                    let protocol: &str;
-
-                    let mut lines = text.lines();
-                    if let Some(first_line) = lines.next() {
-                        let words: Vec<&str> = first_line.split(" ").collect();
-
-                        method = words[0];
-                        path = words[1];
-                        protocol = words[2];
-                        println!("method: {} path: {} protocol: {}", method, path, protocol);
-                    } else {
-                        println!("Invalid request");
-                        return;
-                    }
+// one announcement round on every interface, returns how many messages were sent
+async fn announce_on_interfaces(
+    socket: &UdpSocket,
+    descriptor_url: &str,
+    config_id: u32,
+    config: &Config,
+) -> tokio::io::Result<usize> {
+    let mut sent = 0;
+    for (interface, location) in announce_locations(config, descriptor_url) {
+        if let Some(interface) = interface {
+            info!("Announcing on {}", interface);
+            SockRef::from(socket).set_multicast_if_v4(&interface)?;
+        }
+        sent += broadcast_creation(socket, &location, config_id, config).await?;
+    }
+    Ok(sent)
+}
 
-                    lines.for_each(|line| {
-                        if line.is_empty() {
-                            return;
-                        }
-
-                        let words: Vec<&str> = line.split(": ").collect();
-                        if words.len() == 2 {
-                            headers.insert(words[0], words[1]);
-                        } else {
-                            println!("Invalid header line: {}", line);
-                        }
-                    });
-
-                    println!("Headers: {:#?}", headers);
-
-                    let xml;
-                    let resp: &[u8] = if path == "/" && method == "GET" {
-                        b"HTTP/1.1 200 OK
-Connection: Keep-Alive
-Access-Control-Allow-Origin: *
-Content-Type: text/html; charset=utf-8
-
-<html>
-<body>TEST</body>
-</html>"
-                    } else if path == "/upnp_device_descriptor.xml" && method == "GET" {
-                        let xml_content = &mut String::new();
-                        println!(
-                            "current filepath: {}",
-                            std::env::current_dir().unwrap().display()
-                        );
-                        std::fs::File::open("./src/desc.xml")
-                            .expect("Failed to open file: desc.xml")
-                            .read_to_string(xml_content)
-                            .expect("Failed to read file: desc.xml");
-                        xml = format!(
-                            "HTTP/1.1 200 OK
-content-type: application/xml
-
-{}",
-                            xml_content
-                        );
-                        xml.as_bytes()
-                    } else {
-                        b"HTTP/1.1 404 Not Found"
-                    };
+// the byebyes on every interface we announced on
+async fn byebye_on_interfaces(socket: &UdpSocket, config: &Config) -> tokio::io::Result<()> {
+    if config.announce_interfaces.is_empty() {
+        return broadcast_byebye(socket, config).await;
+    }
+    for interface in &config.announce_interfaces {
+        SockRef::from(socket).set_multicast_if_v4(interface)?;
+        broadcast_byebye(socket, config).await?;
+    }
+    Ok(())
+}
+
+// with loopback enabled we receive our own NOTIFYs, only useful when testing on one machine
+fn set_multicast_loop(socket: &UdpSocket, config: &Config) -> std::io::Result<()> {
+    socket.set_multicast_loop_v4(config.multicast_loop)?;
+    info!("Multicast loopback: {}", config.multicast_loop);
+    Ok(())
+}
+
+// busy networks can overflow the default receive buffer, datagrams are dropped silently then
+fn set_buffer_sizes(socket: &UdpSocket, config: &Config) -> std::io::Result<()> {
+    let socket = SockRef::from(socket);
+    if let Some(size) = config.udp_recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = config.udp_send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    // the OS may round (Linux doubles the value for bookkeeping) or cap the requested sizes
+    info!(
+        "UDP buffer sizes: receive {} bytes, send {} bytes",
+        socket.recv_buffer_size()?,
+        socket.send_buffer_size()?
+    );
+    Ok(())
+}
 
-                    println!("Waiting to become writeable");
-                    socket.writable().await.expect("Failed to become writeable");
-                    println!("Became writeable");
+// how long a kept alive connection may stay idle before we close it
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(15);
 
-                    if let Err(e) = socket.write_all(resp).await {
-                        println!("failed to write response: {}", e);
+async fn handle_connection(mut socket: TcpStream, socket_addr: SocketAddr, ctx: AppContext) {
+    let mut chunk = [0; 8 * 1024];
+    // received bytes that aren't handled yet, grows until a whole request (up to --max-body) is
+    // in it and may already hold the start of the next one
+    let mut pending: Vec<u8> = Vec::new();
+    // whether the current request already got its 100 Continue
+    let mut continued = false;
+    loop {
+        let length = match dial::request_length(&pending, ctx.config.max_uri, ctx.config.max_body) {
+            Ok(Some(length)) => length,
+            Ok(None) => {
+                // the head passed the checks of request_length (e.g. a Content-Length beyond
+                // --max-body is answered with 413 right away), the client can send the body
+                if !continued && dial::expects_continue(&pending) {
+                    continued = true;
+                    if let Err(e) = socket.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await {
+                        info!("failed to write 100 Continue: {}", e);
+                        return;
+                    }
+                }
+                let read = tokio::select! {
+                    read = clock::timeout(&*ctx.clock, KEEP_ALIVE_TIMEOUT, socket.read(&mut chunk)) => read,
+                    // idle keep-alive connections shouldn't hold up the shutdown
+                    _ = ctx.shutdown.wait() => return,
+                };
+                match read {
+                    None => {
+                        debug!("Closing idle connection from {}", socket_addr);
+                        return;
+                    }
+                    // socket closed
+                    Some(Ok(0)) => return,
+                    Some(Ok(n)) => pending.extend_from_slice(&chunk[..n]),
+                    Some(Err(e)) => {
+                        info!("failed to read bytes: {}", e);
                         return;
                     }
+                }
+                continue;
+            }
+            Err(status) => {
+                info!("Rejecting request from {}: {}", socket_addr, status);
+                let mut response = dial::response(status);
+                response
+                    .headers_mut()
+                    .insert("connection", HeaderValue::from_static("close"));
+                // best effort, the connection is closed anyway
+                let _ = socket.write_all(&dial::response_to_bytes(&response)).await;
+                let _ = socket.shutdown().await;
+                return;
+            }
+        };
+
+        info!("Received {} bytes", length);
+
+        let request_bytes: Vec<u8> = pending.drain(..length).collect();
+        continued = false;
+
+        info!("{}", socket_addr);
+        let text = match std::str::from_utf8(&request_bytes) {
+            Ok(text) => {
+                info!("{}", text);
+                text
+            }
+            Err(e) => {
+                info!("Received invalid utf-8 text: {}", e);
+                return;
+            }
+        };
 
-                    socket.flush().await.expect("Failed to flush");
-                    println!("Send response");
-                    socket.shutdown().await.expect("Failed to shutdown");
-                    println!("Shutdown connection");
+        let (response, keep_alive) = match dial::parse_request(text) {
+            Ok(mut request) => {
+                // for handlers that care about where the request came from
+                request.extensions_mut().insert(socket_addr);
+                info!("Headers: {:#?}", request.headers());
+                let in_flight = ctx.in_flight.start(format!(
+                    "{} {} from {}",
+                    request.method(),
+                    request.uri(),
+                    socket_addr
+                ));
+                let mut response = dial::handle_request(&ctx, &request).await;
+                drop(in_flight);
+                let keep_alive = dial::keep_alive(&request);
+                // answer with the version of the request and say so if we deviate from its
+                // default connection handling
+                *response.version_mut() = request.version();
+                let connection = match (request.version(), keep_alive) {
+                    (http::Version::HTTP_10, true) => Some("keep-alive"),
+                    (http::Version::HTTP_11, false) => Some("close"),
+                    _ => None,
+                };
+                if let Some(connection) = connection {
+                    response
+                        .headers_mut()
+                        .insert("connection", HeaderValue::from_static(connection));
                 }
-            });
+                (response, keep_alive)
+            }
+            Err(e) => {
+                info!("{}", e);
+                let mut response = dial::response(StatusCode::BAD_REQUEST);
+                response
+                    .headers_mut()
+                    .insert("connection", HeaderValue::from_static("close"));
+                (response, false)
+            }
+        };
+        let resp = dial::response_to_bytes(&response);
+
+        // one write_all per response, write_all waits for the socket to become
+        // writable by itself
+        if let Err(e) = socket.write_all(&resp).await {
+            info!("failed to write response: {}", e);
+            return;
+        }
+        if let Err(e) = socket.flush().await {
+            info!("failed to flush response: {}", e);
+            return;
         }
+        info!("Send response");
+        if keep_alive {
+            continue;
+        }
+
+        if let Err(e) = socket.shutdown().await {
+            info!("failed to shutdown connection: {}", e);
+        }
+        info!("Shutdown connection");
+        return;
+    }
+}
+
+/// Accepts connections until the shutdown is requested, then waits for the open connections to be
+/// handled (the caller bounds this with the grace period).
+async fn serve_http(tcplistener: TcpListener, ctx: AppContext) {
+    // every handler holds a sender, recv() returns None once all of them are done
+    let (active_sender, mut active_receiver) = mpsc::channel::<()>(1);
+    loop {
+        let (socket, socket_addr) = tokio::select! {
+            accepted = tcplistener.accept() => accepted.expect("Failed to listen for tcp connection "),
+            _ = ctx.shutdown.wait() => break,
+        };
+        // every response is sent with a single write (see dial::response_to_bytes), so Nagle's
+        // algorithm would only delay it while waiting for an ACK
+        if let Err(e) = socket.set_nodelay(true) {
+            info!("failed to set TCP_NODELAY: {}", e);
+        }
+
+        let ctx = ctx.clone();
+        let active = active_sender.clone();
+        tokio::spawn(async move {
+            handle_connection(socket, socket_addr, ctx).await;
+            drop(active);
+        });
+    }
+
+    // stop accepting new connections
+    drop(tcplistener);
+    drop(active_sender);
+    let _ = active_receiver.recv().await;
+}
+
+/// Exits the process once --shutdown-timeout has passed, even if the graceful shutdown is stuck
+/// (e.g. on a stop that never returns).
+fn start_shutdown_watchdog(ctx: &AppContext, exit_code: i32) {
+    let timeout = Duration::from_secs(ctx.config.shutdown_timeout);
+    spawn_watchdog(ctx, timeout, move || {
+        std::process::exit(if exit_code == 0 { 1 } else { exit_code })
     });
+}
 
-    let root_device_url = format!("http://{}/", local_ip);
-    broadcast_creation(&socket, &root_device_url).await?;
+/// Logs what the shutdown is still waiting for and calls `force_exit` after `timeout`. Runs on
+/// its own thread so a blocked runtime can't keep it from firing.
+fn spawn_watchdog(
+    ctx: &AppContext,
+    timeout: Duration,
+    force_exit: impl FnOnce() + Send + 'static,
+) -> std::thread::JoinHandle<()> {
+    let ctx = ctx.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(timeout);
+        info!(
+            "Shutdown didn't finish within {:?}, forcing the exit",
+            timeout
+        );
+        for work in unfinished_work(&ctx) {
+            info!("{}", work);
+        }
+        force_exit();
+    })
+}
+
+// the requests being handled and the apps still running
+fn unfinished_work(ctx: &AppContext) -> Vec<String> {
+    let mut unfinished: Vec<String> = ctx
+        .in_flight
+        .requests()
+        .into_iter()
+        .map(|request| format!("Still handling {}", request))
+        .collect();
+    // a stuck launch or stop holds the write lock
+    match ctx.apps.try_read() {
+        Ok(apps) => {
+            for app in apps.apps() {
+                if let Some(instance) = app.instance() {
+                    unfinished.push(format!(
+                        "Still running {} (run {})",
+                        app.name, instance.run_id
+                    ));
+                }
+            }
+        }
+        Err(_) => unfinished.push("App registry is still locked".to_string()),
+    }
+    unfinished
+}
 
-    // support up to 4KB, go for 8 just to be sure
-    let mut buf = [0; 8 * 1024];
+/// --ssdp-tcp: answers connections to TCP port 1900 like the emulated device, one request per
+/// connection. Nothing but port scanners and curious users connect there.
+async fn serve_ssdp_tcp(listener: TcpListener, mode: SsdpTcp, ctx: AppContext) {
     loop {
-        let (amt, src_addr) = socket.recv_from(&mut buf).expect("didn't receive data");
+        let (mut socket, socket_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    info!("Failed to accept a connection on TCP port 1900: {}", e);
+                    continue;
+                }
+            },
+            _ = ctx.shutdown.wait() => return,
+        };
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            let mut pending = Vec::new();
+            let mut chunk = [0; 8 * 1024];
+            let request = loop {
+                match dial::request_length(&pending, ctx.config.max_uri, ctx.config.max_body) {
+                    Ok(Some(length)) => {
+                        break std::str::from_utf8(&pending[..length])
+                            .ok()
+                            .and_then(|text| dial::parse_request(text).ok())
+                    }
+                    Ok(None) => {}
+                    Err(_) => break None,
+                }
+                let read =
+                    clock::timeout(&*ctx.clock, KEEP_ALIVE_TIMEOUT, socket.read(&mut chunk)).await;
+                match read {
+                    Some(Ok(n)) if n > 0 => pending.extend_from_slice(&chunk[..n]),
+                    _ => return,
+                }
+            };
+            debug!(
+                "TCP request on the SSDP port from {}: {:?}",
+                socket_addr,
+                request.as_ref().map(|request| request.uri())
+            );
+            let mut response = match (mode, request) {
+                (SsdpTcp::Descriptor, Some(mut request))
+                    if matches!(*request.method(), http::Method::GET | http::Method::HEAD) =>
+                {
+                    *request.uri_mut() = http::Uri::from_static(dial::DESCRIPTOR_PATH);
+                    request.extensions_mut().insert(socket_addr);
+                    dial::handle_request(&ctx, &request).await
+                }
+                _ => dial::response(StatusCode::BAD_REQUEST),
+            };
+            response
+                .headers_mut()
+                .insert("connection", HeaderValue::from_static("close"));
+            let _ = socket.write_all(&dial::response_to_bytes(&response)).await;
+            let _ = socket.shutdown().await;
+        });
+    }
+}
 
-        println!("{:?}", src_addr);
-        let mut data = &mut buf[..amt];
-        let text = match std::str::from_utf8(&mut data) {
-            Ok(msg) => Some(msg),
-            Err(e) => {
-                println!("Invalid utf-8 bytes {:?}", e);
-                None
+const MIN_RECV_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECV_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq)]
+enum RecvErrorAction {
+    // nothing wrong with the socket (e.g. an ICMP error for a response we sent earlier)
+    Retry,
+    // the network went away, the multicast membership is likely lost with it
+    Rejoin,
+    Fatal,
+}
+
+fn classify_recv_error(e: &std::io::Error) -> RecvErrorAction {
+    use std::io::ErrorKind;
+    match e.kind() {
+        ErrorKind::Interrupted
+        | ErrorKind::WouldBlock
+        | ErrorKind::TimedOut
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionReset => RecvErrorAction::Retry,
+        ErrorKind::NetworkDown
+        | ErrorKind::NetworkUnreachable
+        | ErrorKind::HostUnreachable
+        | ErrorKind::AddrNotAvailable => RecvErrorAction::Rejoin,
+        _ => RecvErrorAction::Fatal,
+    }
+}
+
+// without the membership we only see unicast searches, that's still better than not running
+fn join_multicast(socket: &UdpSocket, interface: Ipv4Addr) -> bool {
+    match socket.join_multicast_v4(&ssdp::MULTICAST_IP, &interface) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(
+                "joining {} on {} failed: {}, only unicast searches will be answered",
+                ssdp::MULTICAST_IP,
+                interface,
+                e
+            );
+            false
+        }
+    }
+}
+
+// closing the socket would drop the membership as well, leaving explicitly also works for a
+// socket that is kept (rejoin_multicast) and shows up in the log if the kernel disagrees
+fn leave_multicast(socket: &UdpSocket, interface: Ipv4Addr) {
+    match socket.leave_multicast_v4(&ssdp::MULTICAST_IP, &interface) {
+        Ok(()) => debug!("Left {} on {}", ssdp::MULTICAST_IP, interface),
+        // e.g. EADDRNOTAVAIL if joining failed or the interface is gone
+        Err(e) => debug!(
+            "Leaving {} on {} failed: {}",
+            ssdp::MULTICAST_IP,
+            interface,
+            e
+        ),
+    }
+}
+
+// the socket stays bound while the interface is down (and is shared with the announcements),
+// only the group membership has to be renewed. The old membership is left first, joining twice
+// fails with EADDRINUSE.
+fn rejoin_multicast(socket: &UdpSocket, interface: Ipv4Addr) -> std::io::Result<()> {
+    leave_multicast(socket, interface);
+    socket.join_multicast_v4(&ssdp::MULTICAST_IP, &interface)
+}
+
+// a byebye with our UUID is one of ours looped back, or the one of the instance we replaced on a
+// quick restart, it never means we are gone. Receivers that saw it dropped us though.
+fn own_byebye(ctx: &AppContext, src_addr: SocketAddr) {
+    match ctx.config.own_byebye {
+        OwnByebye::Ignore => debug!("Ignoring byebye with our UUID from {}", src_addr),
+        // we send byebyes ourselves when pausing and shutting down, that's no reason to come back
+        OwnByebye::Reannounce if ctx.is_ssdp_paused() || ctx.shutdown.requested() => {
+            debug!("Ignoring our own byebye from {}", src_addr)
+        }
+        OwnByebye::Reannounce => {
+            info!("Byebye with our UUID from {}, announcing again", src_addr);
+            ctx.reannounce.notify_one();
+        }
+    }
+}
+
+/// Answers the searches received on `socket`: the responses are queued for respond(), which sends
+/// them from `reply_socket` after the MX delay (a socket bound to the group address can't be used
+/// as the source of unicast responses). Blocking, runs on its own thread, but never blocks on
+/// anything but the socket: responses that don't fit into the queue are dropped. Only returns on
+/// an error the socket can't recover from.
+fn serve_searches(
+    socket: &UdpSocket,
+    reply_socket: &Arc<UdpSocket>,
+    descriptor_url: &str,
+    ctx: &AppContext,
+    search_observer: ssdp::SearchObserver,
+    responses: &mpsc::Sender<PendingResponse>,
+) -> std::io::Error {
+    let config = &ctx.config;
+    // one byte more than allowed so a datagram that is too large can be told apart from one that
+    // exactly fills the buffer (recv_from silently drops the rest of the datagram)
+    let mut buf = [0; ssdp::MAX_MESSAGE_SIZE + 1];
+    let mut backoff = MIN_RECV_BACKOFF;
+    // source addresses of our responses that were already warned about
+    let mut unexpected_sources = HashSet::new();
+    loop {
+        let (amt, src_addr) = match socket.recv_from(&mut buf) {
+            Ok((amt, src_addr)) => {
+                backoff = MIN_RECV_BACKOFF;
+                let destination = socket
+                    .local_addr()
+                    .unwrap_or_else(|_| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+                capture::record(
+                    capture::Direction::Received,
+                    src_addr,
+                    destination,
+                    &buf[..amt.min(ssdp::MAX_MESSAGE_SIZE)],
+                );
+                (amt, src_addr)
+            }
+            Err(e) => match classify_recv_error(&e) {
+                RecvErrorAction::Retry => {
+                    debug!("Receiving on the SSDP socket failed: {}, retrying", e);
+                    continue;
+                }
+                // never joined, there's nothing to rejoin
+                RecvErrorAction::Rejoin if config.unicast_only => {
+                    warn!(
+                        "receiving on the SSDP socket failed: {}, retrying in {:?}",
+                        e, backoff
+                    );
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_RECV_BACKOFF);
+                    continue;
+                }
+                RecvErrorAction::Rejoin => {
+                    warn!(
+                        "receiving on the SSDP socket failed: {}, rejoining {} in {:?}",
+                        e,
+                        ssdp::MULTICAST_IP,
+                        backoff
+                    );
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_RECV_BACKOFF);
+                    let rejoined = config
+                        .multicast_interfaces()
+                        .into_iter()
+                        .try_for_each(|interface| rejoin_multicast(socket, interface));
+                    if let Err(e) = &rejoined {
+                        info!("Failed to rejoin {}: {}", ssdp::MULTICAST_IP, e);
+                    }
+                    ctx.multicast.set_joined(rejoined.is_ok());
+                    // the network is back, receivers that saw us vanish shouldn't have to wait for
+                    // the next re-announcement
+                    if rejoined.is_ok() {
+                        ctx.reannounce.notify_one();
+                    }
+                    continue;
+                }
+                RecvErrorAction::Fatal => return e,
+            },
+        };
+
+        if amt > ssdp::MAX_MESSAGE_SIZE {
+            warn!(
+                "ignoring datagram from {}, it is larger than {} bytes and was truncated",
+                src_addr,
+                ssdp::MAX_MESSAGE_SIZE
+            );
+            ssdp::record_drop(ssdp::DropReason::Truncated, src_addr);
+            continue;
+        }
+        let msg = match std::str::from_utf8(&buf[..amt]) {
+            Ok(msg) => msg,
+            Err(_) => {
+                ssdp::record_drop(ssdp::DropReason::InvalidUtf8, src_addr);
+                continue;
             }
         };
 
-        if let Some(msg) = text {
-            let mut header_found = false;
-            for line in msg.lines() {
-                if line == "ST: urn:dial-multiscreen-org:service:dial:1" {
-                    header_found = true
+        let msg = match SsdpMessage::parse(msg) {
+            Some(msg) => msg,
+            None => {
+                ssdp::record_drop(ssdp::DropReason::NotSearch, src_addr);
+                continue;
+            }
+        };
+        match msg.start_line {
+            SsdpStartLine::MSearch => {}
+            SsdpStartLine::Notify => {
+                // the source address of looped back NOTIFYs is the one of the outgoing interface,
+                // which isn't necessarily the announce address, all our USNs contain the device
+                // UUID though
+                let is_own = msg
+                    .header("USN")
+                    .map(|usn| usn.contains(ROOT_DEVICE_UUID))
+                    .unwrap_or(false);
+                let is_byebye = msg
+                    .header("NTS")
+                    .is_some_and(|nts| nts.eq_ignore_ascii_case("ssdp:byebye"));
+                if is_own && is_byebye {
+                    own_byebye(ctx, src_addr);
                 }
+                let reason = if is_own {
+                    ssdp::DropReason::SelfEcho
+                } else {
+                    ssdp::DropReason::NotSearch
+                };
+                ssdp::record_drop(reason, src_addr);
+                continue;
+            }
+            SsdpStartLine::Response { .. } => {
+                ssdp::record_drop(ssdp::DropReason::NotSearch, src_addr);
+                continue;
             }
+        }
+        let search = msg.is_discover_search().then(|| ssdp::SearchEvent {
+            src_addr,
+            search_target: msg.header("ST").unwrap_or_default().to_string(),
+            user_agent: msg.header("USER-AGENT").map(str::to_string),
+            timestamp: SystemTime::now(),
+        });
+        // kept while paused too, GET /admin/searches should show whether searches arrive at all
+        if let Some(search) = &search {
+            ctx.recent_searches.push(search.clone());
+        }
+        if ctx.is_ssdp_paused() {
+            ssdp::record_drop(ssdp::DropReason::Paused, src_addr);
+            continue;
+        }
 
-            if header_found {
-                println!("{}", msg);
-                println!("DIAL ueader found :)");
+        if let Some(search) = search {
+            search_observer.notify(search);
+        }
 
-                let response = format!(
-                    "HTTP/1.1 200 OK
-LOCATION: http://{}:8081/upnp_device_descriptor.xml
-ST: urn:dial-multiscreen-org:service:dial:1
-USN: testing-laptop
-",
-                    &local_ip
-                );
-                println!("Sendign LOCATION Resonse: {}", &response);
-                socket
-                    .send_to(&response.as_bytes(), src_addr)
-                    .expect(&format!("failed to respond to {}", &src_addr));
-            } else {
-                println!("Timestamp: {:?}", std::time::SystemTime::now().elapsed());
-                // println!("{}", msg);
-                println!("No DIAL header found :(\n\n");
+        // --respond: emulated search targets only need a valid search, the DIAL specific checks
+        // below don't apply to them
+        let emulated = config
+            .search_targets
+            .iter()
+            .find(|target| msg.header("ST") == Some(target.st.as_str()));
+        if let Some(target) = emulated {
+            if !msg.is_discover_search() {
+                ssdp::record_drop(ssdp::DropReason::MissingMan, src_addr);
+                continue;
+            }
+            info!("Answering search for {} from {}", target.st, src_addr);
+            let response = search_response(
+                config,
+                &target.location,
+                &target.st,
+                &target.usn,
+                config.max_age.shortest(),
+                // the descriptor at that location isn't ours
+                None,
+            );
+            queue_response(ctx, responses, reply_socket, &msg, src_addr, response);
+            continue;
+        }
+
+        if msg.header("ST") != Some(ssdp::DIAL_SEARCH_TARGET) {
+            ssdp::record_drop(ssdp::DropReason::OtherSearchTarget, src_addr);
+            continue;
+        }
+        if !msg.is_discover_search() {
+            ssdp::record_drop(ssdp::DropReason::MissingMan, src_addr);
+            continue;
+        }
+
+        // local senders in --loopback mode search at 127.0.0.1 and get a LOCATION there
+        let local_search = config.loopback && src_addr.ip().is_loopback();
+        let unicast_ip = if local_search {
+            Ipv4Addr::LOCALHOST
+        } else {
+            config.announce_ip
+        };
+        if !msg.has_valid_host(unicast_ip) {
+            warn!(
+                "search from {} has HOST {:?} instead of {} (broken multicast setup?){}",
+                src_addr,
+                msg.header("HOST").unwrap_or("<missing>"),
+                SSDP_MULTICAST_ADDR,
+                if config.lenient_host {
+                    ""
+                } else {
+                    ", ignoring it"
+                }
+            );
+            if !config.lenient_host {
+                ssdp::record_drop(ssdp::DropReason::InvalidHost, src_addr);
+                continue;
+            }
+        }
+
+        // without apps there is nothing a sender could launch, we are only a plain UPnP device then
+        let has_apps = ctx.apps.blocking_read().names().next().is_some();
+        if !has_apps {
+            ssdp::record_drop(ssdp::DropReason::NoApps, src_addr);
+            continue;
+        }
+
+        info!("{}", msg);
+        info!(
+            "DIAL ueader found :) (from {}, {})",
+            src_addr,
+            msg.header("USER-AGENT").unwrap_or("unknown user agent")
+        );
+
+        // the response is sent from port 1900 of the socket it arrived on (or the unicast socket
+        // for the group socket), a wildcard socket leaves the source address to the routing table
+        let source = response_source(reply_socket, src_addr);
+        // with --announce-interface the sender gets the address of the interface it is reached on
+        let interface = config
+            .announce_interfaces
+            .iter()
+            .find(|&&interface| source.ip() == IpAddr::V4(interface));
+        let location = if local_search {
+            format!(
+                "http://{}:{}{}",
+                Ipv4Addr::LOCALHOST,
+                config.http_port,
+                dial::DESCRIPTOR_PATH
+            )
+        } else if let Some(&interface) = interface {
+            format!(
+                "{}{}",
+                config.interface_base_url(interface),
+                dial::DESCRIPTOR_PATH
+            )
+        } else {
+            descriptor_url.to_string()
+        };
+        // DIAL 2.2 section 5.1.2: the USN is the device UUID (the UDN of the descriptor) with the
+        // search target, senders ignore responses with anything else
+        let usn = format!("uuid:{}::{}", ROOT_DEVICE_UUID, ssdp::DIAL_SEARCH_TARGET);
+        let response = search_response(
+            config,
+            &location,
+            ssdp::DIAL_SEARCH_TARGET,
+            &usn,
+            config.max_age.service,
+            Some(ctx.config_id()),
+        );
+        info!("Sendign LOCATION Resonse: {}", &response);
+        // searches from this host are answered over loopback
+        if !src_addr.ip().is_loopback()
+            && interface.is_none()
+            && !config.announce_ip.is_unspecified()
+            && source.ip() != IpAddr::V4(config.announce_ip)
+            && unexpected_sources.insert(source.ip())
+        {
+            warn!(
+                "responses to {} are sent from {} but LOCATION advertises {}, senders that \
+                 compare them ignore the responses (bind SSDP to it with --ssdp-bind-ip)",
+                src_addr, source, config.announce_ip
+            );
+        }
+        queue_response(ctx, responses, reply_socket, &msg, src_addr, response);
+    }
+}
+
+/// The address a datagram from `socket` to `destination` is sent from. For a socket bound to
+/// 0.0.0.0 that is the address of the interface the route to `destination` goes through, looked
+/// up by connecting a throwaway socket (which doesn't send anything).
+fn response_source(socket: &UdpSocket, destination: SocketAddr) -> SocketAddr {
+    let local = socket
+        .local_addr()
+        .unwrap_or_else(|_| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    if !local.ip().is_unspecified() {
+        return local;
+    }
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|probe| {
+            probe.connect(destination)?;
+            probe.local_addr()
+        })
+        .map(|probe| SocketAddr::new(probe.ip(), local.port()))
+        .unwrap_or(local)
+}
+
+// how many responses can wait for their MX delay, the searches of a burst beyond that are dropped
+const RESPONSE_QUEUE_SIZE: usize = 256;
+
+/// A search response waiting for its MX delay.
+#[derive(Debug)]
+struct PendingResponse {
+    socket: Arc<UdpSocket>,
+    destination: SocketAddr,
+    payload: String,
+    send_at: tokio::time::Instant,
+}
+
+fn queue_response(
+    ctx: &AppContext,
+    responses: &mpsc::Sender<PendingResponse>,
+    socket: &Arc<UdpSocket>,
+    search: &SsdpMessage,
+    destination: SocketAddr,
+    payload: String,
+) {
+    let config = &ctx.config;
+    if search.mx() > config.max_mx {
+        debug!(
+            "MX {} from {} is above {}, clamping it",
+            search.mx(),
+            destination,
+            config.max_mx
+        );
+    }
+    let delay = random_delay(Duration::from_secs(
+        search.max_response_delay(config.max_mx),
+    ));
+    let response = PendingResponse {
+        socket: socket.clone(),
+        destination,
+        payload,
+        send_at: ctx.clock.now() + delay,
+    };
+    if responses.try_send(response).is_err() {
+        ssdp::record_drop(ssdp::DropReason::QueueFull, destination);
+    }
+}
+
+// up to `max`, spread evenly. splitmix64 over a counter seeded from the clock, the searches of a
+// burst arrive within the same microseconds so the clock alone isn't random enough
+fn random_delay(max: Duration) -> Duration {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    let seed = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let _ = STATE.compare_exchange(0, seed, Ordering::Relaxed, Ordering::Relaxed);
+    let mut z = STATE
+        .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    max.mul_f64((z >> 11) as f64 / (1u64 << 53) as f64)
+}
+
+/// Sends the responses queued by serve_searches once their MX delay is over. The ones that are
+/// waiting are kept here, at most RESPONSE_QUEUE_SIZE of them, more stay in the channel.
+async fn respond(mut queue: mpsc::Receiver<PendingResponse>, clock: Arc<dyn Clock>) {
+    let mut pending: Vec<PendingResponse> = Vec::new();
+    loop {
+        let next = pending.iter().map(|response| response.send_at).min();
+        // disabled without pending responses, but the future is still created
+        let next_or_never = next.unwrap_or_else(|| clock.now() + Duration::from_secs(3600));
+        tokio::select! {
+            received = queue.recv(), if pending.len() < RESPONSE_QUEUE_SIZE => match received {
+                Some(response) => pending.push(response),
+                // all SSDP loops are gone, we are shutting down
+                None => return,
+            },
+            _ = clock.sleep_until(next_or_never), if next.is_some() => {
+                let now = clock.now();
+                let (due, waiting) = pending
+                    .drain(..)
+                    .partition(|response| response.send_at <= now);
+                pending = waiting;
+                for response in due {
+                    let PendingResponse { socket, destination, payload, .. } = response;
+                    if let Err(e) = send_datagram(&socket, payload.as_bytes(), destination) {
+                        info!("Failed to respond to {}: {}", destination, e);
+                    }
+                }
             }
         }
     }
 }
+
+/// UDA 1.1 section 1.3.3: the answer to a M-SEARCH, CRLF framed like every SSDP message.
+fn search_response(
+    config: &Config,
+    location: &str,
+    search_target: &str,
+    usn: &str,
+    max_age: u32,
+    config_id: Option<u32>,
+) -> String {
+    let mut response = http::Response::builder()
+        .status(StatusCode::OK)
+        .version(http::Version::HTTP_11)
+        .header("CACHE-CONTROL", cache_control(max_age))
+        .header(
+            "DATE",
+            HeaderValue::from_str(&ssdp::http_date(SystemTime::now())).expect("Invalid date"),
+        )
+        // required for backwards compatibility, always empty
+        .header("EXT", HeaderValue::from_static(""))
+        .header(
+            "LOCATION",
+            HeaderValue::from_str(location).expect("Invalid url"),
+        )
+        .header(
+            "SERVER",
+            server_header(config, &config.server_header.service),
+        )
+        .header(
+            "ST",
+            HeaderValue::from_str(search_target).expect("Invalid search target"),
+        )
+        .header("USN", HeaderValue::from_str(usn).expect("Invalid USN"))
+        .body(())
+        .expect("Invalid search response");
+    if let Some(config_id) = config_id {
+        response
+            .headers_mut()
+            .insert("CONFIGID.UPNP.ORG", HeaderValue::from(config_id));
+    }
+    response_to_string(&response)
+}
+
+// like parse_request_to_string, but the header names are written in upper case: they are
+// case-insensitive, but some senders only look for the spelling of the spec
+fn response_to_string(response: &http::Response<()>) -> String {
+    let status = response.status();
+    let mut result = format!(
+        "HTTP/1.1 {} {}\r\n",
+        status.as_str(),
+        status.canonical_reason().unwrap_or("")
+    );
+    for (key, value) in response.headers() {
+        result.push_str(&format!(
+            "{}: {}\r\n",
+            key.as_str().to_ascii_uppercase(),
+            value.to_str().expect("cant convert values to string")
+        ));
+    }
+    result.push_str("\r\n");
+    result
+}
+
+async fn reannounce(
+    socket: Arc<UdpSocket>,
+    descriptor_url: String,
+    ctx: AppContext,
+    mut announce_requests: mpsc::Receiver<AnnounceRequest>,
+) {
+    // UDA 1.1 section 1.2.2: advertisements have to be resent before they expire, use half of the
+    // shortest max-age so no advertisement runs out in between
+    let max_age = &ctx.config.max_age;
+    let reannounce_interval = Duration::from_secs((u64::from(max_age.shortest()) / 2).max(1));
+    let mut paused = ctx.ssdp_paused.subscribe();
+    // a flapping interface requests an announcement every time it comes back, within the cooldown
+    // after an announcement these requests are coalesced into a single one at its end
+    let cooldown = Duration::from_secs(ctx.config.announce_cooldown);
+    let mut last_announcement = ctx.clock.now();
+    let mut requested = false;
+    loop {
+        tokio::select! {
+            _ = ctx.clock.sleep(reannounce_interval) => {
+                if *paused.borrow() {
+                    continue;
+                }
+            }
+            _ = ctx.reannounce.notified() => {
+                if *paused.borrow() || requested {
+                    continue;
+                }
+                let since = ctx.clock.now() - last_announcement;
+                if since < cooldown {
+                    debug!(
+                        "Announcement requested within the cooldown, sending it in {:?}",
+                        cooldown - since
+                    );
+                    requested = true;
+                    continue;
+                }
+                debug!("Announcing on request");
+            }
+            _ = ctx.clock.sleep_until(last_announcement + cooldown), if requested => {
+                requested = false;
+                if *paused.borrow() {
+                    continue;
+                }
+                debug!("Announcing on request after the cooldown");
+            }
+            Some(reply) = announce_requests.recv() => {
+                // POST /admin/announce: sent right away, the cooldown only applies to the
+                // automatic requests
+                if *paused.borrow() {
+                    let _ = reply.send(Ok(0));
+                    continue;
+                }
+                debug!("Announcing on admin request");
+                let result = announce_on_interfaces(&socket, &descriptor_url, ctx.config_id(), &ctx.config).await;
+                if let Err(e) = &result {
+                    info!("Failed to announce: {}", e);
+                }
+                last_announcement = ctx.clock.now();
+                requested = false;
+                let _ = reply.send(result);
+                continue;
+            }
+            changed = paused.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                // paused: clients should forget us now instead of when the max-age runs out
+                if *paused.borrow_and_update() {
+                    info!("SSDP paused");
+                    let config = &ctx.config;
+                    if let Err(e) =
+                        byebye_on_interfaces(&socket, config).await
+                    {
+                        info!("Failed to send byebye: {}", e);
+                    }
+                    continue;
+                }
+                info!("SSDP resumed");
+            }
+        }
+        if let Err(e) =
+            announce_on_interfaces(&socket, &descriptor_url, ctx.config_id(), &ctx.config).await
+        {
+            info!("Failed to re-announce: {}", e);
+        }
+        last_announcement = ctx.clock.now();
+        // covered by this announcement
+        requested = false;
+    }
+}
+
+// --once: shut down after the first launch (or the timeout)
+async fn exit_after_first_launch(ctx: AppContext) {
+    let timeout = Duration::from_secs(ctx.config.once_timeout);
+    let launched = clock::timeout(&*ctx.clock, timeout, ctx.launched.notified())
+        .await
+        .is_some();
+    if launched {
+        info!("App launched, exiting (--once)");
+        ctx.shutdown.trigger(0);
+    } else {
+        info!("No app launched within {:?}, exiting (--once)", timeout);
+        ctx.shutdown.trigger(1);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let exit_code = match run().await {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            info!("{}", e);
+            e.exit_code()
+        }
+    };
+    // the SSDP thread is stuck in a blocking recv_from, exit instead of waiting for it
+    std::process::exit(exit_code);
+}
+
+// how many ports after --http-port are tried with --port-fallback
+const PORT_FALLBACK_ATTEMPTS: u16 = 10;
+
+/// Binds the HTTP listener, with --port-fallback the following ports are tried if the configured
+/// one is taken and config.http_port is updated to the one we got (it ends up in every LOCATION).
+async fn bind_http(config: &mut Config) -> Result<TcpListener, DialError> {
+    let attempts = if config.port_fallback {
+        PORT_FALLBACK_ATTEMPTS
+    } else {
+        1
+    };
+    let first_port = config.http_port;
+    for port in (first_port..=u16::MAX).take(usize::from(attempts)) {
+        match TcpListener::bind((config.http_bind_ip, port)).await {
+            Ok(listener) => {
+                if port != first_port {
+                    info!("Port {} is in use, using {} instead", first_port, port);
+                    if let Some(advertise_port) = config.advertise_port {
+                        warn!(
+                            "port {} is still advertised, the forward to it has to \
+                             point to {} now",
+                            advertise_port, port
+                        );
+                    }
+                }
+                config.http_port = port;
+                return Ok(listener);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => continue,
+            Err(e) => {
+                return Err(DialError::Socket(
+                    format!("Binding {}:{}", config.http_bind_ip, port),
+                    e,
+                ))
+            }
+        }
+    }
+    Err(DialError::PortInUse(first_port, attempts))
+}
+
+// LOCATION of the advertisements and the search responses (except the --loopback ones)
+fn descriptor_url(ctx: &AppContext) -> String {
+    format!("{}{}", ctx.base_url(), dial::DESCRIPTOR_PATH)
+}
+
+/// Runs the server until the shutdown is requested and returns the exit code.
+async fn run() -> Result<i32, DialError> {
+    let mut config = Config::from_args(std::env::args().skip(1)).map_err(DialError::Config)?;
+
+    logging::set_debug(config.debug);
+    logging::set_json(config.log_format == LogFormat::Json);
+    if let Some(path) = &config.capture {
+        capture::start(path, config.capture_max_size).map_err(|e| {
+            DialError::Config(format!("Can't write capture to {}: {}", path.display(), e))
+        })?;
+        info!("Capturing SSDP datagrams to {}", path.display());
+    }
+    for warning in config.address_warnings() {
+        warn!("{}", warning);
+    }
+    if !dial::admin_credentials_configured(&config) {
+        warn!("no --admin-user and --admin-pass, /admin/* is only open to requests from this host");
+    }
+    if config.check_discoverable {
+        return check::run(&config).await;
+    }
+
+    //239.255.255.250
+    let address = config.ssdp_bind_ip;
+    let port = 1900;
+    info!("Opening UDP socket and listening on {}:{}", &address, &port);
+    let socket = UdpSocket::bind((address, port))
+        .socket_context(|| format!("Binding {}:{}", address, port))?;
+    // networks that block multicast: senders that know our address can still search directly
+    let mut joined = if config.unicast_only {
+        info!(
+            "Unicast-only: not joining {}, only searches sent to {}:{} are answered",
+            ssdp::MULTICAST_IP,
+            config.announce_ip,
+            port
+        );
+        false
+    } else {
+        // with --announce-interface searches arrive on each of them, all are joined even if one
+        // fails
+        let joins: Vec<bool> = config
+            .multicast_interfaces()
+            .into_iter()
+            .map(|interface| join_multicast(&socket, interface))
+            .collect();
+        joins.iter().all(|&joined| joined)
+    };
+    // a socket bound to a unicast address only receives datagrams sent to that address (unicast
+    // searches), multicast searches need a second socket bound to the group address. Bound to
+    // 0.0.0.0 the first socket receives both.
+    let multicast_socket = if address.is_unspecified() || config.unicast_only {
+        None
+    } else {
+        info!(
+            "Opening UDP socket and listening on {}:{}",
+            ssdp::MULTICAST_IP,
+            &port
+        );
+        let multicast_socket = UdpSocket::bind((ssdp::MULTICAST_IP, port))
+            .socket_context(|| format!("Binding {}:{}", ssdp::MULTICAST_IP, port))?;
+        joined &= join_multicast(&multicast_socket, address);
+        set_buffer_sizes(&multicast_socket, &config)
+            .socket_context(|| "Setting the UDP buffer sizes".to_string())?;
+        // send the announcements out of the interface we are bound to
+        SockRef::from(&socket)
+            .set_multicast_if_v4(&address)
+            .socket_context(|| format!("Setting the multicast interface to {}", address))?;
+        Some(Arc::new(multicast_socket))
+    };
+    // a socket bound to another address doesn't see searches sent to 127.0.0.1
+    let loopback_socket = if config.loopback && !address.is_unspecified() && !address.is_loopback()
+    {
+        info!(
+            "Opening UDP socket and listening on {}:{}",
+            Ipv4Addr::LOCALHOST,
+            &port
+        );
+        let loopback_socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, port))
+            .socket_context(|| format!("Binding {}:{}", Ipv4Addr::LOCALHOST, port))?;
+        Some(Arc::new(loopback_socket))
+    } else {
+        None
+    };
+    set_multicast_loop(&socket, &config)
+        .socket_context(|| "Setting multicast loopback".to_string())?;
+    set_buffer_sizes(&socket, &config)
+        .socket_context(|| "Setting the UDP buffer sizes".to_string())?;
+
+    let tcplistener = bind_http(&mut config).await?;
+    info!(
+        "Opening TCP socket and listening on {}:{}",
+        config.http_bind_ip, config.http_port
+    );
+
+    let descriptor = config.descriptor.clone();
+    let mut ctx = AppContext::new(config);
+    ctx.multicast.set_joined(joined);
+    let (announce, announce_requests) = mpsc::channel(4);
+    if !ctx.config.unicast_only {
+        ctx.announce = Some(announce);
+    }
+    if let Some(path) = descriptor {
+        let xml = std::fs::read_to_string(&path)
+            .map_err(|e| DialError::Config(format!("Failed to read {}: {}", path.display(), e)))?;
+        // senders classify us by the descriptor, the NOTIFYs should announce the same type
+        if !xml.contains(&format!(
+            "<deviceType>{}</deviceType>",
+            ctx.config.device_type
+        )) {
+            warn!(
+                "the <deviceType> of {} isn't {}, the type in the NOTIFYs (--device-type)",
+                path.display(),
+                ctx.config.device_type
+            );
+        }
+        ctx = ctx.with_descriptor_renderer(move |_| xml.clone())?;
+    }
+    {
+        let source = store::apps_key(&ctx.config);
+        let definitions = registration::load(&ctx).map_err(DialError::Config)?;
+        let mut apps = ctx.apps.write().await;
+        for definition in definitions {
+            if apps.contains(&definition.name) {
+                warn!(
+                    "{} from {} is already registered with --app, ignoring it",
+                    definition.name, source
+                );
+                continue;
+            }
+            if apps.names().count() >= ctx.config.max_apps {
+                warn!(
+                    "more than {} apps (--max-apps), ignoring {} from {}",
+                    ctx.config.max_apps, definition.name, source
+                );
+                continue;
+            }
+            info!("Registering app {} from {}", definition.name, source);
+            definition.register(&mut apps);
+        }
+        dial::restore_dial_data(&ctx, &mut apps);
+    }
+    let descriptor_url = descriptor_url(&ctx);
+
+    let http_server = tokio::spawn(serve_http(tcplistener, ctx.clone()));
+    if let Some(mode) = ctx.config.ssdp_tcp {
+        let address = ctx.config.ssdp_bind_ip;
+        let listener = TcpListener::bind((address, 1900))
+            .await
+            .socket_context(|| format!("Binding TCP {}:1900", address))?;
+        info!("Opening TCP socket and listening on {}:1900", address);
+        tokio::spawn(serve_ssdp_tcp(listener, mode, ctx.clone()));
+    }
+
+    let socket = Arc::new(socket);
+    // NOTIFYs go to the multicast group, with --unicast-only nobody would receive them
+    if !ctx.config.unicast_only {
+        wait_before_announcing(&ctx.config).await;
+        announce_on_interfaces(&socket, &descriptor_url, ctx.config_id(), &ctx.config)
+            .await
+            .socket_context(|| "Announcing".to_string())?;
+        tokio::spawn(reannounce(
+            socket.clone(),
+            descriptor_url.clone(),
+            ctx.clone(),
+            announce_requests,
+        ));
+    }
+
+    if ctx.config.once {
+        tokio::spawn(exit_after_first_launch(ctx.clone()));
+    }
+
+    ctx.set_started();
+    #[cfg(feature = "systemd")]
+    {
+        systemd::notify_ready();
+        systemd::spawn_watchdog();
+    }
+
+    let (search_observer, mut search_events) = ssdp::SearchObserver::channel(64);
+    tokio::spawn(async move {
+        while let Some(event) = search_events.recv().await {
+            debug!(
+                "Search from {} ({}) for {} at {:?}",
+                event.src_addr,
+                event.user_agent.as_deref().unwrap_or("unknown user agent"),
+                event.search_target,
+                event.timestamp
+            );
+        }
+    });
+
+    let (responses, queue) = mpsc::channel(RESPONSE_QUEUE_SIZE);
+    tokio::spawn(respond(queue, ctx.clock.clone()));
+
+    if let Some(loopback_socket) = loopback_socket {
+        let descriptor_url = descriptor_url.clone();
+        let ctx = ctx.clone();
+        let search_observer = search_observer.clone();
+        let responses = responses.clone();
+        tokio::task::spawn_blocking(move || {
+            let e = serve_searches(
+                &loopback_socket,
+                &loopback_socket,
+                &descriptor_url,
+                &ctx,
+                search_observer,
+                &responses,
+            );
+            let e = DialError::Socket("Receiving on the SSDP socket".to_string(), e);
+            info!("{}, shutting down", e);
+            ctx.shutdown.trigger(e.exit_code());
+        });
+    }
+    if let Some(multicast_socket) = multicast_socket.clone() {
+        let socket = socket.clone();
+        let descriptor_url = descriptor_url.clone();
+        let ctx = ctx.clone();
+        let search_observer = search_observer.clone();
+        let responses = responses.clone();
+        tokio::task::spawn_blocking(move || {
+            let e = serve_searches(
+                &multicast_socket,
+                &socket,
+                &descriptor_url,
+                &ctx,
+                search_observer,
+                &responses,
+            );
+            let e = DialError::Socket("Receiving on the SSDP socket".to_string(), e);
+            info!("{}, shutting down", e);
+            ctx.shutdown.trigger(e.exit_code());
+        });
+    }
+    {
+        let socket = socket.clone();
+        let ctx = ctx.clone();
+        tokio::task::spawn_blocking(move || {
+            let e = serve_searches(
+                &socket,
+                &socket,
+                &descriptor_url,
+                &ctx,
+                search_observer,
+                &responses,
+            );
+            let e = DialError::Socket("Receiving on the SSDP socket".to_string(), e);
+            info!("{}, shutting down", e);
+            ctx.shutdown.trigger(e.exit_code());
+        });
+    }
+
+    if ctx.config.selftest {
+        tokio::spawn(selftest::run(ctx.clone()));
+    }
+
+    {
+        let shutdown = ctx.shutdown.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                info!("Received Ctrl-C, shutting down");
+                shutdown.trigger(0);
+            }
+        });
+    }
+
+    let exit_code = ctx.shutdown.wait().await;
+    start_shutdown_watchdog(&ctx, exit_code);
+    // let in-flight requests (e.g. slow launches) finish
+    let grace_period = Duration::from_secs(ctx.config.shutdown_grace);
+    info!(
+        "Waiting up to {:?} for open connections to finish",
+        grace_period
+    );
+    if clock::timeout(&*ctx.clock, grace_period, http_server)
+        .await
+        .is_none()
+    {
+        info!("Grace period elapsed, dropping open connections");
+    }
+
+    if !ctx.config.unicast_only {
+        if let Err(e) = byebye_on_interfaces(&socket, &ctx.config)
+            .await
+            .socket_context(|| "Sending byebye".to_string())
+        {
+            info!("{}", e);
+        }
+        // after the byebye, it is sent to the group
+        for interface in ctx.config.multicast_interfaces() {
+            leave_multicast(&socket, interface);
+        }
+        if let Some(multicast_socket) = &multicast_socket {
+            leave_multicast(multicast_socket, ctx.config.ssdp_bind_ip);
+        }
+    }
+    for reason in ssdp::DropReason::ALL {
+        debug!("Dropped datagrams ({}): {}", reason, ssdp::dropped(reason));
+    }
+    Ok(exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::path::{Path, PathBuf};
+
+    fn context(args: &[&str]) -> AppContext {
+        AppContext::new(Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap())
+    }
+
+    // every alive NOTIFY of one announcement round, in the order they are sent
+    fn alive_messages(ctx: &AppContext) -> Vec<String> {
+        let config = &ctx.config;
+        let url = descriptor_url(ctx);
+        let mut messages = root_device_notifies(&url, config.max_age.root_device, 1, config);
+        messages.extend(device_notifies(&url, config.max_age.device, 1, config));
+        if config.profile.advertise_services {
+            messages.extend(service_notifies(&url, config.max_age.service, 1, config));
+        }
+        messages
+    }
+
+    fn search_message(host: &str, man: Option<&str>, st: &str) -> String {
+        let mut message = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMX: 0\r\nST: {}\r\n",
+            host, st
+        );
+        if let Some(man) = man {
+            message.push_str(&format!("MAN: {}\r\n", man));
+        }
+        message.push_str("\r\n");
+        message
+    }
+
+    fn dial_search() -> String {
+        search_message(
+            "239.255.255.250:1900",
+            Some("\"ssdp:discover\""),
+            ssdp::DIAL_SEARCH_TARGET,
+        )
+    }
+
+    fn start_search_server(ctx: &AppContext) -> SocketAddr {
+        start_observed_search_server(ctx, ssdp::SearchObserver::default())
+    }
+
+    // serve_searches on a loopback socket, answered through respond() like in run()
+    fn start_observed_search_server(
+        ctx: &AppContext,
+        observer: ssdp::SearchObserver,
+    ) -> SocketAddr {
+        serve_searches_on(UdpSocket::bind("127.0.0.1:0").unwrap(), ctx, observer)
+    }
+
+    fn serve_searches_on(
+        socket: UdpSocket,
+        ctx: &AppContext,
+        observer: ssdp::SearchObserver,
+    ) -> SocketAddr {
+        let address = socket.local_addr().unwrap();
+        let reply_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let (responses, queue) = mpsc::channel(RESPONSE_QUEUE_SIZE);
+        tokio::spawn(respond(queue, ctx.clock.clone()));
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            let url = descriptor_url(&ctx);
+            serve_searches(&socket, &reply_socket, &url, &ctx, observer, &responses)
+        });
+        address
+    }
+
+    // the response to `message`, None if there is none within a second
+    async fn search(server: SocketAddr, message: &str) -> Option<String> {
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(message.as_bytes(), server).await.unwrap();
+        let mut buf = [0; ssdp::MAX_MESSAGE_SIZE];
+        let received = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf));
+        let (amt, _) = received.await.ok()?.unwrap();
+        Some(String::from_utf8(buf[..amt].to_vec()).unwrap())
+    }
+
+    async fn start_http_server(ctx: &AppContext) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_http(listener, ctx.clone()));
+        address
+    }
+
+    // writes `request` and reads until the server closes the connection
+    async fn exchange_bytes(server: SocketAddr, request: &[u8]) -> Vec<u8> {
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response));
+        read.await.expect("connection wasn't closed").unwrap();
+        response
+    }
+
+    async fn exchange(server: SocketAddr, request: &[u8]) -> String {
+        String::from_utf8_lossy(&exchange_bytes(server, request).await).into_owned()
+    }
+
+    fn header<'a>(message: &'a str, name: &str) -> &'a str {
+        ssdp::header(message, name).unwrap_or_else(|| panic!("no {} in {}", name, message))
+    }
+
+    #[tokio::test]
+    async fn all_locations_share_the_advertised_base() {
+        let ctx = context(&[
+            "--announce-ip",
+            "10.1.2.3",
+            "--http-port",
+            "8123",
+            "--app",
+            "YouTube",
+        ]);
+        let base = "http://10.1.2.3:8123";
+        assert_eq!(ctx.base_url(), base);
+
+        for message in alive_messages(&ctx) {
+            assert!(
+                header(&message, "LOCATION").starts_with(base),
+                "{}",
+                message
+            );
+        }
+        let response = search(start_search_server(&ctx), &dial_search())
+            .await
+            .unwrap();
+        assert_eq!(
+            header(&response, "LOCATION"),
+            format!("{}{}", base, dial::DESCRIPTOR_PATH)
+        );
+
+        let request = |method, path: &str| {
+            http::Request::builder()
+                .method(method)
+                .uri(path)
+                .body(String::new())
+                .unwrap()
+        };
+        let descriptor = dial::handle_request(&ctx, &request("GET", dial::DESCRIPTOR_PATH)).await;
+        assert_eq!(
+            descriptor.headers()["application-url"],
+            format!("{}/apps/", base)
+        );
+        let launched = dial::handle_request(&ctx, &request("POST", "/apps/YouTube")).await;
+        assert!(launched.headers()["location"]
+            .to_str()
+            .unwrap()
+            .starts_with(&format!("{}/apps/YouTube/run/", base)));
+    }
+
+    #[tokio::test]
+    async fn searches_need_the_quoted_man_header() {
+        let server = start_search_server(&context(&["--app", "YouTube"]));
+        let host = "239.255.255.250:1900";
+        let st = ssdp::DIAL_SEARCH_TARGET;
+        let before = ssdp::dropped(ssdp::DropReason::MissingMan);
+        assert_eq!(search(server, &search_message(host, None, st)).await, None);
+        assert_eq!(
+            search(server, &search_message(host, Some("ssdp:discover"), st)).await,
+            None
+        );
+        assert!(ssdp::dropped(ssdp::DropReason::MissingMan) >= before + 2);
+        let valid = search_message(host, Some("\"ssdp:discover\""), st);
+        assert!(search(server, &valid).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn searches_with_a_wrong_host_are_only_answered_when_lenient() {
+        let wrong_host = search_message(
+            "239.255.255.250:1901",
+            Some("\"ssdp:discover\""),
+            ssdp::DIAL_SEARCH_TARGET,
+        );
+        let server = start_search_server(&context(&["--app", "YouTube"]));
+        let before = ssdp::dropped(ssdp::DropReason::InvalidHost);
+        assert_eq!(search(server, &wrong_host).await, None);
+        assert!(ssdp::dropped(ssdp::DropReason::InvalidHost) > before);
+        assert!(search(server, &dial_search()).await.is_some());
+
+        let lenient = start_search_server(&context(&["--app", "YouTube", "--lenient-host"]));
+        assert!(search(lenient, &wrong_host).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn configured_search_targets_are_answered() {
+        let st = "urn:schemas-upnp-org:device:MediaRenderer:1";
+        let usn = format!("uuid:{}::{}", ROOT_DEVICE_UUID, st);
+        let location = "http://192.0.2.40:49152/renderer.xml";
+        let server = start_search_server(&context(&[
+            "--app",
+            "YouTube",
+            "--respond",
+            st,
+            &usn,
+            location,
+        ]));
+
+        let response = search(
+            server,
+            &search_message("239.255.255.250:1900", Some("\"ssdp:discover\""), st),
+        )
+        .await
+        .expect("no response");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(header(&response, "ST"), st);
+        assert_eq!(header(&response, "USN"), usn);
+        assert_eq!(header(&response, "LOCATION"), location);
+        // the built-in DIAL behavior is still there
+        let dial = search(server, &dial_search())
+            .await
+            .expect("no DIAL response");
+        assert_eq!(header(&dial, "ST"), ssdp::DIAL_SEARCH_TARGET);
+        // other targets stay unanswered
+        let other = search_message(
+            "239.255.255.250:1900",
+            Some("\"ssdp:discover\""),
+            "urn:other:1",
+        );
+        assert_eq!(search(server, &other).await, None);
+    }
+
+    #[tokio::test]
+    async fn the_descriptor_and_the_device_notify_agree_on_the_device_type() {
+        for args in [
+            &[][..],
+            &[
+                "--device-type",
+                "urn:schemas-upnp-org:device:MediaRenderer:1",
+            ][..],
+        ] {
+            let ctx = context(args);
+            let request = http::Request::get(dial::DESCRIPTOR_PATH)
+                .body(String::new())
+                .unwrap();
+            let descriptor = dial::handle_request(&ctx, &request).await.into_body();
+            let descriptor = String::from_utf8(descriptor).unwrap();
+            let device_type = descriptor
+                .split_once("<deviceType>")
+                .and_then(|(_, rest)| rest.split_once("</deviceType>"))
+                .map(|(device_type, _)| device_type)
+                .expect("no deviceType");
+            assert_eq!(device_type, ctx.config.device_type);
+
+            let usn = format!("uuid:{}::{}", ROOT_DEVICE_UUID, device_type);
+            let notifies = alive_messages(&ctx);
+            let device_notify = notifies
+                .iter()
+                .find(|message| ssdp::header(message, "NT") == Some(device_type))
+                .unwrap_or_else(|| panic!("no NOTIFY for {}", device_type));
+            assert_eq!(header(device_notify, "USN"), usn);
+        }
+    }
+
+    #[test]
+    fn the_dial_device_type_is_advertised_by_default() {
+        let ctx = context(&["--app", "YouTube"]);
+        let dial_device = "urn:dial-multiscreen-org:device:dial:1";
+        let notifies = alive_messages(&ctx);
+        let advertised: Vec<(&str, &str)> = notifies
+            .iter()
+            .map(|message| (header(message, "NT"), header(message, "USN")))
+            .collect();
+        let usn = format!("uuid:{}::{}", ROOT_DEVICE_UUID, dial_device);
+        assert!(
+            advertised.contains(&(dial_device, usn.as_str())),
+            "{:?}",
+            advertised
+        );
+        assert!(advertised
+            .iter()
+            .all(|(nt, _)| *nt != "urn:schemas-upnp-org:device:Basic:1"));
+    }
+
+    #[tokio::test]
+    async fn the_dial_search_response_has_a_spec_conform_usn() {
+        let server = start_search_server(&context(&["--app", "YouTube"]));
+        let response = search(server, &dial_search()).await.expect("no response");
+        let usn = header(&response, "USN");
+        let uuid = usn
+            .strip_prefix("uuid:")
+            .and_then(|usn| usn.strip_suffix("::urn:dial-multiscreen-org:service:dial:1"))
+            .unwrap_or_else(|| panic!("invalid USN {}", usn));
+        let groups: Vec<&str> = uuid.split('-').collect();
+        assert_eq!(
+            groups.iter().map(|group| group.len()).collect::<Vec<_>>(),
+            [8, 4, 4, 4, 12],
+            "{}",
+            uuid
+        );
+        assert!(groups
+            .iter()
+            .all(|group| group.chars().all(|c| c.is_ascii_hexdigit())));
+        assert_eq!(uuid, ROOT_DEVICE_UUID);
+    }
+
+    #[tokio::test]
+    async fn the_dial_search_response_has_the_uda_headers_and_crlf_framing() {
+        let ctx = context(&["--app", "YouTube"]);
+        let server = start_search_server(&ctx);
+        let response = search(server, &dial_search()).await.expect("no response");
+
+        let head = response
+            .strip_suffix("\r\n\r\n")
+            .expect("not terminated by CRLF CRLF");
+        assert!(!head.contains("\r\n\r\n"));
+        let lines: Vec<&str> = head.split("\r\n").collect();
+        assert!(lines
+            .iter()
+            .all(|line| !line.contains('\n') && !line.contains('\r')));
+        assert_eq!(lines[0], "HTTP/1.1 200 OK");
+        let message = ssdp::SsdpMessage::parse(&response).unwrap();
+        assert_eq!(message.headers.len(), lines.len() - 1);
+
+        // UDA 1.1 writes it as "max-age = 1800"
+        let cache_control = header(&response, "CACHE-CONTROL").replace(' ', "");
+        let max_age = cache_control.strip_prefix("max-age=").expect("no max-age");
+        assert!(max_age.parse::<u32>().is_ok(), "{}", cache_control);
+        assert_eq!(header(&response, "EXT"), "");
+        assert!(!header(&response, "SERVER").is_empty());
+        let date = header(&response, "DATE");
+        assert!(date.ends_with(" GMT") && date.len() == 29, "{}", date);
+        assert_eq!(header(&response, "LOCATION"), descriptor_url(&ctx));
+        assert_eq!(header(&response, "ST"), ssdp::DIAL_SEARCH_TARGET);
+        assert!(header(&response, "USN").ends_with(ssdp::DIAL_SEARCH_TARGET));
+    }
+
+    #[tokio::test]
+    async fn search_responses_come_from_the_port_the_search_arrived_on() {
+        // like the unicast socket in run(), bound to the wildcard address and answering itself
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let reply_socket = Arc::new(socket.try_clone().unwrap());
+        let (responses, queue) = mpsc::channel(RESPONSE_QUEUE_SIZE);
+        let ctx = context(&["--app", "YouTube"]);
+        tokio::spawn(respond(queue, ctx.clock.clone()));
+        std::thread::spawn(move || {
+            let url = descriptor_url(&ctx);
+            let observer = ssdp::SearchObserver::default();
+            serve_searches(&socket, &reply_socket, &url, &ctx, observer, &responses)
+        });
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        client
+            .send_to(dial_search().as_bytes(), server)
+            .await
+            .unwrap();
+        let mut buf = [0; ssdp::MAX_MESSAGE_SIZE];
+        let received = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf));
+        let (_, source) = received.await.expect("no response").unwrap();
+        assert_eq!(source, server);
+    }
+
+    #[test]
+    fn the_response_source_of_a_wildcard_socket_is_routed() {
+        let wildcard = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = wildcard.local_addr().unwrap().port();
+        let local_sender = SocketAddr::from((Ipv4Addr::LOCALHOST, 50000));
+        assert_eq!(
+            response_source(&wildcard, local_sender),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+        );
+        // a socket bound to an address always sends from it
+        let bound = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert_eq!(
+            response_source(&bound, local_sender),
+            bound.local_addr().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn unicast_only_mode_answers_directed_searches() {
+        let ctx = context(&[
+            "--unicast-only",
+            "--app",
+            "YouTube",
+            "--announce-ip",
+            "192.0.2.2",
+        ]);
+        let server = start_search_server(&ctx);
+        let directed = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: 192.0.2.2:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {}\r\n\r\n",
+            ssdp::DIAL_SEARCH_TARGET
+        );
+        let response = search(server, &directed).await.expect("no response");
+        assert_eq!(header(&response, "LOCATION"), descriptor_url(&ctx));
+
+        // run() doesn't hand out the announcer, nothing would receive the NOTIFYs
+        let mut request = http::Request::post("/admin/announce")
+            .body(String::new())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(SocketAddr::from((Ipv4Addr::LOCALHOST, 50000)));
+        let response = dial::handle_request(&ctx, &request).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let selftest = ["--unicast-only", "--selftest"].map(String::from);
+        assert!(Config::from_args(selftest.into_iter()).is_err());
+    }
+
+    #[tokio::test]
+    async fn unicast_searches_are_answered_without_the_mx_delay() {
+        let ctx = context(&["--app", "YouTube", "--announce-ip", "192.0.2.2"]);
+        let server = start_search_server(&ctx);
+        let unicast = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: 192.0.2.2:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 5\r\nST: {}\r\n\r\n",
+            ssdp::DIAL_SEARCH_TARGET
+        );
+        // search() waits a second, a multicast search with MX 5 would mostly be answered later
+        let response = search(server, &unicast).await.expect("no response");
+        let multicast_response = search(server, &dial_search()).await.unwrap();
+        for name in ["LOCATION", "ST", "USN"] {
+            assert_eq!(header(&response, name), header(&multicast_response, name));
+        }
+    }
+
+    // sends `datagram` to `server` and waits until a drop for `reason` is counted
+    async fn assert_dropped(server: SocketAddr, datagram: &[u8], reason: ssdp::DropReason) {
+        let before = ssdp::dropped(reason);
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(datagram, server).await.unwrap();
+        let counted = async {
+            while ssdp::dropped(reason) == before {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let counted = tokio::time::timeout(Duration::from_secs(1), counted).await;
+        assert!(counted.is_ok(), "{} wasn't counted", reason);
+    }
+
+    #[tokio::test]
+    async fn byebyes_with_our_uuid_only_reannounce_when_asked() {
+        let byebye = format!(
+            "NOTIFY * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nNT: upnp:rootdevice\r\nNTS: ssdp:byebye\r\nUSN: uuid:{}::upnp:rootdevice\r\n\r\n",
+            ROOT_DEVICE_UUID
+        );
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let reannounced = |ctx: &AppContext| {
+            let reannounce = ctx.reannounce.clone();
+            async move {
+                tokio::time::timeout(Duration::from_millis(100), reannounce.notified())
+                    .await
+                    .is_ok()
+            }
+        };
+
+        let ctx = context(&["--app", "YouTube", "--own-byebye", "reannounce"]);
+        let server = start_search_server(&ctx);
+        client.send_to(byebye.as_bytes(), server).await.unwrap();
+        assert!(reannounced(&ctx).await);
+
+        // datagrams are handled in order, once the search is answered the byebye was seen
+        for args in [
+            &["--app", "YouTube"][..],
+            &["--app", "YouTube", "--own-byebye", "ignore"],
+        ] {
+            let ctx = context(args);
+            let server = start_search_server(&ctx);
+            client.send_to(byebye.as_bytes(), server).await.unwrap();
+            assert!(search(server, &dial_search()).await.is_some());
+            assert!(!reannounced(&ctx).await, "{:?}", args);
+        }
+
+        // the byebyes we send ourselves when pausing come back with --multicast-loop
+        let ctx = context(&["--app", "YouTube", "--own-byebye", "reannounce"]);
+        let server = start_search_server(&ctx);
+        ctx.set_ssdp_paused(true);
+        client.send_to(byebye.as_bytes(), server).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        ctx.set_ssdp_paused(false);
+        assert!(search(server, &dial_search()).await.is_some());
+        assert!(!reannounced(&ctx).await);
+    }
+
+    #[tokio::test]
+    async fn searches_are_listed_with_their_source() {
+        let ctx = context(&["--app", "YouTube"]);
+        let server = start_search_server(&ctx);
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let message =
+            dial_search().replace("\r\n\r\n", "\r\nUSER-AGENT: \"Test\" UPnP/1.1\r\n\r\n");
+        client.send_to(message.as_bytes(), server).await.unwrap();
+        let mut buf = [0; ssdp::MAX_MESSAGE_SIZE];
+        tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+            .await
+            .expect("no response")
+            .unwrap();
+
+        let mut request = http::Request::get("/admin/searches")
+            .body(String::new())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(SocketAddr::from((Ipv4Addr::LOCALHOST, 50000)));
+        let response = dial::handle_request(&ctx, &request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let listed = json::parse(std::str::from_utf8(response.body()).unwrap()).unwrap();
+        let json::Value::Array(searches) = listed else {
+            panic!("not an array: {:?}", listed);
+        };
+        let [json::Value::Object(search)] = &searches[..] else {
+            panic!("not one search: {:?}", searches);
+        };
+        let string = |value: &str| json::Value::String(value.to_string());
+        assert_eq!(
+            search["source"],
+            string(&client.local_addr().unwrap().to_string())
+        );
+        assert_eq!(search["st"], string(ssdp::DIAL_SEARCH_TARGET));
+        assert_eq!(search["user_agent"], string("\"Test\" UPnP/1.1"));
+        assert!(matches!(search["time"], json::Value::Number(_)));
+    }
+
+    #[tokio::test]
+    async fn every_ignored_datagram_is_counted_with_its_reason() {
+        use ssdp::DropReason;
+
+        let server = start_search_server(&context(&["--app", "YouTube"]));
+        let host = "239.255.255.250:1900";
+        let man = Some("\"ssdp:discover\"");
+        let notify = |usn: &str| {
+            format!(
+                "NOTIFY * HTTP/1.1\r\nHOST: {}\r\nNT: upnp:rootdevice\r\nNTS: ssdp:alive\r\nUSN: {}\r\n\r\n",
+                host, usn
+            )
+        };
+        let cases = [
+            (
+                vec![b'x'; ssdp::MAX_MESSAGE_SIZE + 1],
+                DropReason::Truncated,
+            ),
+            (vec![0xff, 0xfe], DropReason::InvalidUtf8),
+            (b"hello".to_vec(), DropReason::NotSearch),
+            (
+                notify("uuid:other::upnp:rootdevice").into_bytes(),
+                DropReason::NotSearch,
+            ),
+            (
+                b"HTTP/1.1 200 OK\r\nST: upnp:rootdevice\r\n\r\n".to_vec(),
+                DropReason::NotSearch,
+            ),
+            (
+                notify(&format!("uuid:{}::upnp:rootdevice", ROOT_DEVICE_UUID)).into_bytes(),
+                DropReason::SelfEcho,
+            ),
+            (
+                search_message(host, man, "urn:example:other").into_bytes(),
+                DropReason::OtherSearchTarget,
+            ),
+            (
+                search_message(host, None, ssdp::DIAL_SEARCH_TARGET).into_bytes(),
+                DropReason::MissingMan,
+            ),
+            (
+                search_message("192.0.2.99:1900", man, ssdp::DIAL_SEARCH_TARGET).into_bytes(),
+                DropReason::InvalidHost,
+            ),
+        ];
+        for (datagram, reason) in cases {
+            assert_dropped(server, &datagram, reason).await;
+        }
+
+        let without_apps = start_search_server(&context(&[]));
+        assert_dropped(without_apps, dial_search().as_bytes(), DropReason::NoApps).await;
+
+        let ctx = context(&["--app", "YouTube"]);
+        ctx.set_ssdp_paused(true);
+        let paused = start_search_server(&ctx);
+        assert_dropped(paused, dial_search().as_bytes(), DropReason::Paused).await;
+    }
+
+    #[tokio::test]
+    async fn a_full_response_queue_is_counted() {
+        let ctx = context(&[]);
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let search = SsdpMessage::parse(&dial_search()).unwrap();
+        let destination = SocketAddr::from((Ipv4Addr::LOCALHOST, 9));
+        // nobody takes the responses out of the queue
+        let (responses, _queue) = mpsc::channel(1);
+        let before = ssdp::dropped(ssdp::DropReason::QueueFull);
+        for _ in 0..2 {
+            queue_response(
+                &ctx,
+                &responses,
+                &socket,
+                &search,
+                destination,
+                "r".to_string(),
+            );
+        }
+        assert!(ssdp::dropped(ssdp::DropReason::QueueFull) > before);
+    }
+
+    #[test]
+    fn recv_errors_are_classified_by_kind() {
+        use std::io::{Error, ErrorKind};
+
+        for (kind, action) in [
+            (ErrorKind::Interrupted, RecvErrorAction::Retry),
+            (ErrorKind::WouldBlock, RecvErrorAction::Retry),
+            (ErrorKind::ConnectionRefused, RecvErrorAction::Retry),
+            (ErrorKind::NetworkDown, RecvErrorAction::Rejoin),
+            (ErrorKind::AddrNotAvailable, RecvErrorAction::Rejoin),
+            (ErrorKind::PermissionDenied, RecvErrorAction::Fatal),
+            (ErrorKind::InvalidInput, RecvErrorAction::Fatal),
+        ] {
+            assert_eq!(
+                classify_recv_error(&Error::from(kind)),
+                action,
+                "{:?}",
+                kind
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn searches_are_answered_after_transient_recv_errors() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // every recv_from that times out fails with WouldBlock, the loop has to keep going
+        socket
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let ctx = context(&["--app", "YouTube"]);
+        let server = serve_searches_on(socket, &ctx, ssdp::SearchObserver::default());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(search(server, &dial_search()).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn dial_is_only_advertised_with_a_registered_app() {
+        let ctx = context(&[]);
+        let server = start_search_server(&ctx);
+        assert_eq!(search(server, &dial_search()).await, None);
+
+        let definition = http::Request::builder()
+            .method("POST")
+            .uri("/admin/apps")
+            .body(r#"{"name": "YouTube"}"#.to_string())
+            .unwrap();
+        let registered = registration::register(&ctx, &definition).await;
+        assert_eq!(registered.status(), http::StatusCode::CREATED);
+        // the first app makes us a DIAL device, that is announced right away
+        let notified = tokio::time::timeout(Duration::from_secs(1), ctx.reannounce.notified());
+        assert!(notified.await.is_ok());
+
+        let response = search(server, &dial_search()).await.expect("no response");
+        assert_eq!(header(&response, "ST"), ssdp::DIAL_SEARCH_TARGET);
+    }
+
+    #[tokio::test]
+    async fn local_senders_discover_the_server_over_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let args = [
+            "--loopback",
+            "--app",
+            "YouTube",
+            "--announce-ip",
+            "192.0.2.77",
+        ];
+        let ctx = context(&[&args[..], &["--http-port", &port]].concat());
+        tokio::spawn(serve_http(listener, ctx.clone()));
+        let server = start_search_server(&ctx);
+
+        let local_search = search_message(
+            "127.0.0.1:1900",
+            Some("\"ssdp:discover\""),
+            ssdp::DIAL_SEARCH_TARGET,
+        );
+        let response = search(server, &local_search).await.expect("no response");
+        let location = header(&response, "LOCATION");
+        let base = format!("http://127.0.0.1:{}", port);
+        assert_eq!(location, format!("{}{}", base, dial::DESCRIPTOR_PATH));
+
+        let timeout = Duration::from_secs(5);
+        let descriptor = client::request("GET", location, &[], &[], timeout)
+            .await
+            .unwrap();
+        assert_eq!(descriptor.status, 200);
+        let apps_url = descriptor.header("Application-URL").unwrap();
+        assert_eq!(apps_url, format!("{}/apps/", base));
+        let app_url = format!("{}YouTube", apps_url);
+        let status = client::request("GET", &app_url, &[], &[], timeout)
+            .await
+            .unwrap();
+        assert_eq!(status.status, 200);
+    }
+
+    #[tokio::test]
+    async fn oversized_datagrams_are_dropped_instead_of_parsed() {
+        let server = start_search_server(&context(&["--app", "YouTube"]));
+        // a valid search padded beyond the limit, parsing what fits would answer it
+        let mut oversized = dial_search().trim_end().to_string();
+        oversized.push_str("\r\nX-PADDING: ");
+        oversized.push_str(&"x".repeat(ssdp::MAX_MESSAGE_SIZE));
+        oversized.push_str("\r\n\r\n");
+        let before = ssdp::dropped(ssdp::DropReason::Truncated);
+        assert_eq!(search(server, &oversized).await, None);
+        assert!(ssdp::dropped(ssdp::DropReason::Truncated) > before);
+        // the next one is read from the start of the buffer again
+        assert!(search(server, &dial_search()).await.is_some());
+    }
+
+    // sends `request` twice over one connection, None for the second response if the server
+    // closed the connection after the first one
+    async fn request_twice(server: SocketAddr, request: &[u8]) -> (String, Option<String>) {
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let mut buf = vec![0; 64 * 1024];
+        let mut responses = Vec::new();
+        for _ in 0..2 {
+            // writing to a connection the server closed can still succeed, the read tells
+            let _ = stream.write_all(request).await;
+            let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf));
+            let n = read.await.expect("no response").unwrap_or(0);
+            responses.push((n > 0).then(|| String::from_utf8_lossy(&buf[..n]).into_owned()));
+        }
+        let second = responses.pop().unwrap();
+        (responses.pop().unwrap().expect("no first response"), second)
+    }
+
+    #[tokio::test]
+    async fn connections_follow_the_semantics_of_the_request_version() {
+        let server = start_http_server(&context(&[])).await;
+        let cases = [
+            ("HTTP/1.0", "", false),
+            ("HTTP/1.0", "Connection: keep-alive\r\n", true),
+            ("HTTP/1.1", "", true),
+            ("HTTP/1.1", "Connection: close\r\n", false),
+        ];
+        for (version, connection, kept_alive) in cases {
+            let request = format!("GET /healthz {}\r\n{}\r\n", version, connection);
+            let (first, second) = request_twice(server, request.as_bytes()).await;
+            assert!(first.starts_with(&format!("{} 200 ", version)), "{}", first);
+            assert_eq!(second.is_some(), kept_alive, "{} {:?}", version, connection);
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_on_the_ssdp_port_answers_as_configured() {
+        let ctx = context(&[]);
+        let start = |mode| {
+            let ctx = ctx.clone();
+            async move {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let address = listener.local_addr().unwrap();
+                tokio::spawn(serve_ssdp_tcp(listener, mode, ctx));
+                address
+            }
+        };
+
+        let descriptor = start(SsdpTcp::Descriptor).await;
+        let response = exchange(descriptor, b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        assert!(response.contains("<deviceType>"));
+        assert!(response.contains("connection: close\r\n"));
+        let post = exchange(descriptor, b"POST / HTTP/1.1\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(post.starts_with("HTTP/1.1 400 "), "{}", post);
+
+        let bad_request = start(SsdpTcp::BadRequest).await;
+        let response = exchange(bad_request, b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn request_targets_longer_than_max_uri_get_414() {
+        let target = format!("/apps/{}", "a".repeat(3000));
+        let server = start_http_server(&context(&[])).await;
+        // answered as soon as the target is there, without waiting for the rest of the head
+        let response = exchange(server, format!("GET {} HTTP/1.1\r\n", target).as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 414 "), "{}", response);
+
+        let response = exchange(
+            server,
+            format!(
+                "GET {} HTTP/1.1\r\nConnection: close\r\n\r\n",
+                &target[..2048]
+            )
+            .as_bytes(),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+
+        let raised = start_http_server(&context(&["--max-uri", "4096"])).await;
+        let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", target);
+        let response = exchange(raised, request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn connection_close_wins_over_keep_alive() {
+        let server = start_http_server(&context(&[])).await;
+        for (version, connection) in [
+            ("HTTP/1.1", "Connection: close\r\n"),
+            ("HTTP/1.1", "connection: Close\r\n"),
+            ("HTTP/1.1", "Connection: keep-alive, close\r\n"),
+            (
+                "HTTP/1.0",
+                "Connection: keep-alive\r\nConnection: close\r\n",
+            ),
+        ] {
+            let request = format!("GET /healthz {}\r\n{}\r\n", version, connection);
+            let (first, second) = request_twice(server, request.as_bytes()).await;
+            assert!(first.starts_with(&format!("{} 200 ", version)), "{}", first);
+            assert_eq!(second, None, "{} {:?}", version, connection);
+        }
+        // an HTTP/1.1 response says that it deviates from the default
+        let (first, _) = request_twice(
+            server,
+            b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(first.contains("connection: close\r\n"), "{}", first);
+    }
+
+    #[tokio::test]
+    async fn malformed_request_lines_are_answered_with_400() {
+        let server = start_http_server(&context(&[])).await;
+        for line in ["   ", "GET", "GET /"] {
+            let response = exchange(server, format!("{}\r\n\r\n", line).as_bytes()).await;
+            assert!(
+                response.starts_with("HTTP/1.1 400 "),
+                "{:?}: {}",
+                line,
+                response
+            );
+            assert!(response.contains("connection: close\r\n"));
+        }
+        // the server is still there
+        let response = exchange(
+            server,
+            b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn searches_are_delivered_to_the_observer() {
+        let (observer, mut events) = ssdp::SearchObserver::channel(4);
+        let server = start_observed_search_server(&context(&["--app", "YouTube"]), observer);
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(dial_search().as_bytes(), server)
+            .await
+            .unwrap();
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.src_addr, client.local_addr().unwrap());
+        assert_eq!(event.search_target, ssdp::DIAL_SEARCH_TARGET);
+        assert!(event.timestamp <= SystemTime::now());
+    }
+
+    #[test]
+    fn each_notify_carries_its_max_age() {
+        let ctx = context(&[
+            "--max-age-root",
+            "1000",
+            "--max-age-device",
+            "2000",
+            "--max-age-service",
+            "300",
+        ]);
+        let max_ages: Vec<String> = alive_messages(&ctx)
+            .iter()
+            .map(|message| header(message, "CACHE-CONTROL").to_string())
+            .collect();
+        assert_eq!(
+            max_ages,
+            [
+                "max-age = 1000",
+                "max-age = 1000",
+                "max-age = 1000",
+                "max-age = 2000",
+                "max-age = 2000",
+                "max-age = 300"
+            ]
+        );
+        // the re-announcement keeps the shortest lived one alive
+        assert_eq!(ctx.config.max_age.shortest(), 300);
+    }
+
+    #[tokio::test]
+    async fn responses_reach_a_slow_reader() {
+        // far more than the socket buffers hold, write_all has to wait for the reader
+        let ctx = context(&[])
+            .with_descriptor_renderer(|_| format!("<root>{}</root>", "<a/>".repeat(256 * 1024)))
+            .unwrap();
+        let server = start_http_server(&ctx).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nConnection: close\r\n\r\n",
+            dial::DESCRIPTOR_PATH
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        let mut chunk = [0; 4096];
+        loop {
+            tokio::time::sleep(Duration::from_micros(200)).await;
+            match stream.read(&mut chunk).await.unwrap() {
+                0 => break,
+                n => response.extend_from_slice(&chunk[..n]),
+            }
+        }
+        let text = String::from_utf8(response).unwrap();
+        let (head, body) = text.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 "));
+        assert_eq!(header(head, "content-length"), body.len().to_string());
+        assert_eq!(body, ctx.descriptor_renderer.render(&ctx.config));
+    }
+
+    #[tokio::test]
+    async fn profiles_change_the_server_header() {
+        for profile in profile::PROFILES {
+            let ctx = context(&["--profile", profile.name, "--app", "YouTube"]);
+            for message in alive_messages(&ctx) {
+                assert_eq!(header(&message, "SERVER"), profile.server);
+            }
+            let response = search(start_search_server(&ctx), &dial_search())
+                .await
+                .unwrap();
+            assert_eq!(header(&response, "SERVER"), profile.server);
+            let request = http::Request::builder()
+                .uri(dial::DESCRIPTOR_PATH)
+                .body(String::new())
+                .unwrap();
+            let descriptor = dial::handle_request(&ctx, &request).await;
+            assert_eq!(descriptor.headers()["server"], profile.server);
+            for (name, value) in profile.extra_headers {
+                assert_eq!(descriptor.headers()[*name], *value);
+            }
+            let xml = String::from_utf8(descriptor.into_body()).unwrap();
+            if let Some(friendly_name) = profile.friendly_name {
+                assert!(xml.contains(&format!("<friendlyName>{}</friendlyName>", friendly_name)));
+            }
+        }
+    }
+
+    #[test]
+    fn profiles_without_services_dont_advertise_them() {
+        let messages = alive_messages(&context(&["--profile", "lg-webos"]));
+        assert_eq!(messages.len(), 5);
+        assert!(messages
+            .iter()
+            .all(|message| !header(message, "NT").contains(":service:")));
+    }
+
+    #[tokio::test]
+    async fn descriptor_is_gzipped_for_clients_that_accept_it() {
+        use std::io::Read;
+
+        let ctx = context(&[]);
+        let xml = ctx.descriptor_renderer.render(&ctx.config);
+        assert!(xml.len() >= 512, "too small to be compressed");
+        let server = start_http_server(&ctx).await;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n",
+            dial::DESCRIPTOR_PATH
+        );
+        let response = exchange_bytes(server, request.as_bytes()).await;
+        let head_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = std::str::from_utf8(&response[..head_end]).unwrap();
+        let body = &response[head_end + 4..];
+        assert!(head.starts_with("HTTP/1.1 200 "));
+        assert_eq!(header(head, "content-encoding"), "gzip");
+        assert_eq!(header(head, "content-length"), body.len().to_string());
+        assert!(body.len() < xml.len());
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(body)
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, xml);
+
+        // without Accept-Encoding the body is sent as it is
+        let request = format!(
+            "GET {} HTTP/1.1\r\nConnection: close\r\n\r\n",
+            dial::DESCRIPTOR_PATH
+        );
+        let response = exchange(server, request.as_bytes()).await;
+        assert!(ssdp::header(&response, "content-encoding").is_none());
+        assert!(response.ends_with(&xml));
+    }
+
+    #[tokio::test]
+    async fn once_mode_exits_after_a_launch() {
+        let ctx = context(&["--once", "--app", "YouTube"]);
+        tokio::spawn(exit_after_first_launch(ctx.clone()));
+        let server = start_http_server(&ctx).await;
+        let response = exchange(
+            server,
+            b"POST /apps/YouTube HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 201 "), "{}", response);
+        let exit_code = tokio::time::timeout(Duration::from_secs(1), ctx.shutdown.wait()).await;
+        assert_eq!(exit_code, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn once_mode_fails_without_a_launch() {
+        let ctx = context(&["--once", "--once-timeout", "1", "--app", "YouTube"]);
+        tokio::spawn(exit_after_first_launch(ctx.clone()));
+        let exit_code = tokio::time::timeout(Duration::from_secs(3), ctx.shutdown.wait()).await;
+        assert_eq!(exit_code, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn the_user_agent_of_a_search_surfaces_in_the_event() {
+        let (observer, mut events) = ssdp::SearchObserver::channel(4);
+        let server = start_observed_search_server(&context(&["--app", "YouTube"]), observer);
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let user_agent = "Android/13 UPnP/1.1 YouTube/18.1";
+        let with_agent =
+            dial_search().replace("MX: 0", &format!("MX: 0\r\nUSER-AGENT: {}", user_agent));
+        for message in [with_agent, dial_search()] {
+            client.send_to(message.as_bytes(), server).await.unwrap();
+        }
+        for expected in [Some(user_agent), None] {
+            let event = tokio::time::timeout(Duration::from_secs(1), events.recv()).await;
+            assert_eq!(event.unwrap().unwrap().user_agent.as_deref(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_response_is_sent_with_a_single_write() {
+        let server = start_http_server(&context(&[])).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\n\r\n", dial::DESCRIPTOR_PATH);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        // on loopback one write of a few KB arrives in one piece, a response written in parts
+        // (TCP_NODELAY is set) would be split across reads
+        let mut buf = vec![0; 64 * 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        let text = std::str::from_utf8(&buf[..n]).unwrap();
+        let (head, body) = text.split_once("\r\n\r\n").unwrap();
+        assert_eq!(header(head, "content-length"), body.len().to_string());
+        assert!(body.trim_end().ends_with("</root>"));
+    }
+
+    #[tokio::test]
+    async fn a_request_in_progress_is_answered_after_the_shutdown() {
+        let ctx = context(&["--app", "YouTube"]);
+        let server = start_http_server(&ctx).await;
+        let request = b"GET /apps/YouTube?wait HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let response = tokio::spawn(async move { exchange(server, request).await });
+        // the long-poll holds the request back until the state changes or the server shuts down
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!response.is_finished());
+        ctx.shutdown.trigger(0);
+        let response = response.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("<state>stopped</state>"), "{}", response);
+    }
+
+    #[test]
+    fn the_multicast_loop_follows_the_flag() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for (args, expected) in [(&[][..], false), (&["--multicast-loop"][..], true)] {
+            set_multicast_loop(&socket, &context(args).config).unwrap();
+            assert_eq!(socket.multicast_loop_v4().unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn selftest_stages_pass_against_the_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let ctx = context(&[
+            "selftest",
+            "--http-port",
+            &port,
+            "--announce-ip",
+            "127.0.0.1",
+        ]);
+        tokio::spawn(serve_http(listener, ctx.clone()));
+        // the discovery stage needs multicast on port 1900, the rest follows its LOCATION
+        assert!(selftest::launch_and_stop(&descriptor_url(&ctx))
+            .await
+            .is_some());
+        let apps = ctx.apps.read().await;
+        assert!(apps
+            .get(selftest::SELFTEST_APP)
+            .unwrap()
+            .instance()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn the_first_announcement_waits_for_the_announce_delay() {
+        let config = &context(&["--announce-delay", "300"]).config;
+        let start = tokio::time::Instant::now();
+        wait_before_announcing(config).await;
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        let config = &context(&["--announce-delay", "0"]).config;
+        // a timeout of zero still polls the future once, it has to be ready right away
+        let ready = tokio::time::timeout(Duration::ZERO, wait_before_announcing(config)).await;
+        assert!(ready.is_ok());
+    }
+
+    #[tokio::test]
+    async fn the_http_listener_uses_its_own_bind_address() {
+        let args = [
+            "--http-bind-ip",
+            "127.0.0.1",
+            "--ssdp-bind-ip",
+            "192.0.2.2",
+            "--http-port",
+            "0",
+        ];
+        let mut config = Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        let listener = bind_http(&mut config).await.unwrap();
+        assert_eq!(
+            listener.local_addr().unwrap().ip(),
+            IpAddr::from(Ipv4Addr::LOCALHOST)
+        );
+    }
+
+    #[test]
+    fn byebyes_only_carry_the_allowed_headers() {
+        let ctx = context(&[]);
+        for message in byebye_messages(&ctx.config) {
+            let message = SsdpMessage::parse(&message).unwrap();
+            assert_eq!(message.start_line, SsdpStartLine::Notify);
+            let mut headers: Vec<&str> = message.headers.keys().map(String::as_str).collect();
+            headers.sort_unstable();
+            assert_eq!(headers, ["HOST", "NT", "NTS", "USN"]);
+            assert_eq!(message.header("NTS"), Some("ssdp:byebye"));
+        }
+    }
+
+    #[test]
+    fn byebyes_revoke_the_alive_messages_in_reverse_order() {
+        for args in [
+            &["--profile", "lg-webos"][..],
+            &["--profile", "samsung-tv"][..],
+        ] {
+            let ctx = context(args);
+            let advertised = |messages: Vec<String>| -> Vec<(String, String)> {
+                messages
+                    .iter()
+                    .map(|message| {
+                        let message = SsdpMessage::parse(message).unwrap();
+                        let nt = message.header("NT").unwrap().to_string();
+                        (nt, message.header("USN").unwrap().to_string())
+                    })
+                    .collect()
+            };
+            // the root device and the device messages both advertise the UUID and device type
+            let mut alive = Vec::new();
+            for pair in advertised(alive_messages(&ctx)) {
+                if !alive.contains(&pair) {
+                    alive.push(pair);
+                }
+            }
+            alive.reverse();
+            assert_eq!(advertised(byebye_messages(&ctx.config)), alive);
+        }
+    }
+
+    #[test]
+    fn device_notifies_advertise_uda_usns() {
+        let ctx = context(&["--app", "YouTube"]);
+        let config = &ctx.config;
+        let url = descriptor_url(&ctx);
+        let usns = |messages: Vec<String>| -> Vec<(String, String)> {
+            messages
+                .iter()
+                .map(|message| (header(message, "NT").into(), header(message, "USN").into()))
+                .collect()
+        };
+        let uuid = format!("uuid:{}", ROOT_DEVICE_UUID);
+        let device = (
+            config.device_type.clone(),
+            format!("{}::{}", uuid, config.device_type),
+        );
+        assert_eq!(
+            usns(root_device_notifies(&url, 1800, 1, config)),
+            [
+                (
+                    "upnp:rootdevice".to_string(),
+                    format!("uuid:{}::upnp:rootdevice", ROOT_DEVICE_UUID)
+                ),
+                (uuid.clone(), uuid.clone()),
+                device.clone(),
+            ]
+        );
+        assert_eq!(
+            usns(device_notifies(&url, 1800, 1, config)),
+            [(uuid.clone(), uuid.clone()), device]
+        );
+        assert!(byebye_messages(config)
+            .iter()
+            .all(|message| !header(message, "USN").starts_with("uuid::")));
+    }
+
+    // joined to the SSDP group on the loopback interface, receives what multicast_sender() sends
+    fn multicast_receiver() -> UdpSocket {
+        use socket2::{Domain, Socket, Type};
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        // a dial_server running on this host is bound to the port too
+        socket.set_reuse_address(true).unwrap();
+        let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 1900));
+        socket.bind(&address.into()).unwrap();
+        socket
+            .join_multicast_v4(&ssdp::MULTICAST_IP, &Ipv4Addr::LOCALHOST)
+            .unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        socket.into()
+    }
+
+    fn multicast_sender() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        SockRef::from(&socket)
+            .set_multicast_if_v4(&Ipv4Addr::LOCALHOST)
+            .unwrap();
+        socket.set_multicast_loop_v4(true).unwrap();
+        socket
+    }
+
+    // the datagrams received until none arrived for the read timeout
+    fn received_messages(socket: &UdpSocket) -> Vec<String> {
+        let mut buf = [0; ssdp::MAX_MESSAGE_SIZE];
+        let mut messages = Vec::new();
+        while let Ok(amt) = socket.recv(&mut buf) {
+            messages.push(String::from_utf8_lossy(&buf[..amt]).into_owned());
+        }
+        messages
+    }
+
+    // received_messages() of a single sender, other tests announce on the group as well
+    fn received_from(socket: &UdpSocket, sender: SocketAddr) -> Vec<String> {
+        let mut buf = [0; ssdp::MAX_MESSAGE_SIZE];
+        let mut messages = Vec::new();
+        while let Ok((amt, src)) = socket.recv_from(&mut buf) {
+            if src == sender {
+                messages.push(String::from_utf8_lossy(&buf[..amt]).into_owned());
+            }
+        }
+        messages
+    }
+
+    // the receiver blocks, the announcements are sent from another worker
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pausing_sends_byebye_and_stops_announcing() {
+        let receiver = multicast_receiver();
+        let sender = multicast_sender();
+        let sender_address = sender.local_addr().unwrap();
+        let ctx = context(&["--app", "YouTube", "--announce-repeats", "1"]);
+        let (announce_requests, queue) = mpsc::channel(1);
+        tokio::spawn(reannounce(
+            Arc::new(sender),
+            descriptor_url(&ctx),
+            ctx.clone(),
+            queue,
+        ));
+        // reannounce() only sees changes after it subscribed
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        ctx.set_ssdp_paused(true);
+        let nts = |messages: Vec<String>| -> Vec<String> {
+            messages
+                .iter()
+                .map(|message| header(message, "NTS").to_string())
+                .collect()
+        };
+        let sent = nts(received_from(&receiver, sender_address));
+        assert_eq!(sent.len(), byebye_messages(&ctx.config).len());
+        assert!(sent.iter().all(|nts| nts == "ssdp:byebye"), "{:?}", sent);
+
+        // neither requested nor admin announcements are sent while paused
+        ctx.reannounce.notify_one();
+        let (reply, answer) = tokio::sync::oneshot::channel();
+        announce_requests.send(reply).await.unwrap();
+        assert_eq!(answer.await.unwrap().unwrap(), 0);
+        assert_eq!(
+            received_from(&receiver, sender_address),
+            Vec::<String>::new()
+        );
+
+        ctx.set_ssdp_paused(false);
+        let sent = nts(received_from(&receiver, sender_address));
+        assert_eq!(sent.len(), alive_messages(&ctx).len());
+        assert!(sent.iter().all(|nts| nts == "ssdp:alive"), "{:?}", sent);
+    }
+
+    #[test]
+    fn a_rejoin_leaves_the_group_before_joining_it_again() {
+        let receiver = multicast_receiver();
+        let interface = Ipv4Addr::LOCALHOST;
+        // joined twice fails, a rejoin has to leave first
+        assert!(receiver
+            .join_multicast_v4(&ssdp::MULTICAST_IP, &interface)
+            .is_err());
+        rejoin_multicast(&receiver, interface).unwrap();
+        rejoin_multicast(&receiver, interface).unwrap();
+
+        let sender = multicast_sender();
+        let probe = format!("rejoin probe {}", std::process::id());
+        let group = SocketAddr::from((ssdp::MULTICAST_IP, 1900));
+        sender.send_to(probe.as_bytes(), group).unwrap();
+        assert!(received_messages(&receiver).contains(&probe));
+
+        // after leaving there's no membership left to leave
+        leave_multicast(&receiver, interface);
+        assert!(receiver
+            .leave_multicast_v4(&ssdp::MULTICAST_IP, &interface)
+            .is_err());
+        assert!(join_multicast(&receiver, interface));
+    }
+
+    // tokio's clock is the clock of the timing code, paused it jumps ahead whenever every task waits
+    #[tokio::test(start_paused = true)]
+    async fn reannouncements_follow_half_the_max_age_on_a_paused_clock() {
+        let receiver = multicast_receiver();
+        let sender = multicast_sender();
+        let sender_address = sender.local_addr().unwrap();
+        let ctx = context(&[
+            "--app",
+            "YouTube",
+            "--announce-repeats",
+            "1",
+            "--max-age",
+            "1800",
+        ]);
+        let (_announce_requests, queue) = mpsc::channel(1);
+        tokio::spawn(reannounce(
+            Arc::new(sender),
+            descriptor_url(&ctx),
+            ctx.clone(),
+            queue,
+        ));
+
+        // announced every 900 seconds, none of them before
+        tokio::time::sleep(Duration::from_secs(899)).await;
+        assert_eq!(
+            received_from(&receiver, sender_address),
+            Vec::<String>::new()
+        );
+        for _ in 0..2 {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            let sent = received_from(&receiver, sender_address);
+            assert_eq!(sent.len(), alive_messages(&ctx).len());
+            tokio::time::sleep(Duration::from_secs(898)).await;
+            assert_eq!(
+                received_from(&receiver, sender_address),
+                Vec::<String>::new()
+            );
+        }
+    }
+
+    // the receiver blocks, the announcements are sent from another worker
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reannouncements_wait_for_the_clock_of_the_context() {
+        let receiver = multicast_receiver();
+        let sender = multicast_sender();
+        let sender_address = sender.local_addr().unwrap();
+        let clock = Arc::new(MockClock::new());
+        let mut ctx = context(&["--app", "YouTube", "--announce-repeats", "1"]);
+        ctx.clock = clock.clone();
+        let (_announce_requests, queue) = mpsc::channel(1);
+        tokio::spawn(reannounce(
+            Arc::new(sender),
+            descriptor_url(&ctx),
+            ctx.clone(),
+            queue,
+        ));
+
+        // half of the default max-age of 900 seconds, real time doesn't count
+        assert_eq!(
+            received_from(&receiver, sender_address),
+            Vec::<String>::new()
+        );
+        clock.advance(Duration::from_secs(449));
+        assert_eq!(
+            received_from(&receiver, sender_address),
+            Vec::<String>::new()
+        );
+        clock.advance(Duration::from_secs(1));
+        let sent = received_from(&receiver, sender_address);
+        assert_eq!(sent.len(), alive_messages(&ctx).len());
+    }
+
+    #[tokio::test]
+    async fn admin_announce_broadcasts_and_reports_the_count() {
+        let receiver = multicast_receiver();
+        let sender = multicast_sender();
+        let sender_address = sender.local_addr().unwrap();
+        let mut ctx = context(&[
+            "--app",
+            "YouTube",
+            "--announce-repeats",
+            "2",
+            "--admin-user",
+            "admin",
+            "--admin-pass",
+            "secret",
+        ]);
+        let (announce, queue) = mpsc::channel(1);
+        ctx.announce = Some(announce);
+        tokio::spawn(reannounce(
+            Arc::new(sender),
+            descriptor_url(&ctx),
+            ctx.clone(),
+            queue,
+        ));
+        let request = |authorization: Option<&'static str>| {
+            let mut request = http::Request::post("/admin/announce");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            request.body(String::new()).unwrap()
+        };
+
+        let unauthorized = dial::handle_request(&ctx, &request(None)).await;
+        assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+        // admin:secret
+        let response = dial::handle_request(&ctx, &request(Some("Basic YWRtaW46c2VjcmV0"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let expected = 2 * alive_messages(&ctx).len();
+        assert_eq!(
+            String::from_utf8(response.into_body()).unwrap(),
+            format!(r#"{{"sent":{}}}"#, expected)
+        );
+        let sent = received_from(&receiver, sender_address);
+        assert_eq!(sent.len(), expected);
+        assert!(sent
+            .iter()
+            .all(|message| header(message, "NTS") == "ssdp:alive"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requested_announcements_are_coalesced_within_the_cooldown() {
+        let receiver = multicast_receiver();
+        let sender = multicast_sender();
+        let sender_address = sender.local_addr().unwrap();
+        let ctx = context(&[
+            "--app",
+            "YouTube",
+            "--announce-repeats",
+            "1",
+            "--announce-cooldown",
+            "1",
+        ]);
+        let (_announce_requests, queue) = mpsc::channel(1);
+        tokio::spawn(reannounce(
+            Arc::new(sender),
+            descriptor_url(&ctx),
+            ctx.clone(),
+            queue,
+        ));
+
+        // a flapping interface, every address change requests an announcement
+        for _ in 0..5 {
+            ctx.reannounce.notify_one();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        // the coalesced announcement is sent at the end of the cooldown
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        let sent = received_from(&receiver, sender_address);
+        assert_eq!(sent.len(), alive_messages(&ctx).len());
+        assert!(sent
+            .iter()
+            .all(|message| header(message, "NTS") == "ssdp:alive"));
+    }
+
+    #[test]
+    fn every_interface_gets_its_own_location() {
+        let ctx = context(&[
+            "--announce-interface",
+            "192.0.2.2",
+            "--announce-interface",
+            "198.51.100.2",
+        ]);
+        let locations = announce_locations(&ctx.config, &descriptor_url(&ctx));
+        assert_eq!(
+            locations,
+            [
+                (
+                    Some(Ipv4Addr::new(192, 0, 2, 2)),
+                    format!("http://192.0.2.2:8081{}", dial::DESCRIPTOR_PATH)
+                ),
+                (
+                    Some(Ipv4Addr::new(198, 51, 100, 2)),
+                    format!("http://198.51.100.2:8081{}", dial::DESCRIPTOR_PATH)
+                ),
+            ]
+        );
+        assert_ne!(locations[0].1, locations[1].1);
+
+        let ctx = context(&[]);
+        assert_eq!(
+            announce_locations(&ctx.config, &descriptor_url(&ctx)),
+            [(None, descriptor_url(&ctx))]
+        );
+    }
+
+    #[tokio::test]
+    async fn announcements_on_an_interface_carry_its_address() {
+        let receiver = multicast_receiver();
+        let sender = UdpSocket::bind("0.0.0.0:0").unwrap();
+        sender.set_multicast_loop_v4(true).unwrap();
+        let sender_address =
+            SocketAddr::from((Ipv4Addr::LOCALHOST, sender.local_addr().unwrap().port()));
+        let ctx = context(&[
+            "--announce-ip",
+            "192.0.2.77",
+            "--announce-interface",
+            "127.0.0.1",
+            "--announce-repeats",
+            "1",
+        ]);
+
+        let sent = announce_on_interfaces(&sender, &descriptor_url(&ctx), 1, &ctx.config)
+            .await
+            .unwrap();
+        let received = received_from(&receiver, sender_address);
+        assert_eq!(received.len(), sent);
+        let location = format!("http://127.0.0.1:8081{}", dial::DESCRIPTOR_PATH);
+        assert!(received
+            .iter()
+            .all(|message| header(message, "LOCATION") == location));
+
+        // searches answered from that interface get the same LOCATION
+        let ctx = context(&["--app", "YouTube", "--announce-interface", "127.0.0.1"]);
+        let response = search(start_search_server(&ctx), &dial_search())
+            .await
+            .expect("no response");
+        assert_eq!(header(&response, "LOCATION"), location);
+    }
+
+    #[tokio::test]
+    async fn every_message_is_sent_announce_repeats_times() {
+        let receiver = multicast_receiver();
+        let sender = multicast_sender();
+        // only this test announces 192.0.2.77, other senders on the group are filtered out
+        let ctx = context(&["--announce-repeats", "3", "--announce-ip", "192.0.2.77"]);
+        let url = descriptor_url(&ctx);
+
+        let sent = broadcast_creation(&sender, &url, 1, &ctx.config)
+            .await
+            .unwrap();
+        let per_round = alive_messages(&ctx).len();
+        assert_eq!(sent, 3 * per_round);
+        let received: Vec<String> = received_messages(&receiver)
+            .into_iter()
+            .filter(|message| ssdp::header(message, "LOCATION") == Some(url.as_str()))
+            .collect();
+        assert_eq!(received.len(), 3 * per_round);
+
+        broadcast_byebye(&sender, &ctx.config).await.unwrap();
+        let byebyes = received_messages(&receiver)
+            .into_iter()
+            .filter(|message| ssdp::header(message, "NTS") == Some("ssdp:byebye"))
+            .filter(|message| message.contains(ROOT_DEVICE_UUID))
+            .count();
+        assert_eq!(byebyes, 3 * byebye_messages(&ctx.config).len());
+    }
+
+    #[tokio::test]
+    async fn a_round_of_broadcasts_is_captured() {
+        let sender = multicast_sender();
+        // only this test announces 192.0.2.78, records of other tests are filtered out
+        let ctx = context(&["--announce-repeats", "2", "--announce-ip", "192.0.2.78"]);
+        let url = descriptor_url(&ctx);
+        let path = capture::tests::temp_path("broadcasts");
+        capture::start(&path, 1 << 24).unwrap();
+
+        let sent = broadcast_creation(&sender, &url, 1, &ctx.config)
+            .await
+            .unwrap();
+        capture::stop();
+        let records: Vec<_> = capture::tests::read_records(&path)
+            .into_iter()
+            .filter(|record| {
+                let message = String::from_utf8_lossy(&record.payload);
+                ssdp::header(&message, "LOCATION") == Some(url.as_str())
+            })
+            .collect();
+        assert_eq!(sent, 2 * alive_messages(&ctx).len());
+        assert_eq!(records.len(), sent);
+        assert!(records
+            .iter()
+            .all(|record| record.direction == capture::Direction::Sent
+                && record.destination == SocketAddr::from(([239, 255, 255, 250], 1900))));
+    }
+
+    // an app that writes its DIAL_PAYLOAD to <dir>/seen
+    fn echo_app(name: &str) -> (PathBuf, AppContext) {
+        let dir = std::env::temp_dir().join(format!("dial_server_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ctx = context(&[
+            "--app",
+            r#"Echo=printf '%s' "$DIAL_PAYLOAD" > seen"#,
+            "--app-cwd",
+            "Echo",
+            dir.to_str().unwrap(),
+        ]);
+        (dir, ctx)
+    }
+
+    // what the echo app wrote once it wrote `length` bytes (or after 5 seconds)
+    async fn seen_payload(dir: &Path, length: usize) -> String {
+        let seen = dir.join("seen");
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while std::fs::read(&seen).map_or(0, |seen| seen.len()) < length
+            && tokio::time::Instant::now() < deadline
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        std::fs::read_to_string(&seen).unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_large_launch_body_is_fully_received() {
+        let (dir, ctx) = echo_app("body");
+        let server = start_http_server(&ctx).await;
+        // more than twice the old fixed 8 KiB read buffer
+        let payload = "a".repeat(20 * 1024);
+        let request = format!(
+            "POST /apps/Echo HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+             Content-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            payload.len(),
+            payload
+        );
+
+        let response = exchange(server, request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+        assert_eq!(seen_payload(&dir, payload.len()).await, payload);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_launch_handler_sees_a_body_sent_in_pieces() {
+        let (dir, ctx) = echo_app("pieces");
+        let server = start_http_server(&ctx).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let mut buf = vec![0; 1024];
+
+        // the client waits for the 100 Continue before it sends the body
+        let head = "POST /apps/Echo HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\n\
+                    Transfer-Encoding: chunked\r\n\r\n";
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(1), stream.read(&mut buf));
+        let n = read.await.expect("no 100 Continue").unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 100 Continue\r\n\r\n"));
+
+        for chunk in ["5\r\nv=abc\r\n", "5\r\n&t=10\r\n", "0\r\n\r\n"] {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            stream.write_all(chunk.as_bytes()).await.unwrap();
+        }
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf));
+        let n = read.await.expect("no response").unwrap();
+        let response = String::from_utf8_lossy(&buf[..n]);
+        assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+        assert_eq!(seen_payload(&dir, 10).await, "v=abc&t=10");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_stuck_shutdown_is_forced_after_the_timeout() {
+        let ctx = context(&["--app", "YouTube"]);
+        let _request = ctx
+            .in_flight
+            .start("DELETE /apps/YouTube/run from 127.0.0.1".to_string());
+        // a stop that never returns keeps the registry locked
+        let stuck_stop = ctx.apps.write().await;
+
+        let started = std::time::Instant::now();
+        let (forced, exited) = std::sync::mpsc::channel();
+        spawn_watchdog(&ctx, Duration::from_millis(300), move || {
+            forced.send(started.elapsed()).unwrap()
+        });
+        let elapsed = exited
+            .recv_timeout(Duration::from_secs(5))
+            .expect("not forced");
+        assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+        assert_eq!(
+            unfinished_work(&ctx),
+            [
+                "Still handling DELETE /apps/YouTube/run from 127.0.0.1",
+                "App registry is still locked"
+            ]
+        );
+        drop(stuck_stop);
+    }
+
+    #[tokio::test]
+    async fn running_apps_are_reported_as_unfinished() {
+        let ctx = context(&["--app", "YouTube"]);
+        assert_eq!(unfinished_work(&ctx), Vec::<String>::new());
+        let (run_id, _) = ctx
+            .apps
+            .write()
+            .await
+            .launch("YouTube", "", None, apps::LaunchPolicy::Reuse)
+            .unwrap();
+        assert_eq!(
+            unfinished_work(&ctx),
+            [format!("Still running YouTube (run {})", run_id)]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_taken_port_is_reported_or_skipped_with_port_fallback() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let port_arg = port.to_string();
+        let config = |extra: &[&str]| {
+            let mut args = vec!["--http-bind-ip", "127.0.0.1", "--http-port", &port_arg];
+            args.extend_from_slice(extra);
+            Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap()
+        };
+
+        let e = bind_http(&mut config(&[])).await.unwrap_err();
+        assert!(matches!(e, DialError::PortInUse(p, 1) if p == port));
+        assert!(e.to_string().contains("--http-port"));
+
+        let mut fallback = config(&["--port-fallback"]);
+        let listener = bind_http(&mut fallback).await.unwrap();
+        assert_ne!(fallback.http_port, port);
+        assert_eq!(listener.local_addr().unwrap().port(), fallback.http_port);
+    }
+
+    #[tokio::test]
+    async fn status_reports_the_joined_multicast_group() {
+        let ctx = context(&["--ssdp-bind-ip", "127.0.0.1"]);
+        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        ctx.multicast
+            .set_joined(join_multicast(&socket, ctx.config.ssdp_bind_ip));
+
+        let request = http::Request::builder()
+            .uri("/status")
+            .body(String::new())
+            .unwrap();
+        let response = dial::handle_request(&ctx, &request).await;
+        let status = json::parse(std::str::from_utf8(response.body()).unwrap()).unwrap();
+        let json::Value::Object(fields) = status else {
+            panic!("not an object: {:?}", status);
+        };
+        let string = |value: &str| json::Value::String(value.to_string());
+        assert_eq!(fields["multicast_group"], string("239.255.255.250"));
+        assert_eq!(fields["multicast_interface"], string("127.0.0.1"));
+        assert_eq!(fields["multicast_joined"], json::Value::Bool(true));
+    }
+
+    #[test]
+    fn the_configured_server_is_sent_verbatim() {
+        let server = "Linux/4.9 UPnP/1.0 Cast/1.56 (with a comment)";
+        let ctx = context(&["--server", server]);
+        for message in alive_messages(&ctx) {
+            assert_eq!(header(&message, "SERVER"), server);
+        }
+    }
+
+    #[test]
+    fn every_advertisement_category_has_its_own_server() {
+        let args = [
+            "--profile",
+            "samsung-tv",
+            "--server-root",
+            "Root/1",
+            "--server-device",
+            "Device/2",
+            "--server-service",
+            "Service/3",
+        ];
+        let ctx = context(&args);
+        let config = &ctx.config;
+        let url = descriptor_url(&ctx);
+        let categories = [
+            (root_device_notifies(&url, 1800, 1, config), "Root/1"),
+            (device_notifies(&url, 1800, 1, config), "Device/2"),
+            (service_notifies(&url, 1800, 1, config), "Service/3"),
+        ];
+        for (messages, server) in categories {
+            assert!(!messages.is_empty());
+            for message in messages {
+                assert_eq!(header(&message, "SERVER"), server);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn the_advertised_port_is_only_used_in_urls() {
+        let args = [
+            "--app",
+            "YouTube",
+            "--announce-ip",
+            "192.0.2.2",
+            "--http-bind-ip",
+            "127.0.0.1",
+            "--http-port",
+            "0",
+            "--advertise-port",
+            "8443",
+        ];
+        let mut config = Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        let listener = bind_http(&mut config).await.unwrap();
+        let local_port = listener.local_addr().unwrap().port();
+        assert_ne!(local_port, 8443);
+
+        let ctx = AppContext::new(config);
+        let expected = format!("http://192.0.2.2:8443{}", dial::DESCRIPTOR_PATH);
+        assert_eq!(descriptor_url(&ctx), expected);
+        for message in alive_messages(&ctx) {
+            assert_eq!(header(&message, "LOCATION"), expected);
+        }
+        let response = search(start_search_server(&ctx), &dial_search())
+            .await
+            .unwrap();
+        assert_eq!(header(&response, "LOCATION"), expected);
+
+        // the descriptor is served on the local port and points to the advertised one
+        tokio::spawn(serve_http(listener, ctx.clone()));
+        let request = format!(
+            "GET {} HTTP/1.1\r\nConnection: close\r\n\r\n",
+            dial::DESCRIPTOR_PATH
+        );
+        let server = SocketAddr::from((Ipv4Addr::LOCALHOST, local_port));
+        let descriptor = exchange(server, request.as_bytes()).await;
+        assert_eq!(
+            header(&descriptor, "application-url"),
+            "http://192.0.2.2:8443/apps/"
+        );
+    }
+
+    #[tokio::test]
+    async fn the_advertised_host_is_in_every_url() {
+        let args = [
+            "--app",
+            "YouTube",
+            "--advertise-host",
+            "tv.example",
+            "--http-port",
+            "8081",
+        ];
+        let ctx = context(&args);
+        let base = "http://tv.example:8081";
+        let location = format!("{}{}", base, dial::DESCRIPTOR_PATH);
+        for message in alive_messages(&ctx) {
+            assert_eq!(header(&message, "LOCATION"), location);
+        }
+        let response = search(start_search_server(&ctx), &dial_search())
+            .await
+            .unwrap();
+        assert_eq!(header(&response, "LOCATION"), location);
+
+        let request = |method: &str, path: &str| {
+            http::Request::builder()
+                .method(method)
+                .uri(path)
+                .body(String::new())
+                .unwrap()
+        };
+        let descriptor = dial::handle_request(&ctx, &request("GET", dial::DESCRIPTOR_PATH)).await;
+        assert_eq!(
+            descriptor.headers()["application-url"],
+            format!("{}/apps/", base).as_str()
+        );
+        let launched = dial::handle_request(&ctx, &request("POST", "/apps/YouTube")).await;
+        let instance = launched.headers()["location"].to_str().unwrap();
+        assert!(
+            instance.starts_with(&format!("{}/apps/YouTube/run/", base)),
+            "{}",
+            instance
+        );
+    }
+
+    #[test]
+    fn requested_buffer_sizes_are_applied() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let default_recv = SockRef::from(&socket).recv_buffer_size().unwrap();
+        let config = context(&["--udp-recv-buffer", "65536", "--udp-send-buffer", "32768"])
+            .config
+            .clone();
+        set_buffer_sizes(&socket, &config).unwrap();
+        let socket = SockRef::from(&socket);
+        // Linux doubles the value for its bookkeeping (and caps it at rmem_max/wmem_max, which
+        // are larger than this by default)
+        assert!(socket.recv_buffer_size().unwrap() >= 65536);
+        assert!(socket.send_buffer_size().unwrap() >= 32768);
+        assert_ne!(socket.recv_buffer_size().unwrap(), default_recv);
+    }
+}
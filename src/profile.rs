@@ -0,0 +1,56 @@
+// Emulation profiles, they make the server look like a specific device so senders can be tested
+// against the quirks of that device without having it at hand.
+
+#[derive(Debug)]
+pub struct Profile {
+    pub name: &'static str,
+    // SERVER header of every SSDP message and HTTP response
+    pub server: &'static str,
+    // replace the corresponding fields of desc.xml, None keeps the value from the file
+    pub friendly_name: Option<&'static str>,
+    pub manufacturer: Option<&'static str>,
+    pub model_name: Option<&'static str>,
+    // whether the RenderingControl service is advertised in addition to the devices
+    pub advertise_services: bool,
+    // added to every HTTP response
+    pub extra_headers: &'static [(&'static str, &'static str)],
+}
+
+pub const DEFAULT: Profile = Profile {
+    name: "default",
+    server: "Linus/Arch UPnP/1.0 Linus_Listener/1.0",
+    friendly_name: None,
+    manufacturer: None,
+    model_name: None,
+    advertise_services: true,
+    extra_headers: &[],
+};
+
+pub const SAMSUNG_TV: Profile = Profile {
+    name: "samsung-tv",
+    server: "SHP, UPnP/1.0, Samsung UPnP SDK/1.0",
+    friendly_name: Some("[TV] Samsung 7 Series (55)"),
+    manufacturer: Some("Samsung Electronics"),
+    model_name: Some("UE55MU7000"),
+    advertise_services: true,
+    extra_headers: &[("access-control-allow-origin", "*")],
+};
+
+pub const LG_WEBOS: Profile = Profile {
+    name: "lg-webos",
+    server: "WebOS/4.1.0 UPnP/1.0 webOSTV/1.0",
+    friendly_name: Some("[LG] webOS TV OLED55C9PLA"),
+    manufacturer: Some("LG Electronics"),
+    model_name: Some("OLED55C9PLA"),
+    advertise_services: false,
+    extra_headers: &[],
+};
+
+pub const PROFILES: &[&Profile] = &[&DEFAULT, &SAMSUNG_TV, &LG_WEBOS];
+
+pub fn find(name: &str) -> Option<&'static Profile> {
+    PROFILES
+        .iter()
+        .copied()
+        .find(|profile| profile.name == name)
+}
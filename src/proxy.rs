@@ -0,0 +1,171 @@
+// Proxy mode: the app resources are served by a downstream DIAL device, we only advertise
+// ourselves and relay the requests (useful to log what a sender does with a real receiver).
+use std::time::Duration;
+
+use http::header::HeaderValue;
+use http::{Request, Response, StatusCode};
+
+use crate::client::{self, ClientError};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// forwarded to the downstream device, DIAL servers check the Origin of launch requests
+const FORWARDED_REQUEST_HEADERS: &[&str] = &["content-type", "origin", "user-agent"];
+const RELAYED_RESPONSE_HEADERS: &[&str] = &["content-type", "location"];
+
+/// Forwards a request for `/apps/<rest>` to `<downstream_url><rest>`, `apps_url` is our own
+/// Application-URL, used to rewrite the LOCATION of launched instances to point to us.
+pub async fn forward(
+    downstream_url: &str,
+    apps_url: &str,
+    app_path: &str,
+    request: &Request<String>,
+) -> Response<Vec<u8>> {
+    let mut url = format!("{}{}", downstream_url, app_path);
+    if let Some(query) = request.uri().query() {
+        url.push('?');
+        url.push_str(query);
+    }
+
+    let headers: Vec<(&str, &str)> = FORWARDED_REQUEST_HEADERS
+        .iter()
+        .filter_map(|name| {
+            let value = request.headers().get(*name)?.to_str().ok()?;
+            Some((*name, value))
+        })
+        .collect();
+
+    info!("Forwarding {} {}", request.method(), url);
+    let downstream = match client::request(
+        request.method().as_str(),
+        &url,
+        &headers,
+        request.body().as_bytes(),
+        TIMEOUT,
+    )
+    .await
+    {
+        Ok(downstream) => downstream,
+        Err(e) => {
+            info!("Downstream request {} failed: {}", url, e);
+            let status = match e {
+                ClientError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                ClientError::InvalidUrl(_) | ClientError::Io(_) | ClientError::InvalidResponse => {
+                    StatusCode::BAD_GATEWAY
+                }
+            };
+            let mut response = Response::new(Vec::new());
+            *response.status_mut() = status;
+            return response;
+        }
+    };
+
+    let mut response = Response::new(downstream.body.clone());
+    *response.status_mut() =
+        StatusCode::from_u16(downstream.status).unwrap_or(StatusCode::BAD_GATEWAY);
+    for name in RELAYED_RESPONSE_HEADERS {
+        let value = match downstream.header(name) {
+            Some(value) => value,
+            None => continue,
+        };
+        let value = match value.strip_prefix(downstream_url) {
+            Some(rest) if *name == "location" => format!("{}{}", apps_url, rest),
+            _ => value.to_string(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response.headers_mut().insert(*name, value);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    const APPS_URL: &str = "http://192.0.2.2:8080/apps/";
+
+    // answers one request with the response `respond` builds from the Application-URL of the
+    // mock, the handle returns the received request
+    async fn mock_downstream(respond: fn(&str) -> String) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/apps/", listener.local_addr().unwrap());
+        let response = respond(&url);
+        let received = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0; 1024];
+            // the body of launch_request
+            while !request.ends_with(b"\r\n\r\nv=1") {
+                let n = stream.read(&mut chunk).await.unwrap();
+                assert_ne!(n, 0, "request ended early");
+                request.extend_from_slice(&chunk[..n]);
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, received)
+    }
+
+    fn launch_request() -> Request<String> {
+        Request::builder()
+            .method("POST")
+            .uri("/apps/YouTube?x=1")
+            .header("origin", "https://www.youtube.com")
+            .header("cookie", "secret")
+            .body("v=1".to_string())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn launches_are_relayed_to_the_downstream_device() {
+        let (url, received) = mock_downstream(|url| {
+            format!(
+                "HTTP/1.1 201 Created\r\nLocation: {}YouTube/run\r\nX-Secret: 1\r\nContent-Length: 0\r\n\r\n",
+                url
+            )
+        })
+        .await;
+        let response = forward(&url, APPS_URL, "YouTube", &launch_request()).await;
+        let received = received.await.unwrap();
+
+        assert!(
+            received.starts_with("POST /apps/YouTube?x=1 HTTP/1.1\r\n"),
+            "{}",
+            received
+        );
+        assert!(received.contains("origin: https://www.youtube.com\r\n"));
+        assert!(!received.contains("secret"));
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(!response.headers().contains_key("x-secret"));
+        assert_eq!(
+            response.headers()["location"],
+            "http://192.0.2.2:8080/apps/YouTube/run"
+        );
+    }
+
+    #[tokio::test]
+    async fn locations_elsewhere_are_relayed_unchanged() {
+        let (url, received) = mock_downstream(|_| {
+            "HTTP/1.1 201 Created\r\nLocation: http://192.0.2.9/run\r\nContent-Length: 0\r\n\r\n"
+                .to_string()
+        })
+        .await;
+        let response = forward(&url, APPS_URL, "YouTube", &launch_request()).await;
+        received.await.unwrap();
+        assert_eq!(response.headers()["location"], "http://192.0.2.9/run");
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_downstream_is_a_bad_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/apps/", listener.local_addr().unwrap());
+        drop(listener);
+        let response = forward(&url, APPS_URL, "YouTube", &launch_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+}
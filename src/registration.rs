@@ -0,0 +1,398 @@
+// Apps registered at runtime with POST /admin/apps and removed with DELETE /admin/apps/<name>,
+// no restart needed. They are kept in the state store (see store.rs), with --apps-file or
+// --state-dir they are written to disk and registered again on startup.
+//
+// An app definition is a JSON object:
+//   {"name": "YouTube", "command": "firefox \"$DIAL_PAYLOAD\"", "allow_stop": true,
+//    "install_url": null, "env": {"DISPLAY": ":0"}}
+// only name is required, allow_stop defaults to true. The file is an array of them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+use http::header::HeaderValue;
+use http::{Request, Response, StatusCode};
+
+use crate::apps::{App, AppRegistry, LaunchConfig};
+use crate::context::AppContext;
+use crate::dial;
+use crate::json::{self, Value};
+use crate::store;
+
+pub const ADMIN_APPS_PATH: &str = "/admin/apps";
+// largest body POST /admin/apps accepts
+const MAX_DEFINITION_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppDefinition {
+    pub name: String,
+    pub command: Option<String>,
+    pub allow_stop: bool,
+    pub install_url: Option<String>,
+    pub env: HashMap<String, String>,
+}
+
+impl AppDefinition {
+    pub fn from_json(value: &Value) -> Result<AppDefinition, String> {
+        let members = match value {
+            Value::Object(members) => members,
+            _ => return Err("an app definition has to be an object".to_string()),
+        };
+        if let Some(key) = members.keys().find(|key| {
+            !["name", "command", "allow_stop", "install_url", "env"].contains(&key.as_str())
+        }) {
+            return Err(format!("unknown field {}", key));
+        }
+        let string = |key: &str| match members.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(_) => Err(format!("{} has to be a string", key)),
+        };
+        let name = string("name")?.ok_or_else(|| "name is required".to_string())?;
+        if !is_app_name(&name) {
+            return Err(format!("{:?} can't be used as app name", name));
+        }
+        let allow_stop = match members.get("allow_stop") {
+            None | Some(Value::Null) => true,
+            Some(Value::Bool(allow_stop)) => *allow_stop,
+            Some(_) => return Err("allow_stop has to be a boolean".to_string()),
+        };
+        let env = match members.get("env") {
+            None | Some(Value::Null) => HashMap::new(),
+            Some(Value::Object(env)) => env
+                .iter()
+                .map(|(key, value)| match value {
+                    Value::String(value) => Ok((key.clone(), value.clone())),
+                    _ => Err(format!("env.{} has to be a string", key)),
+                })
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err("env has to be an object".to_string()),
+        };
+        Ok(AppDefinition {
+            name,
+            command: string("command")?,
+            allow_stop,
+            install_url: string("install_url")?,
+            env,
+        })
+    }
+
+    fn from_app(app: &App) -> AppDefinition {
+        AppDefinition {
+            name: app.name.clone(),
+            command: app.launch.command.clone(),
+            allow_stop: app.allow_stop,
+            install_url: app.install_url.clone(),
+            env: app.launch.env.clone(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let string = |value: &Option<String>| match value {
+            Some(value) => format!("\"{}\"", json::escape(value)),
+            None => "null".to_string(),
+        };
+        // sorted, so the file doesn't change if the apps don't
+        let env: BTreeMap<_, _> = self.env.iter().collect();
+        let env: Vec<String> = env
+            .iter()
+            .map(|(key, value)| format!("\"{}\":\"{}\"", json::escape(key), json::escape(value)))
+            .collect();
+        format!(
+            r#"{{"name":"{}","command":{},"allow_stop":{},"install_url":{},"env":{{{}}}}}"#,
+            json::escape(&self.name),
+            string(&self.command),
+            self.allow_stop,
+            string(&self.install_url),
+            env.join(",")
+        )
+    }
+
+    /// Registers the app, it is marked as registered at runtime so it ends up in the --apps-file.
+    pub fn register(&self, apps: &mut AppRegistry) {
+        match &self.install_url {
+            Some(url) => apps.register_installable(&self.name, url),
+            None => apps.register(
+                &self.name,
+                LaunchConfig {
+                    command: self.command.clone(),
+                    env: self.env.clone(),
+                    ..LaunchConfig::default()
+                },
+            ),
+        }
+        let app = apps.get_mut(&self.name).expect("app was just registered");
+        app.allow_stop = self.allow_stop;
+        app.registered_at_runtime = true;
+    }
+}
+
+// the name ends up in the path of the app resources
+pub fn is_app_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '~'))
+}
+
+/// Reads the apps registered at runtime from the store (the --apps-file or apps.json in the
+/// --state-dir), nothing stored means no apps.
+pub fn load(ctx: &AppContext) -> Result<Vec<AppDefinition>, String> {
+    let key = store::apps_key(&ctx.config);
+    let text = match ctx.store.get(&key) {
+        Ok(Some(text)) => text,
+        Ok(None) => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", key, e)),
+    };
+    let invalid = |e: String| format!("Invalid apps file {}: {}", key, e);
+    match json::parse(&text).map_err(invalid)? {
+        Value::Array(values) => values
+            .iter()
+            .map(|value| AppDefinition::from_json(value).map_err(invalid))
+            .collect(),
+        _ => Err(invalid("expected an array of apps".to_string())),
+    }
+}
+
+fn save(ctx: &AppContext, apps: &AppRegistry) -> io::Result<()> {
+    let definitions: Vec<String> = apps
+        .apps()
+        .filter(|app| app.registered_at_runtime)
+        .map(|app| AppDefinition::from_app(app).to_json())
+        .collect();
+    let text = if definitions.is_empty() {
+        "[]\n".to_string()
+    } else {
+        format!("[\n{}\n]\n", definitions.join(",\n"))
+    };
+    ctx.store.put(&store::apps_key(&ctx.config), &text)
+}
+
+fn persist(ctx: &AppContext, apps: &AppRegistry) {
+    if let Err(e) = save(ctx, apps) {
+        warn!(
+            "failed to store the registered apps in {}: {}",
+            store::apps_key(&ctx.config),
+            e
+        );
+    }
+}
+
+/// POST /admin/apps. The definition can carry a command that is run on launch, dial::route only
+/// lets admins (this host without --admin-user) get here.
+pub async fn register(ctx: &AppContext, request: &Request<String>) -> Response<Vec<u8>> {
+    // an app definition is a few hundred bytes
+    if request.body().len() > MAX_DEFINITION_SIZE {
+        return dial::response(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let definition =
+        match json::parse(request.body()).and_then(|value| AppDefinition::from_json(&value)) {
+            Ok(definition) => definition,
+            Err(e) => {
+                return dial::response_with_body(
+                    StatusCode::BAD_REQUEST,
+                    "text/plain; charset=utf-8",
+                    format!("{}\n", e),
+                )
+            }
+        };
+
+    let mut apps = ctx.apps.write().await;
+    if apps.contains(&definition.name) {
+        return dial::response(StatusCode::CONFLICT);
+    }
+    if apps.names().count() >= ctx.config.max_apps {
+        return dial::response(StatusCode::INSUFFICIENT_STORAGE);
+    }
+    let first_app = apps.names().next().is_none();
+    info!("Registering app {} at runtime", definition.name);
+    definition.register(&mut apps);
+    // registered again after an unregister, it gets its data back like after a restart
+    if let Some(app) = apps.get_mut(&definition.name) {
+        app.dial_data = dial::load_dial_data(ctx, &definition.name);
+    }
+    persist(ctx, &apps);
+    drop(apps);
+
+    // without apps we weren't a DIAL device, tell everyone right away instead of on the next
+    // re-announcement
+    if first_app {
+        ctx.reannounce.notify_one();
+    }
+    let mut response = dial::response(StatusCode::CREATED);
+    response.headers_mut().insert(
+        "location",
+        HeaderValue::from_str(&format!("{}/apps/{}", ctx.base_url(), definition.name))
+            .expect("Invalid url"),
+    );
+    response
+}
+
+/// DELETE /admin/apps/<name>, a running instance is stopped first.
+pub async fn unregister(ctx: &AppContext, name: &str) -> Response<Vec<u8>> {
+    let mut apps = ctx.apps.write().await;
+    if !apps.unregister(name) {
+        return dial::response(StatusCode::NOT_FOUND);
+    }
+    info!("Unregistered app {}", name);
+    persist(ctx, &apps);
+    dial::response(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn post(body: String) -> Request<String> {
+        Request::builder()
+            .method("POST")
+            .uri(ADMIN_APPS_PATH)
+            .body(body)
+            .expect("valid request")
+    }
+
+    #[tokio::test]
+    async fn deeply_nested_body_is_a_bad_request() {
+        let ctx = AppContext::new(Config::default());
+        let response = register(&ctx, &post("[".repeat(10000))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn oversized_body_is_rejected_before_parsing() {
+        let ctx = AppContext::new(Config::default());
+        let response = register(&ctx, &post("[".repeat(60000))).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn registers_a_valid_definition() {
+        let ctx = AppContext::new(Config::default());
+        let response = register(&ctx, &post(r#"{"name": "Foo"}"#.to_string())).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(ctx.apps.read().await.contains("Foo"));
+    }
+
+    #[tokio::test]
+    async fn registering_beyond_max_apps_is_rejected() {
+        let config = Config::from_args(
+            ["--max-apps", "1", "--app", "YouTube"]
+                .map(String::from)
+                .into_iter(),
+        );
+        let ctx = AppContext::new(config.unwrap());
+        let response = register(&ctx, &post(r#"{"name": "Foo"}"#.to_string())).await;
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert!(!ctx.apps.read().await.contains("Foo"));
+    }
+
+    #[tokio::test]
+    async fn registered_apps_show_up_in_the_status_until_unregistered() {
+        let ctx = AppContext::new(Config::default());
+        let status = |name: &str| {
+            let request = Request::get(format!("/apps/{}", name))
+                .body(String::new())
+                .expect("valid request");
+            let ctx = ctx.clone();
+            async move { dial::handle_request(&ctx, &request).await }
+        };
+        assert_eq!(status("Foo").await.status(), StatusCode::NOT_FOUND);
+
+        let definition = r#"{"name": "Foo", "command": "true", "allow_stop": false}"#;
+        let response = register(&ctx, &post(definition.to_string())).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = status("Foo").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = String::from_utf8(response.into_body()).unwrap();
+        assert!(body.contains("<name>Foo</name>"), "{}", body);
+        assert!(body.contains(r#"<options allowStop="false"/>"#), "{}", body);
+        let names: Vec<_> = load(&ctx)
+            .unwrap()
+            .into_iter()
+            .map(|app| app.name)
+            .collect();
+        assert_eq!(names, ["Foo"]);
+        // registered twice is a conflict
+        let response = register(&ctx, &post(definition.to_string())).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        assert_eq!(
+            unregister(&ctx, "Foo").await.status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(status("Foo").await.status(), StatusCode::NOT_FOUND);
+        assert!(load(&ctx).unwrap().is_empty());
+        assert_eq!(
+            unregister(&ctx, "Foo").await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn only_admins_register_and_unregister_apps() {
+        use std::net::{Ipv4Addr, SocketAddr};
+
+        let definition = r#"{"name": "Shell", "command": "touch /tmp/owned"}"#;
+        let request = |method: &str, path: &str, peer: Ipv4Addr, authorization: Option<&str>| {
+            let mut request = Request::builder().method(method).uri(path);
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            let mut request = request.body(definition.to_string()).unwrap();
+            request
+                .extensions_mut()
+                .insert(SocketAddr::from((peer, 50000)));
+            request
+        };
+        async fn status(ctx: &AppContext, request: Request<String>) -> StatusCode {
+            dial::handle_request(ctx, &request).await.status()
+        }
+        let remote = Ipv4Addr::new(192, 0, 2, 20);
+        let shell_path = format!("{}/Shell", ADMIN_APPS_PATH);
+
+        // without credentials remote senders can't register a command or remove apps
+        let ctx = AppContext::new(
+            Config::from_args(["--app", "Shell"].map(String::from).into_iter()).unwrap(),
+        );
+        assert_eq!(
+            status(&ctx, request("DELETE", &shell_path, remote, None)).await,
+            StatusCode::FORBIDDEN
+        );
+        assert!(ctx.apps.read().await.contains("Shell"));
+        ctx.apps.write().await.unregister("Shell");
+        assert_eq!(
+            status(&ctx, request("POST", ADMIN_APPS_PATH, remote, None)).await,
+            StatusCode::FORBIDDEN
+        );
+        assert!(!ctx.apps.read().await.contains("Shell"));
+        let local = Ipv4Addr::LOCALHOST;
+        assert_eq!(
+            status(&ctx, request("POST", ADMIN_APPS_PATH, local, None)).await,
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            status(&ctx, request("DELETE", &shell_path, local, None)).await,
+            StatusCode::NO_CONTENT
+        );
+
+        // with credentials they are required from every host
+        let args = ["--admin-user", "admin", "--admin-pass", "secret"];
+        let ctx = AppContext::new(Config::from_args(args.map(String::from).into_iter()).unwrap());
+        assert_eq!(
+            status(&ctx, request("POST", ADMIN_APPS_PATH, local, None)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        // admin:secret
+        let admin = Some("Basic YWRtaW46c2VjcmV0");
+        assert_eq!(
+            status(&ctx, request("POST", ADMIN_APPS_PATH, remote, admin)).await,
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            status(&ctx, request("DELETE", &shell_path, remote, admin)).await,
+            StatusCode::NO_CONTENT
+        );
+    }
+}
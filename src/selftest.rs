@@ -0,0 +1,106 @@
+// `dial_server selftest`: discovers the running server like a sender would and walks through a
+// launch, printing PASS/FAIL for every stage.
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use crate::client::{self, ClientResponse};
+use crate::context::AppContext;
+use crate::ssdp::{self, SsdpMessage, SsdpStartLine};
+
+// registered by Config::from_args for the selftest, it has no command so launching it is harmless
+pub const SELFTEST_APP: &str = "SelfTest";
+
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+async fn search() -> Result<String, String> {
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(|e| format!("bind failed: {}", e))?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {}\r\nUSER-AGENT: dial_server/selftest\r\n\r\n",
+        ssdp::DIAL_SEARCH_TARGET
+    );
+    socket
+        .send_to(search.as_bytes(), "239.255.255.250:1900")
+        .await
+        .map_err(|e| format!("sending M-SEARCH failed: {}", e))?;
+
+    let mut buf = [0; ssdp::MAX_MESSAGE_SIZE];
+    let (amt, _) = tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf))
+        .await
+        .map_err(|_| "no response to M-SEARCH".to_string())?
+        .map_err(|e| format!("receiving failed: {}", e))?;
+    let response = String::from_utf8_lossy(&buf[..amt]).to_string();
+    SsdpMessage::parse(&response)
+        .filter(|message| matches!(message.start_line, SsdpStartLine::Response { .. }))
+        .and_then(|message| message.header("LOCATION").map(str::to_string))
+        .ok_or_else(|| format!("response without LOCATION: {}", response))
+}
+
+async fn request(method: &str, url: &str) -> Result<ClientResponse, String> {
+    client::request(method, url, &[], &[], TIMEOUT)
+        .await
+        .map_err(|e| format!("{} {} failed: {}", method, url, e))
+}
+
+fn expect_status(response: &ClientResponse, expected: u16) -> Result<(), String> {
+    if response.status == expected {
+        Ok(())
+    } else {
+        Err(format!("expected {}, got {}", expected, response.status))
+    }
+}
+
+fn report<T>(stage: &str, result: Result<T, String>) -> Option<T> {
+    match result {
+        Ok(value) => {
+            println!("PASS {}", stage);
+            Some(value)
+        }
+        Err(e) => {
+            println!("FAIL {}: {}", stage, e);
+            None
+        }
+    }
+}
+
+async fn stages() -> Option<()> {
+    let location = report("discovery", search().await)?;
+    launch_and_stop(&location).await
+}
+
+/// The stages after the discovery, starting at the descriptor `location`.
+pub async fn launch_and_stop(location: &str) -> Option<()> {
+    let descriptor = request("GET", location).await.and_then(|response| {
+        expect_status(&response, 200)?;
+        response
+            .header("Application-URL")
+            .map(str::to_string)
+            .ok_or_else(|| "descriptor response without Application-URL".to_string())
+    });
+    let application_url = report("descriptor", descriptor)?;
+
+    let app_url = format!("{}{}", application_url, SELFTEST_APP);
+    let launch = request("POST", &app_url).await.and_then(|response| {
+        expect_status(&response, 201)?;
+        response
+            .header("LOCATION")
+            .map(str::to_string)
+            .ok_or_else(|| "launch response without LOCATION".to_string())
+    });
+    let instance_url = report("launch", launch)?;
+
+    let stop = request("DELETE", &instance_url)
+        .await
+        .and_then(|response| expect_status(&response, 200));
+    report("stop", stop)
+}
+
+/// Runs all stages against the server of `ctx` and shuts it down afterwards, exit code 1 if a
+/// stage failed.
+pub async fn run(ctx: AppContext) {
+    let passed = stages().await.is_some();
+    println!("Selftest {}", if passed { "PASSED" } else { "FAILED" });
+    ctx.shutdown.trigger(if passed { 0 } else { 1 });
+}
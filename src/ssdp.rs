@@ -0,0 +1,534 @@
+// http://www.upnp.org/specs/arch/UPnP-arch-DeviceArchitecture-v1.1.pdf
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use tokio::sync::mpsc;
+
+// SSDP messages have to fit into a single UDP datagram (there is no reassembly), UDA 1.1 section 1.1.2
+// recommends to keep them below the MTU (~1500 bytes) and most devices stay well below 4KB.
+// Anything bigger than this is not a message we can parse.
+pub const MAX_MESSAGE_SIZE: usize = 8 * 1024;
+
+pub const MULTICAST_IP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+
+// UDA 1.1 section 1.3.3: a MX above 5 is treated as 5
+pub const MAX_MX: u64 = 5;
+// highest --max-mx, for senders that really wait that long. Longer delays only fill the queue of
+// pending responses
+pub const MAX_MX_LIMIT: u64 = 120;
+
+pub const DIAL_SEARCH_TARGET: &str = "urn:dial-multiscreen-org:service:dial:1";
+
+/// Returns the (trimmed) value of a header of a SSDP message, header names are case-insensitive.
+pub fn header<'a>(msg: &'a str, name: &str) -> Option<&'a str> {
+    msg.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// The CONFIGID.UPNP.ORG of `descriptor`, UDA 1.1 section 1.1.2: it has to change whenever the
+/// descriptor does so control points fetch it again. A FNV-1a hash, which (unlike the std
+/// hasher) stays the same across releases, cut down to the allowed range 0 to 16777215.
+pub fn config_id(descriptor: &str) -> u32 {
+    let hash = descriptor.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+    hash & 0x00ff_ffff
+}
+
+/// Formats `time` as RFC 9110 section 5.6.7 IMF-fixdate (`Sun, 06 Nov 1994 08:49:37 GMT`), the
+/// format of the DATE header.
+pub fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let seconds = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = seconds / 86400;
+    let seconds_of_day = seconds % 86400;
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+/// The first line of a SSDP message, UDA 1.1 sections 1.2 and 1.3.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SsdpStartLine {
+    // M-SEARCH * HTTP/1.1
+    MSearch,
+    // NOTIFY * HTTP/1.1
+    Notify,
+    // HTTP/1.1 200 OK, the answer to a M-SEARCH
+    Response { status: u16 },
+}
+
+/// A parsed SSDP message, header names are stored uppercase.
+#[derive(Debug, Clone)]
+pub struct SsdpMessage {
+    pub start_line: SsdpStartLine,
+    pub headers: HashMap<String, String>,
+}
+
+impl SsdpMessage {
+    /// Parses a received datagram, None if the start line isn't one of SsdpStartLine. Lines that
+    /// aren't headers are skipped, LF only line endings are accepted as well.
+    pub fn parse(msg: &str) -> Option<SsdpMessage> {
+        let mut lines = msg.lines();
+        let start_line = match lines.next()?.trim_end().split(' ').collect::<Vec<_>>()[..] {
+            ["M-SEARCH", "*", "HTTP/1.1"] => SsdpStartLine::MSearch,
+            ["NOTIFY", "*", "HTTP/1.1"] => SsdpStartLine::Notify,
+            ["HTTP/1.1", status, ..] => SsdpStartLine::Response {
+                status: status.parse().ok()?,
+            },
+            _ => return None,
+        };
+        let mut headers = HashMap::new();
+        for line in lines {
+            if let Some((name, value)) = line.split_once(':') {
+                // a repeated header keeps its first value, like header() does
+                headers
+                    .entry(name.trim().to_ascii_uppercase())
+                    .or_insert_with(|| value.trim().to_string());
+            }
+        }
+        Some(SsdpMessage {
+            start_line,
+            headers,
+        })
+    }
+
+    /// The value of a header, `name` is case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_uppercase())
+            .map(String::as_str)
+    }
+
+    /// UDA 1.1 section 1.3.2: a M-SEARCH is only valid with `MAN: "ssdp:discover"` (including the
+    /// quotes)
+    pub fn is_discover_search(&self) -> bool {
+        self.start_line == SsdpStartLine::MSearch && self.header("MAN") == Some("\"ssdp:discover\"")
+    }
+
+    /// The MX header in seconds, 0 if it is missing or not a number.
+    pub fn mx(&self) -> u64 {
+        self.header("MX")
+            .and_then(|mx| mx.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// UDA 1.1 section 1.3.3: the response to a multicast search is delayed by a random time up to
+    /// MX seconds (at most `max_mx`) so the devices on the network don't all answer at once,
+    /// unicast searches are answered right away.
+    pub fn max_response_delay(&self, max_mx: u64) -> u64 {
+        let multicast = self
+            .header("HOST")
+            .is_some_and(|host| host.starts_with(&MULTICAST_IP.to_string()));
+        if multicast {
+            self.mx().min(max_mx)
+        } else {
+            0
+        }
+    }
+
+    /// UDA 1.1 section 1.3.2: multicast searches have to be sent with `HOST: 239.255.255.250:1900`,
+    /// unicast searches with the address of the device (`unicast_ip`). The port defaults to 1900.
+    pub fn has_valid_host(&self, unicast_ip: Ipv4Addr) -> bool {
+        let host = match self.header("HOST") {
+            Some(host) => host,
+            None => return false,
+        };
+        let (ip, port) = match host.split_once(':') {
+            Some((ip, port)) => (ip, port),
+            None => (host, "1900"),
+        };
+        let ip: Ipv4Addr = match ip.parse() {
+            Ok(ip) => ip,
+            Err(_) => return false,
+        };
+        port == "1900" && (ip == MULTICAST_IP || ip == unicast_ip)
+    }
+}
+
+impl fmt::Display for SsdpStartLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SsdpStartLine::MSearch => f.write_str("M-SEARCH * HTTP/1.1"),
+            SsdpStartLine::Notify => f.write_str("NOTIFY * HTTP/1.1"),
+            SsdpStartLine::Response { status } => write!(f, "HTTP/1.1 {}", status),
+        }
+    }
+}
+
+// for logging, the headers are sorted by name
+impl fmt::Display for SsdpMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.start_line)?;
+        let mut headers: Vec<_> = self.headers.iter().collect();
+        headers.sort();
+        for (name, value) in headers {
+            writeln!(f, "{}: {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// The multicast group membership of the SSDP socket(s), reported by GET /status.
+#[derive(Debug)]
+pub struct MulticastStatus {
+    pub group: Ipv4Addr,
+    // address of the interface the group is joined on, 0.0.0.0 lets the OS pick one
+    pub interface: Ipv4Addr,
+    joined: AtomicBool,
+}
+
+impl MulticastStatus {
+    pub fn new(interface: Ipv4Addr) -> MulticastStatus {
+        MulticastStatus {
+            group: MULTICAST_IP,
+            interface,
+            joined: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_joined(&self, joined: bool) {
+        self.joined.store(joined, Ordering::Relaxed);
+    }
+
+    pub fn joined(&self) -> bool {
+        self.joined.load(Ordering::Relaxed)
+    }
+}
+
+/// Why a datagram received on the SSDP socket wasn't answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    // larger than MAX_MESSAGE_SIZE
+    Truncated,
+    InvalidUtf8,
+    // one of our own NOTIFYs, delivered back with multicast loopback
+    SelfEcho,
+    // NOTIFYs of other devices, responses, garbage
+    NotSearch,
+    // a search for something else than DIAL
+    OtherSearchTarget,
+    MissingMan,
+    InvalidHost,
+    // a DIAL search while no app is registered
+    NoApps,
+    // any search while SSDP is paused (POST /admin/ssdp/pause)
+    Paused,
+    // a search arriving while RESPONSE_QUEUE_SIZE responses already wait for their MX delay
+    QueueFull,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 10] = [
+        DropReason::Truncated,
+        DropReason::InvalidUtf8,
+        DropReason::SelfEcho,
+        DropReason::NotSearch,
+        DropReason::OtherSearchTarget,
+        DropReason::MissingMan,
+        DropReason::InvalidHost,
+        DropReason::NoApps,
+        DropReason::Paused,
+        DropReason::QueueFull,
+    ];
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            DropReason::Truncated => "truncated",
+            DropReason::InvalidUtf8 => "invalid-utf8",
+            DropReason::SelfEcho => "self-echo",
+            DropReason::NotSearch => "not-a-search",
+            DropReason::OtherSearchTarget => "other-search-target",
+            DropReason::MissingMan => "missing-man",
+            DropReason::InvalidHost => "invalid-host",
+            DropReason::NoApps => "no-apps",
+            DropReason::Paused => "paused",
+            DropReason::QueueFull => "queue-full",
+        };
+        f.write_str(reason)
+    }
+}
+
+// indexed by the position of the reason in DropReason::ALL, shared by all SSDP sockets
+static DROPPED: [AtomicU64; DropReason::ALL.len()] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Counts a dropped datagram and logs the reason (only with --debug).
+pub fn record_drop(reason: DropReason, src_addr: SocketAddr) {
+    let count = DROPPED[reason as usize].fetch_add(1, Ordering::Relaxed) + 1;
+    debug!(
+        "Ignoring datagram from {}: {} (#{} for this reason)",
+        src_addr, reason, count
+    );
+}
+
+/// Number of datagrams dropped for `reason` since startup.
+pub fn dropped(reason: DropReason) -> u64 {
+    DROPPED[reason as usize].load(Ordering::Relaxed)
+}
+
+/// A M-SEARCH we received, delivered to the search observer.
+#[derive(Debug, Clone)]
+pub struct SearchEvent {
+    pub src_addr: SocketAddr,
+    // ST header
+    pub search_target: String,
+    // UDA 1.1 section 1.3.2: optional, identifies the control point (e.g. "Android/13 UPnP/1.1 YouTube/18.1")
+    pub user_agent: Option<String>,
+    pub timestamp: SystemTime,
+}
+
+/// The last searches we received, the oldest ones are dropped once `capacity` is reached.
+#[derive(Debug)]
+pub struct RecentSearches {
+    capacity: usize,
+    events: Mutex<VecDeque<SearchEvent>>,
+}
+
+impl RecentSearches {
+    pub fn new(capacity: usize) -> RecentSearches {
+        RecentSearches {
+            capacity,
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, event: SearchEvent) {
+        let mut events = self.events.lock().expect("recent searches lock poisoned");
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Oldest first.
+    pub fn events(&self) -> Vec<SearchEvent> {
+        let events = self.events.lock().expect("recent searches lock poisoned");
+        events.iter().cloned().collect()
+    }
+}
+
+/// Hands received searches to whoever is interested without ever blocking the SSDP loop,
+/// events are dropped when the receiver can't keep up.
+#[derive(Debug, Clone, Default)]
+pub struct SearchObserver {
+    sender: Option<mpsc::Sender<SearchEvent>>,
+}
+
+impl SearchObserver {
+    pub fn channel(capacity: usize) -> (SearchObserver, mpsc::Receiver<SearchEvent>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        (
+            SearchObserver {
+                sender: Some(sender),
+            },
+            receiver,
+        )
+    }
+
+    pub fn notify(&self, event: SearchEvent) {
+        if let Some(sender) = &self.sender {
+            if let Err(e) = sender.try_send(event) {
+                debug!("Dropping search event: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(host: &str, mx: &str) -> SsdpMessage {
+        SsdpMessage::parse(&format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: ssdp:all\r\n\r\n",
+            host, mx
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn multicast_response_delay_is_capped_at_max_mx() {
+        let multicast = "239.255.255.250:1900";
+        assert_eq!(search(multicast, "3").max_response_delay(MAX_MX), 3);
+        assert_eq!(search(multicast, "10").max_response_delay(MAX_MX), MAX_MX);
+        assert_eq!(search(multicast, "10").max_response_delay(0), 0);
+        assert_eq!(search(multicast, "60").max_response_delay(30), 30);
+        assert_eq!(search(multicast, "60").max_response_delay(MAX_MX_LIMIT), 60);
+    }
+
+    #[test]
+    fn unicast_searches_are_answered_right_away() {
+        assert_eq!(search("192.0.2.2:1900", "5").max_response_delay(MAX_MX), 0);
+    }
+
+    #[test]
+    fn invalid_mx_means_no_delay() {
+        let multicast = "239.255.255.250:1900";
+        assert_eq!(search(multicast, "soon").mx(), 0);
+        assert_eq!(search(multicast, "-1").max_response_delay(MAX_MX), 0);
+    }
+
+    #[test]
+    fn hosts_of_multicast_and_unicast_searches() {
+        let unicast_ip = Ipv4Addr::new(192, 0, 2, 2);
+        for host in ["239.255.255.250:1900", "239.255.255.250", "192.0.2.2:1900"] {
+            assert!(search(host, "1").has_valid_host(unicast_ip), "{}", host);
+        }
+        for host in [
+            "239.255.255.250:1901",
+            "192.0.2.3:1900",
+            "example.com:1900",
+            "",
+        ] {
+            assert!(!search(host, "1").has_valid_host(unicast_ip), "{}", host);
+        }
+        let without_host = "M-SEARCH * HTTP/1.1\r\nMAN: \"ssdp:discover\"\r\nST: ssdp:all\r\n\r\n";
+        assert!(!SsdpMessage::parse(without_host)
+            .unwrap()
+            .has_valid_host(unicast_ip));
+    }
+
+    #[test]
+    fn config_id_is_in_the_allowed_range() {
+        for descriptor in [
+            "",
+            "<root/>",
+            "<root><friendlyName>TV</friendlyName></root>",
+        ] {
+            assert!(config_id(descriptor) <= 16_777_215);
+        }
+    }
+
+    #[test]
+    fn config_id_depends_on_the_content() {
+        assert_eq!(config_id("<root>a</root>"), config_id("<root>a</root>"));
+        assert_ne!(config_id("<root>a</root>"), config_id("<root>b</root>"));
+    }
+
+    #[test]
+    fn a_full_observer_channel_drops_events() {
+        let (observer, mut events) = SearchObserver::channel(1);
+        let event = SearchEvent {
+            src_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 1234)),
+            search_target: "ssdp:all".to_string(),
+            user_agent: None,
+            timestamp: SystemTime::now(),
+        };
+        // must not block although nobody receives
+        observer.notify(event.clone());
+        observer.notify(event);
+        assert!(events.try_recv().is_ok());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn each_message_kind_is_parsed() {
+        let search = SsdpMessage::parse(
+            "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: ssdp:all\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(search.start_line, SsdpStartLine::MSearch);
+        assert!(search.is_discover_search());
+        assert_eq!(search.mx(), 2);
+        assert_eq!(search.header("st"), Some("ssdp:all"));
+
+        // LF only, lowercase header names and a repeated header
+        let notify = SsdpMessage::parse(
+            "NOTIFY * HTTP/1.1\nhost: 239.255.255.250:1900\nnts: ssdp:alive\nNTS: ssdp:byebye\nno header\n\n",
+        )
+        .unwrap();
+        assert_eq!(notify.start_line, SsdpStartLine::Notify);
+        assert!(!notify.is_discover_search());
+        assert_eq!(notify.header("NTS"), Some("ssdp:alive"));
+        assert_eq!(notify.headers.len(), 2);
+
+        let response = SsdpMessage::parse(
+            "HTTP/1.1 200 OK\r\nLOCATION: http://192.0.2.2:8081/dd.xml\r\nST: upnp:rootdevice\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(response.start_line, SsdpStartLine::Response { status: 200 });
+        assert_eq!(
+            response.header("Location"),
+            Some("http://192.0.2.2:8081/dd.xml")
+        );
+    }
+
+    #[test]
+    fn other_start_lines_are_not_ssdp() {
+        for message in [
+            "",
+            "GET / HTTP/1.1\r\n\r\n",
+            "M-SEARCH * HTTP/1.0\r\n\r\n",
+            "NOTIFY /path HTTP/1.1\r\n\r\n",
+            "HTTP/1.1 OK\r\n\r\n",
+        ] {
+            assert!(SsdpMessage::parse(message).is_none(), "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn recent_searches_keep_the_newest() {
+        let searches = RecentSearches::new(3);
+        for port in 1..=5 {
+            searches.push(SearchEvent {
+                src_addr: SocketAddr::from(([192, 0, 2, 1], port)),
+                search_target: DIAL_SEARCH_TARGET.to_string(),
+                user_agent: None,
+                timestamp: SystemTime::UNIX_EPOCH,
+            });
+        }
+        let ports: Vec<u16> = searches
+            .events()
+            .iter()
+            .map(|search| search.src_addr.port())
+            .collect();
+        assert_eq!(ports, [3, 4, 5]);
+    }
+}
@@ -0,0 +1,219 @@
+// Where the state that outlives a restart is kept: the apps registered at runtime and the
+// dial_data the apps posted. Keys are relative paths like "dial_data/YouTube.json", values are
+// text.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::config::Config;
+
+/// Key/value storage for state, see FsStore and MemoryStore.
+pub trait StateStore: fmt::Debug + Send + Sync {
+    /// The value of `key`, None if it was never stored.
+    fn get(&self, key: &str) -> io::Result<Option<String>>;
+    /// Stores `value` under `key`, replacing the previous value.
+    fn put(&self, key: &str, value: &str) -> io::Result<()>;
+    /// The stored keys that start with `prefix`, sorted.
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+}
+
+/// Every key is a file below `root`.
+#[derive(Debug)]
+pub struct FsStore {
+    root: PathBuf,
+}
+
+impl FsStore {
+    pub fn new(root: impl Into<PathBuf>) -> FsStore {
+        FsStore { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+impl StateStore for FsStore {
+    fn get(&self, key: &str) -> io::Result<Option<String>> {
+        match fs::read_to_string(self.path(key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // written to a temporary file first, a crash in between doesn't leave a truncated file behind
+    fn put(&self, key: &str, value: &str) -> io::Result<()> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, value)?;
+        fs::rename(&temporary, &path)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        // only the directory the prefix points into is read, keys don't nest deeper than that
+        let (directory, _) = prefix.rsplit_once('/').unwrap_or(("", prefix));
+        let entries = match fs::read_dir(self.root.join(directory)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            let key = if directory.is_empty() {
+                name
+            } else {
+                format!("{}/{}", directory, name)
+            };
+            if key.starts_with(prefix) && !key.ends_with(".tmp") {
+                keys.push(key);
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// Keeps everything in memory, nothing survives a restart.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    values: Mutex<BTreeMap<String, String>>,
+}
+
+impl StateStore for MemoryStore {
+    fn get(&self, key: &str) -> io::Result<Option<String>> {
+        Ok(self
+            .values
+            .lock()
+            .expect("store lock poisoned")
+            .get(key)
+            .cloned())
+    }
+
+    fn put(&self, key: &str, value: &str) -> io::Result<()> {
+        self.values
+            .lock()
+            .expect("store lock poisoned")
+            .insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .values
+            .lock()
+            .expect("store lock poisoned")
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+}
+
+/// --state-dir, or the directory of the --apps-file. Without either the state is only kept in
+/// memory.
+pub fn from_config(config: &Config) -> Arc<dyn StateStore> {
+    let root = config.state_dir.clone().or_else(|| {
+        config
+            .apps_file
+            .as_ref()
+            .map(|path| path.parent().map(PathBuf::from).unwrap_or_default())
+    });
+    match root {
+        Some(root) => Arc::new(FsStore::new(root)),
+        None => Arc::new(MemoryStore::default()),
+    }
+}
+
+/// The key the apps registered at runtime are stored under.
+pub fn apps_key(config: &Config) -> String {
+    config
+        .apps_file
+        .as_ref()
+        .and_then(|path| path.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "apps.json".to_string())
+}
+
+pub fn dial_data_key(app: &str) -> String {
+    format!("dial_data/{}.json", app)
+}
+
+/// The app a key of dial_data_key belongs to.
+pub fn dial_data_app(key: &str) -> Option<&str> {
+    key.strip_prefix("dial_data/")?.strip_suffix(".json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what the registry and dial_data rely on, for either store
+    fn check_store(store: &dyn StateStore) {
+        assert_eq!(store.get("apps.json").unwrap(), None);
+        store.put("apps.json", "[]\n").unwrap();
+        store.put(&dial_data_key("YouTube"), "{}").unwrap();
+        store
+            .put(&dial_data_key("Netflix"), "{\"a\":\"1\"}")
+            .unwrap();
+        store
+            .put(&dial_data_key("YouTube"), "{\"b\":\"2\"}")
+            .unwrap();
+
+        assert_eq!(store.get("apps.json").unwrap().as_deref(), Some("[]\n"));
+        assert_eq!(
+            store.get(&dial_data_key("YouTube")).unwrap().as_deref(),
+            Some("{\"b\":\"2\"}")
+        );
+        let keys = store.list("dial_data/").unwrap();
+        assert_eq!(keys, ["dial_data/Netflix.json", "dial_data/YouTube.json"]);
+        let apps: Vec<_> = keys.iter().filter_map(|key| dial_data_app(key)).collect();
+        assert_eq!(apps, ["Netflix", "YouTube"]);
+        assert!(store.list("other/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_memory_store_keeps_values() {
+        check_store(&MemoryStore::default());
+    }
+
+    #[test]
+    fn the_fs_store_keeps_values_in_files() {
+        let root = std::env::temp_dir().join(format!("dial_server_store_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        check_store(&FsStore::new(&root));
+        assert!(root.join("dial_data/YouTube.json").is_file());
+        // a new store on the same directory sees the values, like after a restart
+        let restarted = FsStore::new(&root);
+        assert_eq!(restarted.get("apps.json").unwrap().as_deref(), Some("[]\n"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn the_store_follows_the_config() {
+        let config = |args: &[&str]| Config::from_args(args.iter().map(|arg| arg.to_string()));
+        let memory = from_config(&config(&[]).unwrap());
+        assert!(format!("{:?}", memory).starts_with("MemoryStore"));
+        let fs = from_config(&config(&["--state-dir", "/var/lib/dial"]).unwrap());
+        assert!(format!("{:?}", fs).contains("/var/lib/dial"));
+
+        let apps_file = config(&["--apps-file", "/etc/dial/registered.json"]).unwrap();
+        assert!(format!("{:?}", from_config(&apps_file)).contains("/etc/dial"));
+        assert_eq!(apps_key(&apps_file), "registered.json");
+        assert_eq!(apps_key(&config(&[]).unwrap()), "apps.json");
+    }
+}
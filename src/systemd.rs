@@ -0,0 +1,63 @@
+// sd_notify(3) protocol, see https://www.freedesktop.org/software/systemd/man/sd_notify.html
+// Every function is a no-op when we haven't been started by systemd (NOTIFY_SOCKET isn't set).
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+fn notify(state: &str) -> std::io::Result<()> {
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+
+    // a leading '@' means the socket lives in the abstract namespace
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// Tells systemd (Type=notify) that the server is up.
+pub fn notify_ready() {
+    if let Err(e) = notify("READY=1") {
+        info!("Failed to notify systemd about readiness: {}", e);
+    }
+}
+
+/// Returns the interval the watchdog has to be pinged in (half of WATCHDOG_USEC as
+/// recommended by sd_watchdog_enabled(3)), None if the watchdog isn't enabled for us.
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Pings the systemd watchdog periodically if WatchdogSec= is set for the service.
+pub fn spawn_watchdog() {
+    let interval = match watchdog_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+
+    info!("Pinging systemd watchdog every {:?}", interval);
+    tokio::spawn(async move {
+        let mut timer = tokio::time::interval(interval);
+        loop {
+            timer.tick().await;
+            if let Err(e) = notify("WATCHDOG=1") {
+                info!("Failed to ping systemd watchdog: {}", e);
+            }
+        }
+    });
+}